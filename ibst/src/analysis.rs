@@ -2,6 +2,8 @@
 use crate::*;
 use crate::ibs::*;
 use crate::codegen::TestParameters;
+use crate::decoded::fmt_addr;
+use std::borrow::Borrow;
 use std::collections::{ BTreeSet, BTreeMap };
 use dynasmrt::{ AssemblyOffset, ExecutableBuffer };
 
@@ -23,17 +25,30 @@ pub struct MemoryAccess {
 }
 impl MemoryAccess {
     /// Try to obtain a [MemoryAccess] from a [Sample].
-    pub fn from_sample(sample: &Sample) -> Option<Self> {
-        let kind = match (sample.data3.st_op(), sample.data3.ld_op()) {
+    pub fn from_sample(sample: &Sample, layout: IbsLayout) -> Option<Self> {
+        Self::from_decoded(&DecodedSample::new(sample, layout))
+    }
+
+    /// Try to obtain a [MemoryAccess] from a [DecodedSample]. 
+    ///
+    /// Returns `None` unless the op was a load/store with a valid physical 
+    /// address.
+    pub fn from_decoded(d: &DecodedSample) -> Option<Self> {
+        let kind = match (d.op.st_op, d.op.ld_op) {
             (true, false) => MemoryAccessKind::ST,
             (false, true) => MemoryAccessKind::LD,
             (true, true) => MemoryAccessKind::LDST,
             (_, _) => return None,
         };
-        let phys = sample.phyad;
-        let width = sample.data3.op_mem_width() as usize;
+        let phys = d.phyad?;
+        let width = d.op.op_mem_width.bits().unwrap_or(0);
         Some(Self { phys, width, kind })
     }
+
+    /// Return a label describing the region targeted by this access.
+    pub fn label(&self, info: &IbstraceInfo) -> &'static str {
+        if info.scratch_page_contains(self.phys) { "scratch" } else { "" }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd)]
@@ -41,34 +56,35 @@ pub struct SampleInfo {
     pub data:  ibs::IbsOpData, 
     pub data2: ibs::IbsOpData2, 
     pub data3: ibs::IbsOpData3, 
-    pub linad: usize, 
-    pub phyad: usize,
-    pub tgt_rip: usize,
+    pub linad: Option<usize>, 
+    pub phyad: Option<usize>,
+    pub tgt_rip: Option<usize>,
 }
 impl SampleInfo {
-    pub fn from_sample(s: &Sample) -> Self { 
+    pub fn from_sample(s: &Sample, layout: IbsLayout) -> Self { 
+        let d = DecodedSample::new(s, layout);
         Self { 
             data: s.data,
             data2: s.data2,
             data3: s.data3,
-            linad: s.linad,
-            phyad: s.phyad,
-            tgt_rip: s.tgt_rip,
+            linad: d.linad,
+            phyad: d.phyad,
+            tgt_rip: d.tgt_rip,
         }
     }
 }
 impl std::fmt::Display for SampleInfo {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(fmt, 
-            "[t2r={:05} c2r={:05} uc={} brn_ret={} brn_tkn={} brn_msp={} phy={:016x} tgt={:016x}",
+            "[t2r={:05} c2r={:05} uc={} brn_ret={} brn_tkn={} brn_msp={} phy={} tgt={}",
             self.data.tag_to_ret_ctr(),
             self.data.comp_to_ret_ctr(),
             self.data.op_microcode() as usize,
             self.data.op_brn_ret() as usize,
             self.data.op_brn_taken() as usize,
             self.data.op_brn_misp() as usize,
-            self.phyad,
-            self.tgt_rip,
+            fmt_addr(self.phyad),
+            fmt_addr(self.tgt_rip),
         )
     }
 }
@@ -78,44 +94,150 @@ impl std::fmt::Display for SampleInfo {
 pub struct TestResult {
     /// Information about the code emitted for this test.
     pub params: TestParameters,
+    /// Offset of the code for this test within the code buffer.
+    pub load_offset: usize,
     /// The resulting samples from this test.
     pub result: Box<[Sample]>,
+    /// The value returned by user code (in RAX).
+    pub return_value: usize,
+    /// Register state after user code returned.
+    pub regs: Registers,
+    /// Statistics about the collected samples.
+    pub stats: SampleStats,
+    /// Layout used to decode the samples.
+    pub layout: IbsLayout,
+}
+impl TestResult {
+    /// Returns `true` if the sample buffer overflowed during this test,
+    /// meaning that [TestResult::result] is incomplete.
+    pub fn overflowed(&self) -> bool {
+        self.stats.overflowed()
+    }
+
+    /// Print a warning if the sample buffer overflowed during this test.
+    fn warn_overflow(&self) {
+        if self.overflowed() {
+            eprintln!("[!] Sample buffer overflowed, results are truncated ({})",
+                self.stats
+            );
+        }
+    }
+
+    /// Return the address of the target instruction, given the base address
+    /// of the code buffer.
+    pub fn target_rip(&self, base: usize) -> usize {
+        base + self.load_offset + self.params.tgt_instr_off
+    }
 }
 
 /// Given some [TestParameters], sample user code and return the results.
-pub fn run_test(fd: i32, params: TestParameters) -> TestResult {
-    let msg = params.to_userbuf();
-    let result = crate::measure(fd, &msg);
-    TestResult { params, result }
+pub fn run_test<B>(dev: &mut B, params: TestParameters) 
+    -> Result<TestResult, Error> 
+    where B: MeasurementBackend + ?Sized
+{
+    run_test_with(dev, params, &SamplingConfig::default())
+}
+
+/// Given some [TestParameters], sample user code with a particular 
+/// [SamplingConfig] and return the results.
+pub fn run_test_with<B>(dev: &mut B, params: TestParameters, 
+    cfg: &SamplingConfig) 
+    -> Result<TestResult, Error> 
+    where B: MeasurementBackend + ?Sized
+{
+    let layout = dev.layout()?;
+    dev.upload(&params.buf)?;
+    let outcome = dev.measure_with(cfg)?;
+    let res = TestResult { 
+        params, 
+        load_offset: 0,
+        result: outcome.samples, 
+        return_value: outcome.return_value,
+        regs: outcome.regs,
+        stats: outcome.stats,
+        layout,
+    };
+    res.warn_overflow();
+    Ok(res)
+}
+
+/// Given a list of [TestParameters], sample the code for all tests with 
+/// a single batch (see [MeasurementBackend::measure_batch]) and return the 
+/// results (in order).
+///
+/// When some test faults, only the results for the tests before it are 
+/// returned, along with the fault. The tests after it were not run.
+pub fn run_batch<B>(dev: &mut B, tests: Vec<TestParameters>, 
+    cfg: &SamplingConfig) 
+    -> Result<(Vec<TestResult>, Option<BatchFault>), Error> 
+    where B: MeasurementBackend + ?Sized
+{
+    let layout = dev.layout()?;
+    let progs: Vec<&[u8]> = tests.iter().map(|t| &t.buf[..]).collect();
+    let outcome = dev.measure_batch(&progs, cfg)?;
+    let tests = tests.into_iter().take(outcome.len());
+    let results = tests.enumerate().map(|(idx, params)| {
+        let res = outcome.outcome(idx);
+        let test = TestResult {
+            params,
+            load_offset: outcome.results[idx].load_offset,
+            result: res.samples,
+            return_value: res.return_value,
+            regs: res.regs,
+            stats: res.stats,
+            layout,
+        };
+        test.warn_overflow();
+        test
+    }).collect();
+    Ok((results, outcome.fault))
 }
 
 /// Given some [TestParameters], sample a particular micro-op in user code 
 /// and return the result. 
-pub fn run_precise_test(
-    fd: i32, 
+pub fn run_precise_test<B>(
+    dev: &mut B, 
     params: &TestParameters,
     arg: ioctl::PreciseArgs,
 ) 
-    -> Box<[Sample]>
+    -> Result<MeasureOutcome, Error>
+    where B: MeasurementBackend + ?Sized
 {
-    let msg = params.to_userbuf();
-    let result = crate::measure_precise(fd, &msg, &arg);
-    result
+    dev.upload(&params.buf)?;
+    dev.measure_precise(&arg)
+}
+
+/// Given some [TestParameters], sample a range of micro-ops in user code 
+/// (uploading user code only once) and return the result.
+pub fn run_sweep_test<B>(
+    dev: &mut B, 
+    params: &TestParameters,
+    args: ioctl::SweepArgs,
+) 
+    -> Result<SweepOutcome, Error>
+    where B: MeasurementBackend + ?Sized
+{
+    dev.upload(&params.buf)?;
+    dev.measure_sweep(&args)
 }
 
 
 
 
+/// Return the samples whose (valid) instruction pointer is `tgt_rip`.
 pub fn filter_by_rip(samples: &[Sample], tgt_rip: usize) 
     -> impl Iterator<Item = &Sample> 
 {
-    samples.iter().filter(move |&x| x.rip == tgt_rip)
+    samples.iter().filter(move |&x| !x.data.rip_invalid() && x.rip == tgt_rip)
 }
 
-pub fn print_sample(s: &Sample) {
+/// Print the fields of a sample (decoded with a particular [IbsLayout]).
+pub fn print_sample(s: &Sample, layout: IbsLayout) {
+    let dec = DecodedSample::new(s, layout);
+    let d = &dec.op;
     //println!("[*] IbsOpCtl:  {:016x}", s.ctl.0);
-    println!("[*] IbsOpRip:   {:016x} (valid={})", 
-             s.rip, !s.data.rip_invalid());
+    println!("[*] IbsOpRip:   {} (valid={})", 
+             fmt_addr(dec.rip), dec.rip.is_some());
     println!("[*] IbsOpData:  {:016x}", s.data.0);
     //println!("  reserved bits (hi):         {:x}", s.data.res_hi());
     //println!("  reserved bits (lo):         {:x}", s.data.res_lo());
@@ -132,23 +254,34 @@ pub fn print_sample(s: &Sample) {
     if s.data.res_32() {       println!("  reserved bit 32?"); }
 
     println!("[*] IbsOpData2: {:016x}", s.data2.0);
-    if s.data2.data_src() != NbDataSrc::Invalid {
-        println!("  Data source: {:?}", s.data2.data_src());
-        if s.data2.data_src() == NbDataSrc::Cache {
+    if d.data_src != NbDataSrc::Invalid {
+        println!("  Data source: {:?}", d.data_src);
+        if d.data_src == NbDataSrc::Cache {
             println!("  Cache hit state: {}", s.data2.cache_hit_st());
         }
     }
     println!("[*] IbsOpData3: {:016x}", s.data3.0);
     if s.data3.0 != 0 {
         //println!("  res_lo:  {:04b}", s.data3.res_lo());
-        println!("  PhyAddr: {:016x} (valid={})", 
-            s.phyad, s.data3.dc_phy_addr_valid());
-        println!("  LinAddr: {:016x} (valid={})", 
-            s.linad, s.data3.dc_lin_addr_valid());
+        println!("  PhyAddr: {} (valid={})", 
+            fmt_addr(dec.phyad), dec.phyad.is_some());
+        println!("  LinAddr: {} (valid={})", 
+            fmt_addr(dec.linad), dec.linad.is_some());
         println!("  Width:   {:?}", s.data3.op_mem_width());
         if s.data3.st_op() { println!("  Store op"); }
         if s.data3.ld_op() { println!("  Load op"); }
 
+        if d.dc_l1tlb_miss {
+            println!("  L1 DTLB miss");
+        } else if d.dc_phy_addr_valid {
+            if let Some(pg_sz) = d.dc_l1tlb_pg_sz {
+                println!("  L1 DTLB page size: {:?}", pg_sz);
+            }
+            if d.dc_l1tlb_hit_2m == Some(true) { println!("  L1 DTLB hit (2M)"); }
+            if d.dc_l1tlb_hit_1g == Some(true) { println!("  L1 DTLB hit (1G)"); }
+        }
+        if d.dc_l2tlb_miss { println!("  L2 DTLB miss"); }
+
         if s.data3.sw_pf() { 
             println!("  Software prefetch op"); 
         }
@@ -172,23 +305,30 @@ pub fn print_sample(s: &Sample) {
         }
     }
 
-    if s.tgt_rip != 0 {
-    //if s.data.op_brn_ret() {
-        println!("[*] IbsTgtRip:  {:016x}", s.tgt_rip);  
+    if let Some(tgt_rip) = dec.tgt_rip {
+        println!("[*] IbsTgtRip:  {:016x}", tgt_rip);  
+    }
+    for w in layout.warnings(s).iter() {
+        println!("[!] Warning: {}", w);
     }
 
     println!("");
 }
 
 
-pub fn print_samples(samples: &[Sample], tgt_rip: usize) {
+pub fn print_samples(samples: &[Sample], tgt_rip: usize, layout: IbsLayout) {
     for (idx, s) in filter_by_rip(&samples, tgt_rip).enumerate() {
-        print_sample(&s);
+        print_sample(&s, layout);
     }
 }
 
 /// Print the latency distribution for all unique load operations.
-pub fn print_load_lat_dist(samples: &[Sample], tgt_rip: usize) {
+///
+/// The distribution is incomplete when the sample buffer overflowed
+/// (see [TestResult::overflowed]).
+pub fn print_load_lat_dist(samples: &[Sample], tgt_rip: usize, 
+    layout: IbsLayout) 
+{
     use std::collections::btree_map::{ BTreeMap, Entry };
 
     struct Stats { 
@@ -206,7 +346,7 @@ pub fn print_load_lat_dist(samples: &[Sample], tgt_rip: usize) {
 
     let mut stats: BTreeMap<MemoryAccess, Stats> = BTreeMap::new();
     for s in filter_by_rip(&samples, tgt_rip).filter(|x| x.data3.ld_op()) { 
-        if let Some(acc) = MemoryAccess::from_sample(s) {
+        if let Some(acc) = MemoryAccess::from_sample(s, layout) {
             match stats.entry(acc) {
                 Entry::Vacant(map) => { 
                     map.insert(Stats::new_from_sample(s));
@@ -236,7 +376,9 @@ pub fn print_load_lat_dist(samples: &[Sample], tgt_rip: usize) {
     println!("");
 }
 
-pub fn print_uniq_samples(samples: &[Sample], tgt_rip: usize) {
+pub fn print_uniq_samples(samples: &[Sample], tgt_rip: usize, 
+    layout: IbsLayout) 
+{
     use std::collections::HashSet;
     let mut uniq = HashSet::new();
     for s in filter_by_rip(&samples, tgt_rip) { 
@@ -244,37 +386,38 @@ pub fn print_uniq_samples(samples: &[Sample], tgt_rip: usize) {
     }
     for (idx, s) in uniq.iter().enumerate() { 
         println!("[*] Unique sample {}", idx);
-        print_sample(&s); 
+        print_sample(&s, layout); 
         println!("");
     }
 }
 
 /// Return a list of the *unique* memory accesses in some set of samples.
-pub fn get_uniq_accesses(samples: &[Sample], tgt_rip: usize) 
+///
+/// This accepts any iterator over samples, ie. a slice of samples, or a 
+/// [SampleStream] reading samples directly from the kernel module.
+pub fn get_uniq_accesses<I>(samples: I, tgt_rip: usize, layout: IbsLayout) 
     -> BTreeSet<MemoryAccess>
+    where I: IntoIterator, I::Item: Borrow<Sample>
 {
     let mut uniq_accesses = BTreeSet::<MemoryAccess>::new();
 
-    for sample in filter_by_rip(&samples, tgt_rip) {
-        if sample.data.rip_invalid() { 
+    for sample in samples {
+        let d = DecodedSample::new(sample.borrow(), layout);
+        if d.rip != Some(tgt_rip) { 
             continue; 
         }
-        let kind = match (sample.data3.st_op(), sample.data3.ld_op()) {
-            (true, false) => MemoryAccessKind::ST,
-            (false, true) => MemoryAccessKind::LD,
-            (true, true) => MemoryAccessKind::LDST,
-            (_, _) => continue,
-        };
-        let phys = sample.phyad;
-        let width = sample.data3.op_mem_width() as usize;
-        let access = MemoryAccess { phys, kind, width };
-        uniq_accesses.insert(access);
+        if let Some(access) = MemoryAccess::from_decoded(&d) {
+            uniq_accesses.insert(access);
+        }
     }
     uniq_accesses
 }
 
 
-pub fn print_uniq_map_accesses<K>(map: &BTreeMap<K, TestResult>, buf: usize)
+/// Print the memory accesses for each test in `map`. Accesses to the scratch
+/// page are labeled using `info`.
+pub fn print_uniq_map_accesses<K>(map: &BTreeMap<K, TestResult>, 
+    info: &IbstraceInfo)
     where K: Clone + Copy + Ord + std::fmt::Debug + std::fmt::LowerHex
 {
     let mut per_key_accs: BTreeMap<K, BTreeSet<MemoryAccess>> = BTreeMap::new();
@@ -282,8 +425,10 @@ pub fn print_uniq_map_accesses<K>(map: &BTreeMap<K, TestResult>, buf: usize)
     let mut common_accs = BTreeSet::new();
 
     for (key, test) in map {
-        let tgt_rip = buf + test.params.tgt_instr_off;
-        per_key_accs.insert(*key, get_uniq_accesses(&test.result, tgt_rip));
+        let tgt_rip = test.target_rip(info.code_buf);
+        per_key_accs.insert(*key, 
+            get_uniq_accesses(&test.result, tgt_rip, test.layout)
+        );
     }
     for (cur_key, accs) in &per_key_accs {
         let mut uniq = Vec::new();
@@ -304,14 +449,16 @@ pub fn print_uniq_map_accesses<K>(map: &BTreeMap<K, TestResult>, buf: usize)
 
     println!("Common accesses (among all keys):");
     for acc in common_accs.iter() {
-        println!("{:016x} {:02} {:?}", acc.phys, acc.width, acc.kind);
+        println!("{:016x} {:02} {:?} {}", 
+                 acc.phys, acc.width, acc.kind, acc.label(info));
     }
     println!("");
 
     for (key, accs) in unique_accs {
         println!("Unique accesses for key {:08x?}:", key);
         for acc in accs.iter() {
-            println!("  {:016x} {:02} {:?}", acc.phys, acc.width, acc.kind);
+            println!("  {:016x} {:02} {:?} {}", 
+                     acc.phys, acc.width, acc.kind, acc.label(info));
         }
         println!("");
     }
@@ -319,9 +466,67 @@ pub fn print_uniq_map_accesses<K>(map: &BTreeMap<K, TestResult>, buf: usize)
     for (cur_key, accs) in &per_key_accs {
         println!("All accesses for key {:08x?}", cur_key);
         for acc in accs.iter() {
-            println!("  {:016x} {:02} {:?}", acc.phys, acc.width, acc.kind);
+            println!("  {:016x} {:02} {:?} {}", 
+                     acc.phys, acc.width, acc.kind, acc.label(info));
         }
         println!("");
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::*;
+
+    #[test]
+    fn uniq_accesses_from_stream() {
+        let tgt_rip = 0xffff_c900_0000_0040;
+        let runs: Vec<Vec<u8>> = (0..4usize).map(|run| {
+            (0..0x100usize).flat_map(|i| SampleBuilder::new()
+                .with_rip(if i % 2 == 0 { tgt_rip } else { tgt_rip + 1 })
+                .with_load(IbsMemWidth::Qword)
+                .with_phyad(0x1000 * run + 0x8 * (i % 4))
+                .build().to_le_bytes().to_vec()).collect()
+        }).collect();
+
+        // Merge the samples from all runs without collecting them
+        let mut streams: Vec<_> = runs.iter()
+            .map(|r| SampleStream::with_chunk_samples(&r[..], 0x10))
+            .collect();
+        let accs = get_uniq_accesses(streams.iter_mut().flatten(), tgt_rip, 
+            IbsLayout::default()
+        );
+        for stream in streams {
+            assert_eq!(stream.finish(), Ok(0x100));
+        }
+        assert_eq!(accs.len(), 4 * 2);
+        assert!(accs.iter().all(|a| a.phys % 0x10 == 0));
+    }
+
+    #[test]
+    fn skip_invalid_addresses() {
+        let tgt_rip = 0xffff_c900_0000_0040;
+        let samples = vec![
+            // Valid load
+            SampleBuilder::new().with_rip(tgt_rip)
+                .with_load(IbsMemWidth::Qword).with_phyad(0x1000).build(),
+            // Leftover physical address without the valid bit
+            Sample { phyad: 0x2000, ..SampleBuilder::new().with_rip(tgt_rip)
+                .with_load(IbsMemWidth::Qword).build() },
+            // Invalid RIP
+            SampleBuilder::new().with_rip(tgt_rip)
+                .with_data(|d| d.set_rip_invalid(true))
+                .with_store(IbsMemWidth::Byte).with_phyad(0x3000).build(),
+        ];
+        let layout = IbsLayout::Zen3;
+        let accs = get_uniq_accesses(&samples, tgt_rip, layout);
+        assert_eq!(accs.len(), 1);
+        assert_eq!(accs.iter().next().unwrap().phys, 0x1000);
+        assert_eq!(filter_by_rip(&samples, tgt_rip).count(), 2);
+        assert_eq!(MemoryAccess::from_sample(&samples[1], layout), None);
+
+        let info = SampleInfo::from_sample(&samples[1], layout);
+        assert_eq!((info.phyad, info.tgt_rip), (None, None));
+        assert!(info.to_string().contains("phy=---"));
+    }
+}
@@ -0,0 +1,1394 @@
+//! Recording and replaying sessions with a [MeasurementBackend].
+//!
+//! A [RecordingBackend] wraps some other backend and appends every exchange
+//! (uploaded code, precise-mode arguments, raw sample data, and queries like
+//! the base address or module information) to a single archive file. A 
+//! [ReplayBackend] serves the contents of an archive back to the same 
+//! sequence of calls, so analyses can be repeated without access to the 
+//! original machine.
+//!
+//! # Archive format
+//! An archive begins with an 8-byte magic value and a 32-bit version number,
+//! followed by a sequence of records. Each record consists of a 1-byte tag,
+//! a 64-bit payload length, and the payload itself. Every payload begins with
+//! a 32-bit status (zero on success, otherwise the errno associated with the
+//! failure). All integers are little-endian.
+//!
+//! When measured code faults, the status is `EFAULT` and the arguments of 
+//! the measurement are followed by the exception vector, the address of the
+//! faulting instruction, and the error code (see [Failure]).
+//!
+//! Successful measurements record the final register state of user code 
+//! (see [Registers]) and the [SampleStats] for the measurement, followed by 
+//! the samples as [SampleFormat::V2] records (including any [SampleMeta]).
+//!
+//! Records are flushed as soon as they are written, so the archive remains
+//! usable even if the machine crashes during a session. A truncated trailing
+//! record is ignored when reading an archive.
+
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{ Read, Write, BufReader };
+use std::path::Path;
+
+use nix::errno::Errno;
+
+use crate::*;
+use crate::ioctl::{ BatchResult, PreciseArgs, SweepArgs };
+
+const MAGIC: &[u8; 8] = b"IBSTSESS";
+const VERSION: u32 = 1;
+
+const TAG_UPLOAD: u8        = 0x01;
+const TAG_MEASURE: u8       = 0x02;
+const TAG_PRECISE: u8       = 0x03;
+const TAG_SET_CPU: u8       = 0x04;
+const TAG_FETCH: u8         = 0x05;
+const TAG_SWEEP: u8         = 0x06;
+const TAG_BATCH: u8         = 0x07;
+const TAG_UPLOAD_DATA: u8   = 0x08;
+const TAG_SAMPLE_COUNT: u8  = 0x10;
+const TAG_CAPACITY: u8      = 0x11;
+const TAG_BASE_ADDRESS: u8  = 0x12;
+const TAG_INFO: u8          = 0x13;
+const TAG_READ_SCRATCH: u8  = 0x14;
+const TAG_LAYOUT: u8        = 0x15;
+
+/// The reason a measurement failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The request failed with some errno.
+    Errno(Errno),
+    /// Measured code faulted (see [Error::MeasuredCodeFault]).
+    Fault { vector: usize, rip: usize, error_code: usize },
+}
+impl Failure {
+    /// Return the failure associated with some [Error].
+    fn of(e: &Error) -> Self {
+        match e {
+            Error::MeasuredCodeFault { vector, rip, error_code } => Self::Fault {
+                vector: *vector, rip: *rip, error_code: *error_code
+            },
+            e => Self::Errno(errno_of(e)),
+        }
+    }
+
+    /// Convert this back into an [Error] for a measurement command.
+    fn to_error(self, cmd: usize) -> Error {
+        match self {
+            Self::Errno(errno) => Error::Ioctl { cmd, errno },
+            Self::Fault { vector, rip, error_code } => 
+                Error::MeasuredCodeFault { vector, rip, error_code },
+        }
+    }
+}
+impl From<Failure> for Errno {
+    fn from(f: Failure) -> Self {
+        match f {
+            Failure::Errno(errno) => errno,
+            Failure::Fault { .. } => Errno::EFAULT,
+        }
+    }
+}
+
+/// A single exchange with a [MeasurementBackend].
+///
+/// Failed requests only preserve the errno associated with the [Error]
+/// (or the fault taken by measured code, see [Failure]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Exchange {
+    /// User code was uploaded.
+    Upload { code: Vec<u8>, res: Result<(), Errno> },
+    /// Data was uploaded to the scratch page.
+    UploadData { data: Vec<u8>, res: Result<(), Errno> },
+    /// The core used to run user code was changed.
+    SetTargetCpu { cpu: usize, res: Result<(), Errno> },
+    /// Uploaded code was measured.
+    Measure { config: SamplingConfig, res: Result<MeasureOutcome, Failure> },
+    /// A list of programs was uploaded and measured.
+    Batch {
+        progs: Vec<Vec<u8>>,
+        config: SamplingConfig,
+        res: Result<BatchOutcome, Failure>
+    },
+    /// Uploaded code was measured with fetch sampling.
+    Fetch { config: FetchConfig, res: Result<FetchOutcome, Failure> },
+    /// A particular micro-op was measured.
+    Precise { arg: usize, offset: usize, res: Result<MeasureOutcome, Failure> },
+    /// A range of micro-ops was measured.
+    Sweep { args: SweepArgs, res: Result<SweepOutcome, Failure> },
+    /// The number of collected samples was queried.
+    SampleCount { res: Result<usize, Errno> },
+    /// The sample capacity was queried.
+    Capacity { res: Result<usize, Errno> },
+    /// The base address of the code buffer was queried.
+    BaseAddress { res: Result<usize, Errno> },
+    /// Information about the kernel module was queried.
+    Info { res: Result<IbstraceInfo, Errno> },
+    /// The contents of the scratch page were read back.
+    ReadScratch { res: Result<Vec<u8>, Errno> },
+    /// The layout used to decode samples was queried (only recorded when 
+    /// the query succeeds).
+    Layout { layout: IbsLayout },
+}
+impl Exchange {
+    /// Return `true` if this exchange doesn't change the state of the backend.
+    fn is_query(&self) -> bool {
+        matches!(self,
+            Self::SampleCount { .. } | Self::Capacity { .. } |
+            Self::BaseAddress { .. } | Self::Info { .. } |
+            Self::ReadScratch { .. } | Self::Layout { .. }
+        )
+    }
+
+    /// Encode this exchange as a record.
+    fn write_to(&self, w: &mut impl Write) -> std::io::Result<()> {
+        fn word(res: &Result<usize, Errno>) -> Vec<u8> {
+            match res {
+                Ok(x) => (*x as u64).to_le_bytes().to_vec(),
+                Err(_) => Vec::new()
+            }
+        }
+
+        let (tag, status, payload) = match self {
+            Self::Upload { code, res } => {
+                (TAG_UPLOAD, status(res), code.clone())
+            },
+            Self::UploadData { data, res } => {
+                (TAG_UPLOAD_DATA, status(res), data.clone())
+            },
+            Self::SetTargetCpu { cpu, res } => {
+                (TAG_SET_CPU, status(res), (*cpu as u64).to_le_bytes().to_vec())
+            },
+            Self::Measure { config, res } => {
+                encode_measure(config, res.as_ref().map_err(|f| *f))
+            },
+            Self::Batch { progs, config, res } => {
+                encode_batch(progs, config, res.as_ref().map_err(|f| *f))
+            },
+            Self::Fetch { config, res } => {
+                encode_fetch(config, res.as_ref().map_err(|f| *f))
+            },
+            Self::Precise { arg, offset, res } => {
+                encode_precise(*arg, *offset, res.as_ref().map_err(|f| *f))
+            },
+            Self::Sweep { args, res } => {
+                encode_sweep(args, res.as_ref().map_err(|f| *f))
+            },
+            Self::SampleCount { res } => (TAG_SAMPLE_COUNT, status(res), word(res)),
+            Self::Capacity { res } => (TAG_CAPACITY, status(res), word(res)),
+            Self::BaseAddress { res } => (TAG_BASE_ADDRESS, status(res), word(res)),
+            Self::Info { res } => {
+                let payload = match res {
+                    Ok(info) => info_to_words(info).iter()
+                        .flat_map(|w| (*w as u64).to_le_bytes()).collect(),
+                    Err(_) => Vec::new(),
+                };
+                (TAG_INFO, status(res), payload)
+            },
+            Self::ReadScratch { res } => {
+                let payload = res.clone().unwrap_or_default();
+                (TAG_READ_SCRATCH, status(res), payload)
+            },
+            Self::Layout { layout } => {
+                let payload = (layout_to_word(*layout) as u64).to_le_bytes();
+                (TAG_LAYOUT, 0, payload.to_vec())
+            },
+        };
+        write_record(w, tag, status, &payload)
+    }
+
+    /// Decode an exchange from a record.
+    fn from_record(tag: u8, payload: &[u8]) -> Result<Self, Error> {
+        let malformed = |what| Error::Archive(format!("malformed {} record", what));
+        if payload.len() < 4 {
+            return Err(malformed("short"));
+        }
+        let (status, body) = payload.split_at(4);
+        let status = i32::from_le_bytes(status.try_into().unwrap());
+        let res: Result<&[u8], Errno> = match status {
+            0 => Ok(body),
+            e => Err(Errno::from_raw(e)),
+        };
+        let outcome = |b: &[u8]| -> Result<MeasureOutcome, Error> {
+            if b.len() < Registers::SIZE {
+                return Err(malformed("measurement"));
+            }
+            let (regs, samples) = b.split_at(Registers::SIZE);
+            let regs = Registers::from_le_bytes(regs.try_into().unwrap());
+            let (stats, samples) = stats_from_bytes(samples)?;
+            Ok(MeasureOutcome::new(samples_from_bytes(samples)?, regs)
+                .with_stats(stats))
+        };
+        // The remainder of a failed measurement record
+        let failure = |errno: Errno, b: &[u8]| -> Result<Failure, Error> {
+            match (errno, b.len()) {
+                (_, 0) => Ok(Failure::Errno(errno)),
+                (Errno::EFAULT, 24) => {
+                    let word = |i: usize| {
+                        u64::from_le_bytes(b[i..i+8].try_into().unwrap()) as usize
+                    };
+                    Ok(Failure::Fault { 
+                        vector: word(0), rip: word(8), error_code: word(16)
+                    })
+                },
+                _ => Err(malformed("failed measurement")),
+            }
+        };
+        let word = |res: Result<&[u8], Errno>| -> Result<Result<usize, Errno>, Error> {
+            match res {
+                Ok(b) => {
+                    let b: [u8; 8] = b.try_into().map_err(|_| malformed("query"))?;
+                    Ok(Ok(u64::from_le_bytes(b) as usize))
+                },
+                Err(e) => Ok(Err(e)),
+            }
+        };
+
+        match tag {
+            TAG_UPLOAD => Ok(Self::Upload {
+                code: body.to_vec(),
+                res: res.map(|_| ()),
+            }),
+            TAG_UPLOAD_DATA => Ok(Self::UploadData {
+                data: body.to_vec(),
+                res: res.map(|_| ()),
+            }),
+            TAG_SET_CPU => {
+                let cpu: [u8; 8] = body.try_into()
+                    .map_err(|_| malformed("set cpu"))?;
+                Ok(Self::SetTargetCpu {
+                    cpu: u64::from_le_bytes(cpu) as usize,
+                    res: res.map(|_| ()),
+                })
+            },
+            TAG_MEASURE => {
+                if body.len() < 24 {
+                    return Err(malformed("measurement"));
+                }
+                let (args, body) = body.split_at(24);
+                let word = |i: usize| {
+                    u64::from_le_bytes(args[i..i+8].try_into().unwrap()) as usize
+                };
+                let args = ioctl::MeasureArgs::new(word(0), word(8), word(16));
+                Ok(Self::Measure {
+                    config: SamplingConfig::from_args(&args),
+                    res: match res { 
+                        Ok(_) => Ok(outcome(body)?), 
+                        Err(e) => Err(failure(e, body)?),
+                    },
+                })
+            },
+            TAG_BATCH => {
+                let mut cur = Cursor(body);
+                let malformed = || malformed("batch");
+                let args = ioctl::MeasureArgs::new(
+                    cur.word().ok_or_else(malformed)?,
+                    cur.word().ok_or_else(malformed)?,
+                    cur.word().ok_or_else(malformed)?,
+                );
+                let num_progs = cur.word().ok_or_else(malformed)?;
+                let mut progs = Vec::new();
+                for _ in 0..num_progs {
+                    let len = cur.word().ok_or_else(malformed)?;
+                    progs.push(cur.take(len).ok_or_else(malformed)?.to_vec());
+                }
+                let res = match res {
+                    Ok(_) => {
+                        let num_results = cur.word().ok_or_else(malformed)?;
+                        if num_results > num_progs {
+                            return Err(malformed());
+                        }
+                        let mut results = Vec::with_capacity(num_results);
+                        for _ in 0..num_results {
+                            let load_offset = cur.word().ok_or_else(malformed)?;
+                            let first = cur.word().ok_or_else(malformed)?;
+                            let count = cur.word().ok_or_else(malformed)?;
+                            let dropped = cur.word().ok_or_else(malformed)?;
+                            let spurious = cur.word().ok_or_else(malformed)?;
+                            let regs = cur.take(Registers::SIZE)
+                                .ok_or_else(malformed)?;
+                            let regs = Registers::from_le_bytes(regs.try_into().unwrap());
+                            results.push(BatchResult { 
+                                load_offset, first, count, dropped, spurious, regs,
+                                fault: Default::default(),
+                            });
+                        }
+                        // The remaining programs only go unrun after a fault
+                        let mut fault = [0; 4];
+                        for w in fault.iter_mut() {
+                            *w = cur.word().ok_or_else(malformed)?;
+                        }
+                        if (fault[0] != 0) != (num_results < num_progs) {
+                            return Err(malformed());
+                        }
+                        let mut outcome = BatchOutcome::new(
+                            samples_from_bytes(cur.0)?,
+                            results.into_boxed_slice()
+                        );
+                        if fault[0] != 0 {
+                            outcome = outcome.with_fault(BatchFault {
+                                index: num_results, 
+                                vector: fault[1], 
+                                rip: fault[2], 
+                                error_code: fault[3],
+                            });
+                        }
+                        outcome.validate().map_err(|_| malformed())?;
+                        Ok(outcome)
+                    },
+                    Err(e) => Err(failure(e, cur.0)?),
+                };
+                Ok(Self::Batch {
+                    progs,
+                    config: SamplingConfig::from_args(&args),
+                    res
+                })
+            },
+            TAG_FETCH => {
+                if body.len() < 16 {
+                    return Err(malformed("fetch"));
+                }
+                let (args, body) = body.split_at(16);
+                let fetch_ctl = u64::from_le_bytes(args[0..8].try_into().unwrap());
+                let warmup = u64::from_le_bytes(args[8..16].try_into().unwrap());
+                let args = ioctl::FetchArgs::new(fetch_ctl as usize, warmup as usize);
+                let res = match res {
+                    Ok(_) => {
+                        if body.len() < Registers::SIZE {
+                            return Err(malformed("fetch"));
+                        }
+                        let (regs, samples) = body.split_at(Registers::SIZE);
+                        let regs = Registers::from_le_bytes(regs.try_into().unwrap());
+                        let (stats, samples) = stats_from_bytes(samples)?;
+                        Ok(FetchOutcome::new(FetchSample::from_bytes(samples)?, regs)
+                            .with_stats(stats))
+                    },
+                    Err(e) => Err(failure(e, body)?),
+                };
+                Ok(Self::Fetch { config: FetchConfig::from_args(&args), res })
+            },
+            TAG_PRECISE => {
+                if body.len() < 16 {
+                    return Err(malformed("precise"));
+                }
+                let (args, samples) = body.split_at(16);
+                let arg = u64::from_le_bytes(args[0..8].try_into().unwrap());
+                let offset = u64::from_le_bytes(args[8..16].try_into().unwrap());
+                Ok(Self::Precise {
+                    arg: arg as usize,
+                    offset: offset as usize,
+                    res: match res {
+                        Ok(_) => Ok(outcome(samples)?),
+                        Err(e) => Err(failure(e, samples)?),
+                    },
+                })
+            },
+            TAG_SWEEP => {
+                if body.len() < 24 {
+                    return Err(malformed("sweep"));
+                }
+                let (args, body) = body.split_at(24);
+                let word = |b: &[u8], i: usize| {
+                    u64::from_le_bytes(b[i..i+8].try_into().unwrap()) as usize
+                };
+                let (arg, first, count) = (word(args, 0), word(args, 8), word(args, 16));
+                let args = count.checked_sub(1)
+                    .and_then(|n| first.checked_add(n))
+                    .and_then(|last| SweepArgs::new(arg, first..=last).ok())
+                    .ok_or_else(|| malformed("sweep"))?;
+                let res = match res {
+                    Ok(_) => {
+                        if body.len() < Registers::SIZE {
+                            return Err(malformed("sweep"));
+                        }
+                        let (regs, entries) = body.split_at(Registers::SIZE);
+                        let regs = Registers::from_le_bytes(regs.try_into().unwrap());
+                        let (stats, entries) = stats_from_bytes(entries)?;
+                        let entry_size = 8 + SAMPLE_FORMAT.record_size();
+                        if !entries.len().is_multiple_of(entry_size) {
+                            return Err(malformed("sweep"));
+                        }
+                        let mut samples = vec![None; count];
+                        for entry in entries.chunks_exact(entry_size) {
+                            let idx = word(entry, 0);
+                            let slot = samples.get_mut(idx)
+                                .ok_or_else(|| malformed("sweep"))?;
+                            *slot = Some(Sample::from_record(SAMPLE_FORMAT, &entry[8..]));
+                        }
+                        Ok(SweepOutcome::new(first, samples.into_boxed_slice(), regs)
+                            .with_stats(stats))
+                    },
+                    Err(e) => Err(failure(e, body)?),
+                };
+                Ok(Self::Sweep { args, res })
+            },
+            TAG_SAMPLE_COUNT => Ok(Self::SampleCount { res: word(res)? }),
+            TAG_CAPACITY => Ok(Self::Capacity { res: word(res)? }),
+            TAG_BASE_ADDRESS => Ok(Self::BaseAddress { res: word(res)? }),
+            TAG_LAYOUT => match word(res)? {
+                Ok(w) => Ok(Self::Layout {
+                    layout: layout_from_word(w).ok_or_else(|| malformed("layout"))?,
+                }),
+                Err(_) => Err(malformed("layout")),
+            },
+            TAG_READ_SCRATCH => Ok(Self::ReadScratch {
+                res: res.map(|b| b.to_vec()),
+            }),
+            TAG_INFO => {
+                let res = match res {
+                    Ok(b) => {
+                        if b.len() != 8 * INFO_WORDS {
+                            return Err(malformed("info"));
+                        }
+                        let mut words = [0usize; INFO_WORDS];
+                        for (w, b) in words.iter_mut().zip(b.chunks_exact(8)) {
+                            *w = u64::from_le_bytes(b.try_into().unwrap()) as usize;
+                        }
+                        Ok(info_from_words(&words))
+                    },
+                    Err(e) => Err(e),
+                };
+                Ok(Self::Info { res })
+            },
+            _ => Err(Error::Archive(format!("unknown record tag {:#x}", tag))),
+        }
+    }
+}
+
+/// Reads consecutive fields from the payload of a record.
+struct Cursor<'a>(&'a [u8]);
+impl<'a> Cursor<'a> {
+    /// Take the next `n` bytes.
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    /// Take the next 64-bit word.
+    fn word(&mut self) -> Option<usize> {
+        let b = self.take(8)?;
+        Some(u64::from_le_bytes(b.try_into().unwrap()) as usize)
+    }
+}
+
+/// Number of words in an encoded [IbstraceInfo].
+///
+/// A missing [IbstraceInfo::page_offset] is encoded as zero (the direct map
+/// never starts at zero).
+const INFO_WORDS: usize = 8;
+
+fn info_to_words(info: &IbstraceInfo) -> [usize; INFO_WORDS] {
+    [
+        info.code_buf, info.sample_buf, info.scratch_page,
+        info.scratch_page_paddr, info.sample_capacity,
+        info.code_buf_max_size, info.target_cpu, info.page_offset.unwrap_or(0),
+    ]
+}
+
+fn info_from_words(w: &[usize; INFO_WORDS]) -> IbstraceInfo {
+    IbstraceInfo {
+        code_buf: w[0],
+        sample_buf: w[1],
+        scratch_page: w[2],
+        scratch_page_paddr: w[3],
+        sample_capacity: w[4],
+        code_buf_max_size: w[5],
+        target_cpu: w[6],
+        page_offset: if w[7] != 0 { Some(w[7]) } else { None },
+    }
+}
+
+fn layout_to_word(layout: IbsLayout) -> usize {
+    match layout {
+        IbsLayout::Zen2 => 2,
+        IbsLayout::Zen3 => 3,
+        IbsLayout::Zen4 => 4,
+    }
+}
+
+fn layout_from_word(w: usize) -> Option<IbsLayout> {
+    match w {
+        2 => Some(IbsLayout::Zen2),
+        3 => Some(IbsLayout::Zen3),
+        4 => Some(IbsLayout::Zen4),
+        _ => None,
+    }
+}
+
+/// Split the [SampleStats] from the start of a measurement body.
+fn stats_from_bytes(bytes: &[u8]) -> Result<(SampleStats, &[u8]), Error> {
+    if bytes.len() < SampleStats::SIZE {
+        return Err(Error::Archive("malformed measurement record".to_string()));
+    }
+    let (stats, rest) = bytes.split_at(SampleStats::SIZE);
+    Ok((SampleStats::from_le_bytes(stats.try_into().unwrap()), rest))
+}
+
+/// The format of sample records in an archive.
+const SAMPLE_FORMAT: SampleFormat = SampleFormat::V2;
+
+/// Convert samples into raw sample data.
+fn samples_to_bytes(samples: &[Sample]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_record(SAMPLE_FORMAT)).collect()
+}
+
+/// Convert raw sample data into samples.
+fn samples_from_bytes(bytes: &[u8]) -> Result<Box<[Sample]>, Error> {
+    Ok(SampleBuffer::with_format(bytes, SAMPLE_FORMAT)?.iter().collect())
+}
+
+/// Write a single record.
+fn write_record(w: &mut impl Write, tag: u8, status: i32, payload: &[u8]) 
+    -> std::io::Result<()>
+{
+    let len = (4 + payload.len()) as u64;
+    w.write_all(&[tag])?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&status.to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+// The measurement records are encoded from a borrowed outcome, so that a
+// [RecordingBackend] doesn't need to copy samples in order to record them.
+
+/// An encoded record (the tag, status, and the rest of the payload).
+type Record = (u8, i32, Vec<u8>);
+
+/// Return the status recorded for some result.
+fn status<T, E: Copy>(res: &Result<T, E>) -> i32 where Errno: From<E> {
+    match res { Ok(_) => 0, Err(e) => Errno::from(*e) as i32 }
+}
+
+/// Encode the fault taken by measured code (if any).
+fn fault_to_bytes<T>(res: &Result<T, Failure>) -> Vec<u8> {
+    match res {
+        Err(Failure::Fault { vector, rip, error_code }) => {
+            [*vector, *rip, *error_code].iter()
+                .flat_map(|w| (*w as u64).to_le_bytes()).collect()
+        },
+        _ => Vec::new()
+    }
+}
+
+/// Encode the body of a measurement record.
+fn outcome_to_bytes(res: &Result<&MeasureOutcome, Failure>) -> Vec<u8> {
+    match res {
+        Ok(x) => {
+            let mut body = x.regs.to_le_bytes().to_vec();
+            body.extend_from_slice(&x.stats.to_le_bytes());
+            body.extend(samples_to_bytes(&x.samples));
+            body
+        },
+        Err(_) => fault_to_bytes(res)
+    }
+}
+
+fn encode_measure(config: &SamplingConfig, res: Result<&MeasureOutcome, Failure>)
+    -> Record
+{
+    let args = config.to_args();
+    let mut payload = Vec::new();
+    for word in [args.op_ctl(), args.flags(), args.warmup()] {
+        payload.extend_from_slice(&(word as u64).to_le_bytes());
+    }
+    payload.extend(outcome_to_bytes(&res));
+    (TAG_MEASURE, status(&res), payload)
+}
+
+fn encode_batch(progs: &[impl AsRef<[u8]>], config: &SamplingConfig, 
+    res: Result<&BatchOutcome, Failure>) -> Record
+{
+    let args = config.to_args();
+    let mut payload = Vec::new();
+    for word in [args.op_ctl(), args.flags(), args.warmup(), progs.len()] {
+        payload.extend_from_slice(&(word as u64).to_le_bytes());
+    }
+    for prog in progs {
+        payload.extend_from_slice(&(prog.as_ref().len() as u64).to_le_bytes());
+        payload.extend_from_slice(prog.as_ref());
+    }
+    // Results are followed by the fault which stopped the batch
+    // (with a zero 'valid' word if no program faulted)
+    if let Ok(x) = res {
+        payload.extend_from_slice(&(x.results.len() as u64).to_le_bytes());
+        for r in x.results.iter() {
+            for word in [r.load_offset, r.first, r.count, r.dropped, r.spurious] {
+                payload.extend_from_slice(&(word as u64).to_le_bytes());
+            }
+            payload.extend_from_slice(&r.regs.to_le_bytes());
+        }
+        let fault = match x.fault {
+            Some(f) => [1, f.vector, f.rip, f.error_code],
+            None => [0; 4],
+        };
+        for word in fault {
+            payload.extend_from_slice(&(word as u64).to_le_bytes());
+        }
+        payload.extend(samples_to_bytes(&x.samples));
+    }
+    payload.extend(fault_to_bytes(&res));
+    (TAG_BATCH, status(&res), payload)
+}
+
+fn encode_fetch(config: &FetchConfig, res: Result<&FetchOutcome, Failure>) 
+    -> Record
+{
+    let args = config.to_args();
+    let mut payload = Vec::new();
+    for word in [args.fetch_ctl(), args.warmup()] {
+        payload.extend_from_slice(&(word as u64).to_le_bytes());
+    }
+    if let Ok(x) = res {
+        payload.extend_from_slice(&x.regs.to_le_bytes());
+        payload.extend_from_slice(&x.stats.to_le_bytes());
+        for s in x.samples.iter() {
+            payload.extend_from_slice(&s.to_le_bytes());
+        }
+    }
+    payload.extend(fault_to_bytes(&res));
+    (TAG_FETCH, status(&res), payload)
+}
+
+fn encode_precise(arg: usize, offset: usize, res: Result<&MeasureOutcome, Failure>)
+    -> Record
+{
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(arg as u64).to_le_bytes());
+    payload.extend_from_slice(&(offset as u64).to_le_bytes());
+    payload.extend(outcome_to_bytes(&res));
+    (TAG_PRECISE, status(&res), payload)
+}
+
+fn encode_sweep(args: &SweepArgs, res: Result<&SweepOutcome, Failure>) -> Record {
+    let mut payload = Vec::new();
+    for word in [args.arg(), args.first(), args.count()] {
+        payload.extend_from_slice(&(word as u64).to_le_bytes());
+    }
+    // Only offsets with a sample are recorded (with their index)
+    if let Ok(x) = res {
+        payload.extend_from_slice(&x.regs.to_le_bytes());
+        payload.extend_from_slice(&x.stats.to_le_bytes());
+        for (idx, s) in x.samples.iter().enumerate() {
+            if let Some(s) = s {
+                payload.extend_from_slice(&(idx as u64).to_le_bytes());
+                payload.extend(s.to_record(SAMPLE_FORMAT));
+            }
+        }
+    }
+    payload.extend(fault_to_bytes(&res));
+    (TAG_SWEEP, status(&res), payload)
+}
+
+/// Read all exchanges from an archive.
+pub fn read_archive(r: impl Read) -> Result<Vec<Exchange>, Error> {
+    let mut data = Vec::new();
+    let mut r = BufReader::new(r);
+    r.read_to_end(&mut data).map_err(|e| Error::Archive(e.to_string()))?;
+
+    if data.len() < 12 || &data[0..8] != MAGIC {
+        return Err(Error::Archive("not a session archive".to_string()));
+    }
+    let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    if version != VERSION {
+        return Err(Error::Archive(format!("unsupported version {}", version)));
+    }
+
+    let mut res = Vec::new();
+    let mut cur = &data[12..];
+    while cur.len() >= 9 {
+        let tag = cur[0];
+        let len = u64::from_le_bytes(cur[1..9].try_into().unwrap()) as usize;
+        match cur[9..].get(..len) {
+            Some(payload) => {
+                res.push(Exchange::from_record(tag, payload)?)
+            },
+            // Truncated trailing record (ie. from a crash while recording)
+            None => break,
+        }
+        cur = &cur[9 + len..];
+    }
+    Ok(res)
+}
+
+
+/// Wraps some [MeasurementBackend], recording all exchanges to an archive.
+///
+/// The [IbsLayout] of the wrapped backend is recorded up front, so that 
+/// replayed samples are decoded with the layout of the original machine.
+pub struct RecordingBackend<B, W: Write = File> {
+    inner: B,
+    out: RefCell<W>,
+}
+impl<B: MeasurementBackend> RecordingBackend<B, File> {
+    /// Record all exchanges with `inner` into a new archive file.
+    pub fn create(inner: B, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let f = File::create(path).map_err(|e| Error::Archive(e.to_string()))?;
+        Self::new(inner, f)
+    }
+}
+impl<B: MeasurementBackend, W: Write> RecordingBackend<B, W> {
+    /// Record all exchanges with `inner` into some writer.
+    pub fn new(inner: B, mut out: W) -> Result<Self, Error> {
+        out.write_all(MAGIC)
+            .and_then(|_| out.write_all(&VERSION.to_le_bytes()))
+            .and_then(|_| out.flush())
+            .map_err(|e| Error::Archive(e.to_string()))?;
+        let res = Self { inner, out: RefCell::new(out) };
+        if let Ok(layout) = res.inner.layout() {
+            res.record(Exchange::Layout { layout })?;
+        }
+        Ok(res)
+    }
+
+    /// Stop recording, returning the wrapped backend and writer.
+    pub fn into_inner(self) -> (B, W) {
+        (self.inner, self.out.into_inner())
+    }
+
+    fn record(&self, exchange: Exchange) -> Result<(), Error> {
+        exchange.write_to(&mut *self.out.borrow_mut())
+            .map_err(|e| Error::Archive(e.to_string()))
+    }
+
+    fn write(&self, (tag, status, payload): Record) -> Result<(), Error> {
+        write_record(&mut *self.out.borrow_mut(), tag, status, &payload)
+            .map_err(|e| Error::Archive(e.to_string()))
+    }
+}
+
+/// Return the errno associated with some [Error].
+fn errno_of(e: &Error) -> Errno {
+    e.errno().unwrap_or(Errno::UnknownErrno)
+}
+
+impl<B: MeasurementBackend, W: Write> MeasurementBackend for RecordingBackend<B, W> {
+    fn upload(&mut self, code: &[u8]) -> Result<(), Error> {
+        let res = self.inner.upload(code);
+        self.record(Exchange::Upload {
+            code: code.to_vec(),
+            res: res.as_ref().map(|_| ()).map_err(errno_of),
+        })?;
+        res
+    }
+
+    fn upload_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        let res = self.inner.upload_data(data);
+        self.record(Exchange::UploadData {
+            data: data.to_vec(),
+            res: res.as_ref().map(|_| ()).map_err(errno_of),
+        })?;
+        res
+    }
+
+    fn read_scratch(&self) -> Result<Box<[u8]>, Error> {
+        let res = self.inner.read_scratch();
+        self.record(Exchange::ReadScratch {
+            res: res.as_ref().map(|b| b.to_vec()).map_err(errno_of),
+        })?;
+        res
+    }
+
+    fn set_target_cpu(&mut self, cpu: usize) -> Result<(), Error> {
+        let res = self.inner.set_target_cpu(cpu);
+        self.record(Exchange::SetTargetCpu {
+            cpu,
+            res: res.as_ref().map(|_| ()).map_err(errno_of),
+        })?;
+        res
+    }
+
+    fn measure_with(&mut self, cfg: &SamplingConfig)
+        -> Result<MeasureOutcome, Error>
+    {
+        let res = self.inner.measure_with(cfg);
+        self.write(encode_measure(cfg, res.as_ref().map_err(Failure::of)))?;
+        res
+    }
+
+    fn measure_precise(&mut self, arg: &PreciseArgs)
+        -> Result<MeasureOutcome, Error>
+    {
+        let res = self.inner.measure_precise(arg);
+        self.write(encode_precise(arg.arg(), arg.offset(), 
+            res.as_ref().map_err(Failure::of)
+        ))?;
+        res
+    }
+
+    fn measure_batch(&mut self, progs: &[&[u8]], cfg: &SamplingConfig)
+        -> Result<BatchOutcome, Error>
+    {
+        let res = self.inner.measure_batch(progs, cfg);
+        self.write(encode_batch(progs, cfg, res.as_ref().map_err(Failure::of)))?;
+        res
+    }
+
+    fn measure_sweep(&mut self, args: &SweepArgs)
+        -> Result<SweepOutcome, Error>
+    {
+        let res = self.inner.measure_sweep(args);
+        self.write(encode_sweep(args, res.as_ref().map_err(Failure::of)))?;
+        res
+    }
+
+    fn measure_fetch(&mut self, cfg: &FetchConfig)
+        -> Result<FetchOutcome, Error>
+    {
+        let res = self.inner.measure_fetch(cfg);
+        self.write(encode_fetch(cfg, res.as_ref().map_err(Failure::of)))?;
+        res
+    }
+
+    fn sample_count(&self) -> Result<usize, Error> {
+        let res = self.inner.sample_count();
+        self.record(Exchange::SampleCount {
+            res: res.as_ref().copied().map_err(errno_of)
+        })?;
+        res
+    }
+
+    fn capacity(&self) -> Result<usize, Error> {
+        let res = self.inner.capacity();
+        self.record(Exchange::Capacity {
+            res: res.as_ref().copied().map_err(errno_of)
+        })?;
+        res
+    }
+
+    fn base_address(&self) -> Result<usize, Error> {
+        let res = self.inner.base_address();
+        self.record(Exchange::BaseAddress {
+            res: res.as_ref().copied().map_err(errno_of)
+        })?;
+        res
+    }
+
+    fn info(&self) -> Result<IbstraceInfo, Error> {
+        let res = self.inner.info();
+        self.record(Exchange::Info {
+            res: res.as_ref().copied().map_err(errno_of)
+        })?;
+        res
+    }
+
+    fn layout(&self) -> Result<IbsLayout, Error> {
+        let layout = self.inner.layout()?;
+        self.record(Exchange::Layout { layout })?;
+        Ok(layout)
+    }
+}
+
+
+/// Serves the contents of an archive back as a [MeasurementBackend].
+///
+/// Uploads, measurements, and changes to the target core must occur in the
+/// same order as they were recorded (otherwise, [Error::ReplayMismatch] is 
+/// returned). Queries (for the sample count, capacity, base address, module
+/// information, sample layout, and the contents of the scratch page) may 
+/// occur at any point, and are answered with the most-recently recorded 
+/// value.
+///
+/// Failed requests are replayed as an [Error::Ioctl] (or as an
+/// [Error::DebugfsUnreadable] for the base address and module information)
+/// with the recorded errno. Faults in measured code are replayed as the
+/// original [Error::MeasuredCodeFault].
+pub struct ReplayBackend {
+    exchanges: Vec<Exchange>,
+    cursor: usize,
+}
+impl ReplayBackend {
+    /// Replay the archive at the given path.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let f = File::open(path).map_err(|e| Error::Archive(e.to_string()))?;
+        Self::from_reader(f)
+    }
+
+    /// Replay an archive from some reader.
+    pub fn from_reader(r: impl Read) -> Result<Self, Error> {
+        Ok(Self::new(read_archive(r)?))
+    }
+
+    /// Replay a list of exchanges.
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        Self { exchanges, cursor: 0 }
+    }
+
+    /// Return all exchanges in this archive.
+    pub fn exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
+
+    /// Return `true` if all uploads, measurements, and changes to the target
+    /// core have been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.exchanges[self.cursor..].iter().all(|e| e.is_query())
+    }
+
+    /// Take the next exchange (other than a query) from the archive.
+    fn next_exchange(&mut self) -> Result<(usize, &Exchange), Error> {
+        while let Some(e) = self.exchanges.get(self.cursor) {
+            self.cursor += 1;
+            if !e.is_query() {
+                return Ok((self.cursor - 1, e));
+            }
+        }
+        Err(Error::ReplayExhausted)
+    }
+
+    /// Find the most-recently recorded answer to some query.
+    fn query<T>(&self, f: impl Fn(&Exchange) -> Option<Result<T, Errno>>)
+        -> Option<Result<T, Errno>>
+    {
+        let (prev, next) = self.exchanges.split_at(self.cursor);
+        prev.iter().rev().find_map(&f).or_else(|| next.iter().find_map(&f))
+    }
+}
+
+impl MeasurementBackend for ReplayBackend {
+    fn upload(&mut self, code: &[u8]) -> Result<(), Error> {
+        match self.next_exchange()? {
+            (index, Exchange::Upload { code: recorded, res }) => {
+                if recorded != code {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "uploaded code differs"
+                    });
+                }
+                res.map_err(|errno| Error::Ioctl { cmd: ioctl::CMD_WRITE, errno })
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected an upload"
+            }),
+        }
+    }
+
+    fn upload_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.next_exchange()? {
+            (index, Exchange::UploadData { data: recorded, res }) => {
+                if recorded != data {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "uploaded data differs"
+                    });
+                }
+                res.map_err(|errno| Error::Ioctl { cmd: ioctl::CMD_WRITE_DATA, errno })
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected an upload of data"
+            }),
+        }
+    }
+
+    fn read_scratch(&self) -> Result<Box<[u8]>, Error> {
+        let res = self.query(|e| match e {
+            Exchange::ReadScratch { res } => Some(res.clone()),
+            _ => None,
+        });
+        res.ok_or(Error::ReplayExhausted)?
+            .map(|b| b.into_boxed_slice())
+            .map_err(|errno| Error::Ioctl { cmd: ioctl::CMD_READ_DATA, errno })
+    }
+
+    fn set_target_cpu(&mut self, cpu: usize) -> Result<(), Error> {
+        match self.next_exchange()? {
+            (index, Exchange::SetTargetCpu { cpu: recorded, res }) => {
+                if *recorded != cpu {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "target cpu differs"
+                    });
+                }
+                res.map_err(|errno| Error::Ioctl { cmd: ioctl::CMD_SET_CPU, errno })
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected a change of target cpu"
+            }),
+        }
+    }
+
+    fn measure_with(&mut self, cfg: &SamplingConfig)
+        -> Result<MeasureOutcome, Error>
+    {
+        match self.next_exchange()? {
+            (index, Exchange::Measure { config, res }) => {
+                if config != cfg {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "sampling configuration differs"
+                    });
+                }
+                match res {
+                    Ok(outcome) => Ok(outcome.clone()),
+                    Err(f) => Err(f.to_error(ioctl::CMD_MEASURE)),
+                }
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected a measurement"
+            }),
+        }
+    }
+
+    fn measure_precise(&mut self, arg: &PreciseArgs)
+        -> Result<MeasureOutcome, Error>
+    {
+        match self.next_exchange()? {
+            (index, Exchange::Precise { arg: a, offset, res }) => {
+                if *a != arg.arg() || *offset != arg.offset() {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "precise arguments differ"
+                    });
+                }
+                match res {
+                    Ok(outcome) => Ok(outcome.clone()),
+                    Err(f) => Err(f.to_error(ioctl::CMD_PRECISE)),
+                }
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected a precise measurement"
+            }),
+        }
+    }
+
+    fn measure_batch(&mut self, progs: &[&[u8]], cfg: &SamplingConfig)
+        -> Result<BatchOutcome, Error>
+    {
+        match self.next_exchange()? {
+            (index, Exchange::Batch { progs: recorded, config, res }) => {
+                if recorded.len() != progs.len() || 
+                    recorded.iter().zip(progs).any(|(r, p)| r != p)
+                {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "uploaded programs differ"
+                    });
+                }
+                if config != cfg {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "sampling configuration differs"
+                    });
+                }
+                match res {
+                    Ok(outcome) => Ok(outcome.clone()),
+                    Err(f) => Err(f.to_error(ioctl::CMD_BATCH)),
+                }
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected a batch"
+            }),
+        }
+    }
+
+    fn measure_sweep(&mut self, args: &SweepArgs)
+        -> Result<SweepOutcome, Error>
+    {
+        match self.next_exchange()? {
+            (index, Exchange::Sweep { args: recorded, res }) => {
+                if recorded != args {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "sweep arguments differ"
+                    });
+                }
+                match res {
+                    Ok(outcome) => Ok(outcome.clone()),
+                    Err(f) => Err(f.to_error(ioctl::CMD_SWEEP)),
+                }
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected a sweep"
+            }),
+        }
+    }
+
+    fn measure_fetch(&mut self, cfg: &FetchConfig)
+        -> Result<FetchOutcome, Error>
+    {
+        match self.next_exchange()? {
+            (index, Exchange::Fetch { config, res }) => {
+                if config != cfg {
+                    return Err(Error::ReplayMismatch {
+                        index, reason: "fetch sampling configuration differs"
+                    });
+                }
+                match res {
+                    Ok(outcome) => Ok(outcome.clone()),
+                    Err(f) => Err(f.to_error(ioctl::CMD_FETCH)),
+                }
+            },
+            (index, _) => Err(Error::ReplayMismatch {
+                index, reason: "expected a fetch measurement"
+            }),
+        }
+    }
+
+    fn sample_count(&self) -> Result<usize, Error> {
+        let res = self.query(|e| match e {
+            Exchange::SampleCount { res } => Some(*res),
+            _ => None,
+        });
+        // Samples are always consumed by measurements
+        res.unwrap_or(Ok(0)).map_err(|errno| {
+            Error::Ioctl { cmd: ioctl::CMD_SAMPLES, errno }
+        })
+    }
+
+    fn capacity(&self) -> Result<usize, Error> {
+        let res = self.query(|e| match e {
+            Exchange::Capacity { res } => Some(*res),
+            _ => None,
+        });
+        res.ok_or(Error::ReplayExhausted)?.map_err(|errno| {
+            Error::Ioctl { cmd: ioctl::CMD_CAPACITY, errno }
+        })
+    }
+
+    fn base_address(&self) -> Result<usize, Error> {
+        let res = self.query(|e| match e {
+            Exchange::BaseAddress { res } => Some(*res),
+            _ => None,
+        });
+        res.ok_or(Error::ReplayExhausted)?.map_err(|errno| {
+            Error::DebugfsUnreadable {
+                path: "<replay>".to_string(),
+                source: errno.into(),
+            }
+        })
+    }
+
+    fn info(&self) -> Result<IbstraceInfo, Error> {
+        let res = self.query(|e| match e {
+            Exchange::Info { res } => Some(*res),
+            _ => None,
+        });
+        res.ok_or(Error::ReplayExhausted)?.map_err(|errno| {
+            Error::DebugfsUnreadable {
+                path: "<replay>".to_string(),
+                source: errno.into(),
+            }
+        })
+    }
+
+    fn layout(&self) -> Result<IbsLayout, Error> {
+        let res = self.query(|e| match e {
+            Exchange::Layout { layout } => Some(Ok(*layout)),
+            _ => None,
+        });
+        match res {
+            Some(Ok(layout)) => Ok(layout),
+            _ => Err(Error::ReplayExhausted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use crate::ibs::*;
+    use crate::archive::*;
+    use crate::analysis::*;
+    use crate::backend::MockRequest;
+    use crate::codegen::emit_msr_test;
+    use crate::trace::Trace;
+
+    fn mock() -> MockBackend {
+        let base = MockBackend::DEFAULT_BASE_ADDRESS;
+        MockBackend::new().with_registers(|req| Registers {
+            rax: req.code().len(), rflags: 0x246, ..Default::default()
+        }).with_scratch_writer(|req, page| {
+            page[8] = req.code().len() as u8;
+        }).with_fetch_generator(move |_| {
+            vec![FetchSample::new(
+                IbsFetchCtl(0x0003_0040_0000_0100),
+                IbsFetchLinAd(base),
+                IbsFetchPhysAd(0x2000),
+                IbsFetchExtCtl(0),
+            ); 2]
+        }).with_generator(move |req| {
+            let offset = match req {
+                MockRequest::Precise { offset, .. } => *offset,
+                _ => 0,
+            };
+            vec![SampleBuilder::new()
+                .with_rip(base + offset)
+                .with_load(IbsMemWidth::Qword)
+                .with_phyad(0x1000 + offset)
+                .with_meta(SampleMeta { seq: offset, tsc: 0x100, intr_rip: base })
+                .build(); 3]
+        })
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let mut rec = RecordingBackend::new(mock().with_layout(IbsLayout::Zen4), 
+            Vec::new()
+        ).unwrap();
+        rec.set_target_cpu(3).unwrap();
+        rec.upload_data(&[0xaa; 8]).unwrap();
+        rec.inner.push_error(Error::Ioctl {
+            cmd: ioctl::CMD_MEASURE, errno: Errno::EIO
+        });
+        assert!(run_test(&mut rec, emit_msr_test(0x10, 1)).is_err());
+        let test = run_test(&mut rec, emit_msr_test(0x10, 1)).unwrap();
+        let params = emit_msr_test(0x10, 1);
+        let trace = Trace::collect_from(&mut rec, &params, 0..=3, 7).unwrap();
+        let fetch = rec.measure_fetch(&FetchConfig::new().with_warmup(1))
+            .unwrap();
+        let info = rec.info().unwrap();
+        let scratch = rec.read_scratch().unwrap();
+        assert_eq!(&scratch[..8], &[0xaa; 8]);
+        assert_ne!(scratch[8], 0);
+        let (_, archive) = rec.into_inner();
+
+        let mut replay = ReplayBackend::from_reader(&archive[..]).unwrap();
+        // Each test and trace also queries the layout
+        assert_eq!(replay.exchanges().len(), 1 + 1 + 1 + 3 + 3 + 5 + 1 + 1 + 1);
+        assert_eq!(replay.exchanges()[0],
+            Exchange::Layout { layout: IbsLayout::Zen4 }
+        );
+        replay.set_target_cpu(3).unwrap();
+        assert!(matches!(replay.upload_data(&[0xbb; 8]),
+            Err(Error::ReplayMismatch { index: 2, .. })
+        ));
+        assert_eq!(replay.info().map(|i| i.target_cpu), Ok(3));
+        assert_eq!(replay.info(), Ok(info));
+        assert_eq!(
+            run_test(&mut replay, emit_msr_test(0x10, 1)).err(),
+            Some(Error::Ioctl { cmd: ioctl::CMD_MEASURE, errno: Errno::EIO })
+        );
+        let replayed = run_test(&mut replay, emit_msr_test(0x10, 1)).unwrap();
+        assert_eq!(replayed.result, test.result);
+        assert!(replayed.result.iter().zip(test.result.iter())
+            .all(|(a, b)| a.meta.is_some() && a.meta == b.meta));
+        assert_eq!(replayed.regs, test.regs);
+        assert_eq!(replayed.stats, test.stats);
+        assert_eq!(replayed.return_value, test.params.buf.len());
+        let replayed = Trace::collect_from(&mut replay, &params, 0..=3, 7)
+            .unwrap();
+        assert_eq!(replayed.samples, trace.samples);
+        assert_eq!(replayed.layout, IbsLayout::Zen4);
+        assert_eq!(replay.measure_fetch(&FetchConfig::new().with_warmup(1)),
+            Ok(fetch)
+        );
+        assert!(replay.is_finished());
+        assert_eq!(replay.read_scratch(), Ok(scratch));
+        assert_eq!(replay.measure().err(), Some(Error::ReplayExhausted));
+    }
+
+    #[test]
+    fn rewrite_archive() {
+        // Records encoded from the borrowed outcomes while recording match 
+        // the records encoded from the decoded exchanges
+        let fault = Error::MeasuredCodeFault { vector: 6, rip: 0x1000, error_code: 0 };
+        let mut rec = RecordingBackend::new(mock(), Vec::new()).unwrap();
+        run_test(&mut rec, emit_msr_test(0x10, 1)).unwrap();
+        rec.inner.push_error(fault.clone());
+        assert!(rec.measure().is_err());
+        rec.measure_precise(&PreciseArgs::new(0, 4).unwrap()).unwrap();
+        rec.measure_sweep(&SweepArgs::new(0, 0..=3).unwrap()).unwrap();
+        rec.measure_fetch(&FetchConfig::new()).unwrap();
+        let progs: [&[u8]; 2] = [&[0xc3], &[0x90, 0xc3]];
+        rec.inner.push_samples(vec![]);
+        rec.inner.push_error(fault);
+        rec.measure_batch(&progs, &SamplingConfig::default()).unwrap();
+        let (_, archive) = rec.into_inner();
+
+        let mut rewritten = archive[..12].to_vec();
+        for x in read_archive(&archive[..]).unwrap() {
+            x.write_to(&mut rewritten).unwrap();
+        }
+        assert_eq!(rewritten, archive);
+    }
+
+    #[test]
+    fn record_and_replay_fault() {
+        let fault = Error::MeasuredCodeFault { vector: 13, rip: 0x1234, error_code: 0 };
+        let mut rec = RecordingBackend::new(mock(), Vec::new()).unwrap();
+        rec.inner.push_error(fault.clone());
+        rec.inner.push_error(Error::Ioctl { 
+            cmd: ioctl::CMD_PRECISE, errno: Errno::EFAULT 
+        });
+        assert_eq!(run_test(&mut rec, emit_msr_test(0x10, 1)).err(), 
+            Some(fault.clone())
+        );
+        let args = PreciseArgs::new(0, 4).unwrap();
+        assert!(rec.measure_precise(&args).is_err());
+        let progs: [&[u8]; 3] = [&[0xc3], &[0x90, 0xc3], &[0x90, 0x90, 0xc3]];
+        rec.inner.push_error(fault.clone());
+        let batch = rec.measure_batch(&progs[1..], &SamplingConfig::default())
+            .unwrap();
+        rec.inner.push_samples(vec![]);
+        rec.inner.push_error(fault.clone());
+        let partial = rec.measure_batch(&progs, &SamplingConfig::default())
+            .unwrap();
+        assert_eq!((batch.len(), partial.len()), (0, 1));
+        let (_, archive) = rec.into_inner();
+
+        let mut replay = ReplayBackend::from_reader(&archive[..]).unwrap();
+        assert!(matches!(replay.exchanges()[3], 
+            Exchange::Measure { res: Err(Failure::Fault { vector: 13, .. }), .. }
+        ));
+        assert_eq!(run_test(&mut replay, emit_msr_test(0x10, 1)).err(), 
+            Some(fault)
+        );
+        assert_eq!(replay.measure_precise(&args).err(), 
+            Some(Error::Ioctl { cmd: ioctl::CMD_PRECISE, errno: Errno::EFAULT })
+        );
+        assert_eq!(replay.measure_batch(&progs[1..], &SamplingConfig::default()),
+            Ok(batch)
+        );
+        let replayed = replay.measure_batch(&progs, &SamplingConfig::default())
+            .unwrap();
+        assert_eq!(replayed.fault.map(|f| f.index), Some(1));
+        assert_eq!(replayed, partial);
+    }
+
+    #[test]
+    fn replay_mismatch() {
+        let mut rec = RecordingBackend::new(mock(), Vec::new()).unwrap();
+        run_test(&mut rec, emit_msr_test(0x10, 1)).unwrap();
+        let (_, archive) = rec.into_inner();
+
+        let mut replay = ReplayBackend::from_reader(&archive[..]).unwrap();
+        let cfg = SamplingConfig::new().with_warmup(1);
+        assert!(matches!(
+            run_test_with(&mut replay, emit_msr_test(0x10, 1), &cfg),
+            Err(Error::ReplayMismatch { index: 3, .. })
+        ));
+
+        // Only the current version is accepted
+        let mut other = archive.clone();
+        other[8] = 2;
+        assert!(matches!(ReplayBackend::from_reader(&other[..]), 
+            Err(Error::Archive(_))
+        ));
+
+        // Truncated trailing records are ignored
+        let archive = &archive[..archive.len() - 1];
+        let mut replay = ReplayBackend::from_reader(archive).unwrap();
+        assert_eq!(replay.exchanges().len(), 3);
+        assert!(matches!(
+            run_test(&mut replay, emit_msr_test(0x20, 1)),
+            Err(Error::ReplayMismatch { index: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn malformed_sweep() {
+        // Ranges which are empty, out of bounds, or which overflow
+        let cases = [
+            (0, 0), (ioctl::MAX_OFFSET, 1), (ioctl::MAX_OFFSET - 1, 2), 
+            (usize::MAX, 2),
+        ];
+        for (first, count) in cases {
+            let mut payload = 0i32.to_le_bytes().to_vec();
+            for word in [0, first, count] {
+                payload.extend_from_slice(&(word as u64).to_le_bytes());
+            }
+            payload.extend_from_slice(&Registers::default().to_le_bytes());
+            assert!(matches!(Exchange::from_record(TAG_SWEEP, &payload),
+                Err(Error::Archive(_))
+            ));
+        }
+    }
+}
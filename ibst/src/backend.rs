@@ -0,0 +1,651 @@
+//! Interchangeable backends for measuring user code.
+//!
+//! [IbstraceDevice] drives the actual `ibstrace` kernel module, while
+//! [MockBackend] produces scripted samples in-memory (so that analyses can
+//! be exercised on machines without the module loaded).
+
+use std::collections::VecDeque;
+
+use crate::*;
+use crate::ioctl::{ BatchResult, PreciseArgs, SweepArgs };
+
+/// Some way of uploading user code and collecting samples.
+pub trait MeasurementBackend {
+    /// Upload user code.
+    fn upload(&mut self, code: &[u8]) -> Result<(), Error>;
+
+    /// Change the core used to run user code.
+    fn set_target_cpu(&mut self, cpu: usize) -> Result<(), Error>;
+
+    /// Fill the scratch page passed to user code (the rest of the page is 
+    /// zeroed).
+    fn upload_data(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// Read back the contents of the scratch page passed to user code.
+    fn read_scratch(&self) -> Result<Box<[u8]>, Error>;
+
+    /// Sample the previously-uploaded user code.
+    fn measure(&mut self) -> Result<MeasureOutcome, Error> {
+        self.measure_with(&SamplingConfig::default())
+    }
+
+    /// Sample the previously-uploaded user code with a particular 
+    /// [SamplingConfig].
+    fn measure_with(&mut self, cfg: &SamplingConfig)
+        -> Result<MeasureOutcome, Error>;
+
+    /// Upload and sample a list of programs (one after the other) with a 
+    /// particular [SamplingConfig].
+    ///
+    /// By default, each program is uploaded and measured separately. The 
+    /// batch stops after the first program that faults (see 
+    /// [BatchOutcome::fault]).
+    fn measure_batch(&mut self, progs: &[&[u8]], cfg: &SamplingConfig)
+        -> Result<BatchOutcome, Error>
+    {
+        let mut samples = Vec::new();
+        let mut results = Vec::with_capacity(progs.len());
+        let mut fault = None;
+        for (idx, prog) in progs.iter().enumerate() {
+            self.upload(prog)?;
+            let res = match self.measure_with(cfg) {
+                Ok(res) => res,
+                Err(e) => {
+                    // Stop after the first fault (like the kernel module)
+                    fault = Some(BatchFault::from_error(idx, &e).ok_or(e)?);
+                    break;
+                },
+            };
+            results.push(BatchResult {
+                load_offset: 0,
+                first: samples.len(),
+                count: res.samples.len(),
+                dropped: res.stats.dropped,
+                spurious: res.stats.spurious,
+                regs: res.regs,
+                fault: Default::default(),
+            });
+            samples.extend(res.samples.into_vec());
+        }
+        let outcome = BatchOutcome::new(samples.into_boxed_slice(), 
+            results.into_boxed_slice()
+        );
+        Ok(match fault {
+            Some(fault) => outcome.with_fault(fault),
+            None => outcome,
+        })
+    }
+
+    /// Sample a particular micro-op in the previously-uploaded user code.
+    fn measure_precise(&mut self, arg: &PreciseArgs)
+        -> Result<MeasureOutcome, Error>;
+
+    /// Sample a range of micro-ops in the previously-uploaded user code 
+    /// (with one precise measurement per offset).
+    ///
+    /// By default, this performs a separate [MeasurementBackend::measure_precise]
+    /// for each offset.
+    fn measure_sweep(&mut self, args: &SweepArgs)
+        -> Result<SweepOutcome, Error>
+    {
+        let mut samples = Vec::with_capacity(args.count());
+        let mut regs = Registers::default();
+        let mut stats = SampleStats::default();
+        for offset in args.offsets() {
+            let res = self.measure_precise(&PreciseArgs::new(args.arg(), offset)?)?;
+            samples.push(res.samples.first().cloned());
+            regs = res.regs;
+            stats.dropped += res.stats.dropped;
+            stats.spurious += res.stats.spurious;
+        }
+        stats.collected = samples.iter().flatten().count();
+        Ok(SweepOutcome::new(args.first(), samples.into_boxed_slice(), regs)
+            .with_stats(stats))
+    }
+
+    /// Sample instruction fetches in the previously-uploaded user code with 
+    /// a particular [FetchConfig].
+    fn measure_fetch(&mut self, cfg: &FetchConfig)
+        -> Result<FetchOutcome, Error>;
+
+    /// Return the number of samples currently held by the backend.
+    fn sample_count(&self) -> Result<usize, Error>;
+
+    /// Return the maximum number of samples returned by a single measurement.
+    fn capacity(&self) -> Result<usize, Error>;
+
+    /// Return the base address of the code buffer.
+    fn base_address(&self) -> Result<usize, Error>;
+
+    /// Return information about the kernel module (ie. the location of the
+    /// scratch page).
+    fn info(&self) -> Result<IbstraceInfo, Error>;
+
+    /// Return the [IbsLayout] used to decode samples from this backend.
+    fn layout(&self) -> Result<IbsLayout, Error>;
+}
+
+impl MeasurementBackend for IbstraceDevice {
+    fn upload(&mut self, code: &[u8]) -> Result<(), Error> {
+        IbstraceDevice::upload(self, code)
+    }
+    fn set_target_cpu(&mut self, cpu: usize) -> Result<(), Error> {
+        IbstraceDevice::set_target_cpu(self, cpu)
+    }
+    fn upload_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        IbstraceDevice::upload_data(self, data)
+    }
+    fn read_scratch(&self) -> Result<Box<[u8]>, Error> {
+        IbstraceDevice::read_scratch(self)
+    }
+    fn measure_with(&mut self, cfg: &SamplingConfig)
+        -> Result<MeasureOutcome, Error>
+    {
+        IbstraceDevice::measure_with(self, cfg)
+    }
+    fn measure_batch(&mut self, progs: &[&[u8]], cfg: &SamplingConfig)
+        -> Result<BatchOutcome, Error>
+    {
+        IbstraceDevice::measure_batch(self, progs, cfg)
+    }
+    fn measure_precise(&mut self, arg: &PreciseArgs)
+        -> Result<MeasureOutcome, Error>
+    {
+        IbstraceDevice::measure_precise(self, arg)
+    }
+    fn measure_sweep(&mut self, args: &SweepArgs)
+        -> Result<SweepOutcome, Error>
+    {
+        IbstraceDevice::measure_sweep(self, args)
+    }
+    fn measure_fetch(&mut self, cfg: &FetchConfig)
+        -> Result<FetchOutcome, Error>
+    {
+        IbstraceDevice::measure_fetch(self, cfg)
+    }
+    fn sample_count(&self) -> Result<usize, Error> {
+        IbstraceDevice::sample_count(self)
+    }
+    fn capacity(&self) -> Result<usize, Error> {
+        IbstraceDevice::capacity(self)
+    }
+    fn base_address(&self) -> Result<usize, Error> {
+        IbstraceDevice::base_address(self)
+    }
+    fn info(&self) -> Result<IbstraceInfo, Error> {
+        IbstraceDevice::info(self)
+    }
+    fn layout(&self) -> Result<IbsLayout, Error> {
+        Ok(IbstraceDevice::layout(self))
+    }
+}
+
+
+/// A measurement requested from a [MockBackend].
+#[derive(Clone, Copy, Debug)]
+pub enum MockRequest<'a> {
+    /// User code was sampled with [MeasurementBackend::measure].
+    Measure {
+        /// The uploaded user code
+        code: &'a [u8],
+        /// The core used to run user code
+        cpu: usize,
+        /// The sampling configuration
+        config: SamplingConfig
+    },
+    /// User code was sampled with [MeasurementBackend::measure_precise].
+    Precise {
+        /// The uploaded user code
+        code: &'a [u8],
+        /// The core used to run user code
+        cpu: usize,
+        /// Argument passed through to user code
+        arg: usize,
+        /// Counter offset
+        offset: usize
+    },
+    /// User code was sampled with [MeasurementBackend::measure_fetch].
+    Fetch {
+        /// The uploaded user code
+        code: &'a [u8],
+        /// The core used to run user code
+        cpu: usize,
+        /// The sampling configuration
+        config: FetchConfig
+    },
+}
+impl MockRequest<'_> {
+    /// Return the user code associated with this request.
+    pub fn code(&self) -> &[u8] {
+        match self {
+            Self::Measure { code, .. } => code,
+            Self::Precise { code, .. } => code,
+            Self::Fetch { code, .. } => code,
+        }
+    }
+
+    /// Return the core used to run user code for this request.
+    pub fn cpu(&self) -> usize {
+        match self {
+            Self::Measure { cpu, .. } => *cpu,
+            Self::Precise { cpu, .. } => *cpu,
+            Self::Fetch { cpu, .. } => *cpu,
+        }
+    }
+}
+
+type MockGenerator = Box<dyn FnMut(&MockRequest) -> Vec<Sample>>;
+type MockFetchGenerator = Box<dyn FnMut(&MockRequest) -> Vec<FetchSample>>;
+type MockRegisters = Box<dyn FnMut(&MockRequest) -> Registers>;
+type MockScratch = Box<dyn FnMut(&MockRequest, &mut [u8])>;
+
+/// An in-memory backend which returns canned or generated samples.
+///
+/// Each measurement is answered with the next scripted response (see
+/// [MockBackend::push_samples] and [MockBackend::push_error]). Once the
+/// scripted responses are exhausted, samples are produced by the generator
+/// (see [MockBackend::with_generator]), or otherwise no samples are returned.
+/// Generated samples are accompanied by register state from
+/// [MockBackend::with_registers] (or otherwise, zeroed registers).
+///
+/// Scripted responses only apply to op sampling. Fetch samples are always
+/// produced by [MockBackend::with_fetch_generator]. Changes made to the 
+/// scratch page by user code can be simulated with 
+/// [MockBackend::with_scratch_writer].
+pub struct MockBackend {
+    /// Information reported for the kernel module
+    info: IbstraceInfo,
+    /// Layout used to decode samples
+    layout: IbsLayout,
+    /// Number of cores which can be selected to run user code
+    num_cpus: usize,
+    /// Previously-uploaded user code
+    code: Vec<u8>,
+    /// Contents of the scratch page
+    scratch: Vec<u8>,
+    /// Scripted responses to measurements
+    responses: VecDeque<Result<MeasureOutcome, Error>>,
+    /// Fallback for producing samples
+    generator: Option<MockGenerator>,
+    /// Fallback for producing fetch samples
+    fetch_generator: Option<MockFetchGenerator>,
+    /// Fallback for producing register state
+    registers: Option<MockRegisters>,
+    /// Simulates user code writing to the scratch page
+    scratch_writer: Option<MockScratch>,
+    /// The number of measurements performed so far
+    measurements: usize,
+}
+impl MockBackend {
+    /// Default base address reported for the code buffer.
+    pub const DEFAULT_BASE_ADDRESS: usize = 0xffff_c900_0000_0000;
+
+    /// Default sample capacity (matches the kernel module).
+    pub const DEFAULT_CAPACITY: usize = 0x40000;
+
+    /// Default number of cores.
+    pub const DEFAULT_NUM_CPUS: usize = 16;
+
+    /// Default physical address reported for the scratch page.
+    pub const DEFAULT_SCRATCH_PAGE_PADDR: usize = 0x0000_0001_0000_0000;
+
+    /// Default base address reported for the direct map (ie. `PAGE_OFFSET` 
+    /// without KASLR).
+    pub const DEFAULT_PAGE_OFFSET: usize = 0xffff_8880_0000_0000;
+
+    pub fn new() -> Self {
+        let info = IbstraceInfo {
+            code_buf: Self::DEFAULT_BASE_ADDRESS,
+            sample_buf: Self::DEFAULT_BASE_ADDRESS + 0x0100_0000,
+            scratch_page: Self::DEFAULT_PAGE_OFFSET + Self::DEFAULT_SCRATCH_PAGE_PADDR,
+            scratch_page_paddr: Self::DEFAULT_SCRATCH_PAGE_PADDR,
+            sample_capacity: Self::DEFAULT_CAPACITY,
+            code_buf_max_size: ioctl::CODE_BUFFER_MAX_SIZE,
+            target_cpu: 0,
+            page_offset: Some(Self::DEFAULT_PAGE_OFFSET),
+        };
+        Self {
+            info,
+            layout: IbsLayout::default(),
+            num_cpus: Self::DEFAULT_NUM_CPUS,
+            code: Vec::new(),
+            scratch: vec![0; IbstraceInfo::SCRATCH_PAGE_SIZE],
+            responses: VecDeque::new(),
+            generator: None,
+            fetch_generator: None,
+            registers: None,
+            scratch_writer: None,
+            measurements: 0,
+        }
+    }
+
+    /// Set the base address reported for the code buffer.
+    pub fn with_base_address(mut self, base_address: usize) -> Self {
+        self.info.code_buf = base_address;
+        self
+    }
+
+    /// Set the maximum number of samples returned from a measurement.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.info.sample_capacity = capacity;
+        self
+    }
+
+    /// Set the number of cores which can be selected to run user code.
+    pub fn with_num_cpus(mut self, num_cpus: usize) -> Self {
+        self.num_cpus = num_cpus;
+        self
+    }
+
+    /// Set the information reported for the kernel module.
+    pub fn with_info(mut self, info: IbstraceInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Set the layout used to decode samples.
+    pub fn with_layout(mut self, layout: IbsLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Use a function to generate samples for each measurement (once all
+    /// scripted responses have been consumed).
+    pub fn with_generator(mut self,
+        f: impl FnMut(&MockRequest) -> Vec<Sample> + 'static
+    ) -> Self
+    {
+        self.generator = Some(Box::new(f));
+        self
+    }
+
+    /// Use a function to generate fetch samples for each fetch-mode 
+    /// measurement.
+    pub fn with_fetch_generator(mut self,
+        f: impl FnMut(&MockRequest) -> Vec<FetchSample> + 'static
+    ) -> Self
+    {
+        self.fetch_generator = Some(Box::new(f));
+        self
+    }
+
+    /// Use a function to generate the register state after each measurement 
+    /// (once all scripted responses have been consumed).
+    pub fn with_registers(mut self,
+        f: impl FnMut(&MockRequest) -> Registers + 'static
+    ) -> Self
+    {
+        self.registers = Some(Box::new(f));
+        self
+    }
+
+    /// Use a function to modify the scratch page during each measurement 
+    /// (simulating user code which writes to the scratch page).
+    pub fn with_scratch_writer(mut self,
+        f: impl FnMut(&MockRequest, &mut [u8]) + 'static
+    ) -> Self
+    {
+        self.scratch_writer = Some(Box::new(f));
+        self
+    }
+
+    /// Script the samples returned by a future measurement.
+    pub fn push_samples(&mut self, samples: impl Into<Vec<Sample>>) {
+        let samples = samples.into().into_boxed_slice();
+        self.push_outcome(MeasureOutcome::new(samples, Registers::default()));
+    }
+
+    /// Script the outcome of a future measurement.
+    pub fn push_outcome(&mut self, outcome: MeasureOutcome) {
+        self.responses.push_back(Ok(outcome));
+    }
+
+    /// Script an error returned by a future measurement.
+    pub fn push_error(&mut self, err: Error) {
+        self.responses.push_back(Err(err));
+    }
+
+    /// Return the previously-uploaded user code.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Return the number of measurements performed so far.
+    pub fn measurements(&self) -> usize {
+        self.measurements
+    }
+
+    fn respond(&mut self, req: &MockRequest) -> Result<MeasureOutcome, Error> {
+        self.measurements += 1;
+        if let Some(f) = self.scratch_writer.as_mut() {
+            f(req, &mut self.scratch);
+        }
+        let mut outcome = match self.responses.pop_front() {
+            Some(res) => res?,
+            None => {
+                let samples = match self.generator.as_mut() {
+                    Some(f) => f(req),
+                    None => Vec::new(),
+                };
+                let regs = match self.registers.as_mut() {
+                    Some(f) => f(req),
+                    None => Registers::default(),
+                };
+                MeasureOutcome::new(samples.into_boxed_slice(), regs)
+            },
+        };
+        // Pretend that samples beyond the capacity were dropped
+        if outcome.samples.len() > self.info.sample_capacity {
+            let mut samples = std::mem::take(&mut outcome.samples).into_vec();
+            outcome.stats.dropped += samples.len() - self.info.sample_capacity;
+            samples.truncate(self.info.sample_capacity);
+            outcome.stats.collected = samples.len();
+            outcome.samples = samples.into_boxed_slice();
+        }
+        Ok(outcome)
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MeasurementBackend for MockBackend {
+    fn upload(&mut self, code: &[u8]) -> Result<(), Error> {
+        if code.len() > self.info.code_buf_max_size {
+            return Err(Error::BufferTooLarge {
+                len: code.len(),
+                max: self.info.code_buf_max_size,
+            });
+        }
+        self.code = code.to_vec();
+        Ok(())
+    }
+
+    fn set_target_cpu(&mut self, cpu: usize) -> Result<(), Error> {
+        if cpu >= self.num_cpus {
+            return Err(Error::Ioctl {
+                cmd: ioctl::CMD_SET_CPU,
+                errno: nix::errno::Errno::EINVAL,
+            });
+        }
+        self.info.target_cpu = cpu;
+        Ok(())
+    }
+
+    fn upload_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > self.scratch.len() {
+            return Err(Error::BufferTooLarge {
+                len: data.len(),
+                max: self.scratch.len(),
+            });
+        }
+        self.scratch.fill(0);
+        self.scratch[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_scratch(&self) -> Result<Box<[u8]>, Error> {
+        Ok(self.scratch.clone().into_boxed_slice())
+    }
+
+    fn measure_with(&mut self, cfg: &SamplingConfig)
+        -> Result<MeasureOutcome, Error>
+    {
+        let code = std::mem::take(&mut self.code);
+        let res = self.respond(&MockRequest::Measure {
+            code: &code,
+            cpu: self.info.target_cpu,
+            config: *cfg,
+        });
+        self.code = code;
+        res
+    }
+
+    fn measure_precise(&mut self, arg: &PreciseArgs)
+        -> Result<MeasureOutcome, Error>
+    {
+        let code = std::mem::take(&mut self.code);
+        let res = self.respond(&MockRequest::Precise {
+            code: &code,
+            cpu: self.info.target_cpu,
+            arg: arg.arg(),
+            offset: arg.offset()
+        });
+        self.code = code;
+        res
+    }
+
+    fn measure_fetch(&mut self, cfg: &FetchConfig)
+        -> Result<FetchOutcome, Error>
+    {
+        self.measurements += 1;
+        let req = MockRequest::Fetch {
+            code: &self.code,
+            cpu: self.info.target_cpu,
+            config: *cfg,
+        };
+        if let Some(f) = self.scratch_writer.as_mut() {
+            f(&req, &mut self.scratch);
+        }
+        let mut samples = match self.fetch_generator.as_mut() {
+            Some(f) => f(&req),
+            None => Vec::new(),
+        };
+        let regs = match self.registers.as_mut() {
+            Some(f) => f(&req),
+            None => Registers::default(),
+        };
+        let dropped = samples.len().saturating_sub(self.info.sample_capacity);
+        samples.truncate(self.info.sample_capacity);
+        let stats = SampleStats { collected: samples.len(), dropped, spurious: 0 };
+        Ok(FetchOutcome::new(samples.into_boxed_slice(), regs).with_stats(stats))
+    }
+
+    fn sample_count(&self) -> Result<usize, Error> {
+        // Samples are always consumed by the measurement itself
+        Ok(0)
+    }
+
+    fn capacity(&self) -> Result<usize, Error> {
+        Ok(self.info.sample_capacity)
+    }
+
+    fn base_address(&self) -> Result<usize, Error> {
+        Ok(self.info.code_buf)
+    }
+
+    fn info(&self) -> Result<IbstraceInfo, Error> {
+        Ok(self.info)
+    }
+
+    fn layout(&self) -> Result<IbsLayout, Error> {
+        Ok(self.layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use crate::ibs::*;
+    use crate::backend::*;
+    use crate::analysis::*;
+    use crate::codegen::emit_msr_test;
+    use nix::errno::Errno;
+
+    #[test]
+    fn mock_scripted_responses() {
+        let mut dev = MockBackend::new().with_capacity(2);
+        let sample = Sample { rip: 0x1000, ..Default::default() };
+        dev.push_samples(vec![sample.clone(); 4]);
+        dev.push_error(Error::Ioctl { cmd: ioctl::CMD_MEASURE, errno: Errno::EIO });
+
+        dev.upload(&[0xc3]).unwrap();
+        assert_eq!(dev.measure().unwrap().samples.len(), 2);
+        assert!(dev.measure().is_err());
+        assert!(dev.measure().unwrap().samples.is_empty());
+        assert_eq!(dev.measurements(), 3);
+        assert_eq!(dev.code(), &[0xc3]);
+    }
+
+    #[test]
+    fn mock_scratch_page() {
+        let mut dev = MockBackend::new().with_scratch_writer(|_, page| {
+            let sum = page[..4].iter().map(|&b| b as usize).sum::<usize>();
+            page[4..8].copy_from_slice(&(sum as u32).to_le_bytes());
+        });
+        dev.upload_data(&[1, 2, 3, 4, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        dev.upload(&[0xc3]).unwrap();
+        dev.measure().unwrap();
+        let page = dev.read_scratch().unwrap();
+        assert_eq!(page.len(), IbstraceInfo::SCRATCH_PAGE_SIZE);
+        assert_eq!(&page[4..8], &10u32.to_le_bytes());
+        assert_eq!(page[8], 0xff);
+
+        // Uploading clears the rest of the page
+        dev.upload_data(&[5]).unwrap();
+        assert!(dev.read_scratch().unwrap()[1..].iter().all(|&b| b == 0));
+        assert!(matches!(dev.upload_data(&[0; 0x1001]),
+            Err(Error::BufferTooLarge { len: 0x1001, max: 0x1000 })
+        ));
+    }
+
+    #[test]
+    fn mock_target_cpu() {
+        let mut dev = MockBackend::new().with_num_cpus(2)
+            .with_generator(|req| vec![Sample { rip: req.cpu(), ..Default::default() }]);
+        dev.upload(&[0xc3]).unwrap();
+        assert_eq!(dev.measure().unwrap().samples[0].rip, 0);
+        dev.set_target_cpu(1).unwrap();
+        assert_eq!(dev.info().unwrap().target_cpu, 1);
+        assert_eq!(dev.measure().unwrap().samples[0].rip, 1);
+        assert!(matches!(dev.set_target_cpu(2),
+            Err(Error::Ioctl { cmd: ioctl::CMD_SET_CPU, errno: Errno::EINVAL })
+        ));
+    }
+
+    #[test]
+    fn mock_run_test() {
+        let base = MockBackend::DEFAULT_BASE_ADDRESS;
+        let mut dev = MockBackend::new().with_generator(move |req| {
+            // Pretend that the first 'rdmsr' in user code performs a load
+            let off = req.code().windows(2)
+                .position(|w| w == [0x0f, 0x32]).unwrap();
+            vec![SampleBuilder::new()
+                .with_rip(base + off)
+                .with_load(IbsMemWidth::Qword)
+                .with_phyad(0x1000)
+                .build(); 16]
+        });
+
+        let test = run_test(&mut dev, emit_msr_test(0xc001_0015, 1)).unwrap();
+        let tgt_rip = test.target_rip(base);
+        let accs = get_uniq_accesses(&test.result, tgt_rip, test.layout);
+        assert_eq!(accs.len(), 1);
+        assert_eq!(accs.first(), Some(&MemoryAccess {
+            phys: 0x1000, width: 64, kind: MemoryAccessKind::LD
+        }));
+    }
+}
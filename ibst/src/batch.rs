@@ -0,0 +1,181 @@
+//! Measuring a list of programs with a single request.
+
+use crate::*;
+use crate::ioctl::{ BatchResult, BATCH_ALIGN };
+
+/// The result of measuring a list of programs, see
+/// [MeasurementBackend::measure_batch].
+///
+/// Samples from all programs are stored back-to-back, and each [BatchResult]
+/// describes the range of samples collected for a particular program.
+///
+/// The batch stops after the first program that faults. In that case, there
+/// are only results for the programs before it (see [BatchOutcome::fault]).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchOutcome {
+    /// Samples collected while running all programs
+    pub samples: Box<[Sample]>,
+    /// The result for each program (in order)
+    pub results: Box<[BatchResult]>,
+    /// The fault taken by some program (if any), which is always the 
+    /// program after the last result
+    pub fault: Option<BatchFault>,
+}
+impl BatchOutcome {
+    pub fn new(samples: Box<[Sample]>, results: Box<[BatchResult]>) -> Self {
+        Self { samples, results, fault: None }
+    }
+
+    /// Set the fault which stopped the batch.
+    pub fn with_fault(mut self, fault: BatchFault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// Return the number of programs which ran to completion.
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Returns `true` if no programs ran to completion.
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Return the samples collected for a particular program.
+    pub fn samples_of(&self, idx: usize) -> &[Sample] {
+        let res = &self.results[idx];
+        &self.samples[res.first..res.first + res.count]
+    }
+
+    /// Return the outcome for a particular program.
+    pub fn outcome(&self, idx: usize) -> MeasureOutcome {
+        let res = &self.results[idx];
+        let samples = self.samples_of(idx).to_vec().into_boxed_slice();
+        MeasureOutcome::new(samples, res.regs).with_stats(SampleStats {
+            collected: res.count,
+            dropped: res.dropped,
+            spurious: res.spurious,
+        })
+    }
+
+    /// Check that the range of samples for each program is in-bounds.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        for res in self.results.iter() {
+            let end = res.first.checked_add(res.count);
+            if end.is_none_or(|end| end > self.samples.len()) {
+                return Err(Error::ShortRead {
+                    expected: res.first.saturating_add(res.count) * Sample::SIZE,
+                    actual: self.samples.len() * Sample::SIZE,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A fault taken by one of the programs in a batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchFault {
+    /// Index of the program which faulted
+    pub index: usize,
+    /// Exception vector
+    pub vector: usize,
+    /// Address of the faulting instruction
+    pub rip: usize,
+    /// Error code pushed by the exception (if any)
+    pub error_code: usize,
+}
+impl BatchFault {
+    /// Return the fault taken by program `index`, if `err` is an 
+    /// [Error::MeasuredCodeFault].
+    pub fn from_error(index: usize, err: &Error) -> Option<Self> {
+        match err {
+            Error::MeasuredCodeFault { vector, rip, error_code } => Some(Self {
+                index, vector: *vector, rip: *rip, error_code: *error_code
+            }),
+            _ => None,
+        }
+    }
+
+    /// Convert this into an [Error::MeasuredCodeFault].
+    pub fn to_error(&self) -> Error {
+        Error::MeasuredCodeFault { 
+            vector: self.vector, rip: self.rip, error_code: self.error_code 
+        }
+    }
+}
+
+/// Return the offset of each program in the code buffer when a list of
+/// programs is measured with [ioctl::CMD_BATCH].
+///
+/// Each program is aligned to [ioctl::BATCH_ALIGN] bytes. Returns an error
+/// if the programs don't fit in a code buffer of the given size.
+pub fn load_offsets(progs: &[&[u8]], max: usize) -> Result<Vec<usize>, Error> {
+    let mut res = Vec::with_capacity(progs.len());
+    let mut cur = 0;
+    for prog in progs {
+        res.push(cur);
+        cur += prog.len().next_multiple_of(BATCH_ALIGN);
+    }
+    let len = res.last().map(|off| off + progs[progs.len() - 1].len())
+        .unwrap_or(0);
+    if len > max {
+        return Err(Error::BufferTooLarge { len, max });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::batch::*;
+    use nix::errno::Errno;
+
+    #[test]
+    fn batch_layout() {
+        let progs: [&[u8]; 3] = [&[0xc3; 0x41], &[0xc3; 0x40], &[0xc3; 1]];
+        assert_eq!(load_offsets(&progs, 0x1000).unwrap(), [0x00, 0x80, 0xc0]);
+        assert_eq!(load_offsets(&progs, 0xc1).unwrap().len(), 3);
+        assert_eq!(load_offsets(&progs, 0xc0),
+            Err(Error::BufferTooLarge { len: 0xc1, max: 0xc0 })
+        );
+        assert!(load_offsets(&[], 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn batch_mock() {
+        let mut dev = MockBackend::new().with_generator(|req| {
+            vec![Sample { rip: req.code().len(), ..Default::default() }; 2]
+        }).with_registers(|req| Registers {
+            rax: req.code().len(), ..Default::default()
+        });
+        dev.push_samples(vec![]);
+
+        let progs: [&[u8]; 3] = [&[0xc3], &[0x90, 0xc3], &[0x90, 0x90, 0xc3]];
+        let res = dev.measure_batch(&progs, &SamplingConfig::default()).unwrap();
+        assert_eq!(dev.measurements(), 3);
+        assert_eq!(res.len(), 3);
+        assert!(res.samples_of(0).is_empty());
+        assert_eq!(res.results[2].first, 2);
+        assert!(res.samples_of(2).iter().all(|s| s.rip == 3));
+        assert_eq!(res.outcome(1).return_value, 2);
+        assert!(res.validate().is_ok());
+        assert_eq!(res.fault, None);
+
+        // The batch stops after the first program that faults
+        let fault = Error::MeasuredCodeFault { vector: 6, rip: 0x10, error_code: 0 };
+        dev.push_samples(vec![]);
+        dev.push_error(fault.clone());
+        let res = dev.measure_batch(&progs, &SamplingConfig::default()).unwrap();
+        assert_eq!(dev.measurements(), 5);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res.fault, Some(BatchFault { 
+            index: 1, vector: 6, rip: 0x10, error_code: 0 
+        }));
+        assert_eq!(res.fault.unwrap().to_error(), fault);
+
+        // Other errors still fail the whole batch
+        dev.push_error(Error::Ioctl { cmd: ioctl::CMD_MEASURE, errno: Errno::EIO });
+        assert!(dev.measure_batch(&progs, &SamplingConfig::default()).is_err());
+    }
+}
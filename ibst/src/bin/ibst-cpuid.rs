@@ -3,37 +3,94 @@ use std::collections::{ BTreeMap, BTreeSet };
 use dynasmrt::AssemblyOffset;
 use ibst::codegen::*;
 use ibst::analysis::*;
-use ibst::Sample;
+use ibst::{ IbstraceDevice, MeasurementBackend, Sample, SamplingConfig };
+use clap::Parser;
 
-/// Test a single CPUID leaf, returning a list of samples
-fn sample_cpuid_single(fd: i32, eax: u32, iters: usize) -> TestResult {
-    run_test(fd, ibst::codegen::emit_cpuid_test(eax, iters))
+/// ibst-cpuid
+/// ==========
+///
+/// Use the 'ibstrace' kernel module to analyze the memory accesses produced 
+/// by 'cpuid' for all typical leaves.
+///
+#[derive(Parser)]
+#[command(verbatim_doc_comment)]
+struct Args { 
+    /// The core used to run tests (otherwise, use the current target core)
+    #[arg(long,)]
+    cpu: Option<usize>,
+
+    /// The number of leaves tested with a single request to 'ibstrace'
+    #[arg(long,default_value_t=4)]
+    batch_size: usize,
 }
 
-/// Test all valid/typical CPUID leaves, returning a map from leaf numbers to
-/// lists of samples.
-fn sample_cpuid_known(fd: i32) -> BTreeMap<u32, TestResult> {
+/// Test all valid/typical CPUID leaves (in batches of `batch_size`), 
+/// returning a map from leaf numbers to lists of samples.
+fn sample_cpuid_known<B: MeasurementBackend>(dev: &mut B, batch_size: usize) 
+    -> Result<BTreeMap<u32, TestResult>, ibst::Error> 
+{
+    let leaves: Vec<u32> = (0x0000_0000..=0x0000_0020)
+        .chain(0x8000_0000..=0x8000_0021)
+        .collect();
     let mut map: BTreeMap<u32, TestResult> = BTreeMap::new();
-    for eax in 0x0000_0000..=0x0000_0020 {
-        let samples = sample_cpuid_single(fd, eax, 0x100000);
-        map.insert(eax, samples);
-    }
-    for eax in 0x8000_0000..=0x8000_0021 {
-        let samples = sample_cpuid_single(fd, eax, 0x100000);
-        map.insert(eax, samples);
+    for chunk in leaves.chunks(batch_size.max(1)) {
+        let tests = chunk.iter()
+            .map(|eax| ibst::codegen::emit_cpuid_test(*eax, 0x100000))
+            .collect();
+        let (results, fault) = run_batch(dev, tests, &SamplingConfig::default())?;
+        if let Some(fault) = fault {
+            return Err(fault.to_error());
+        }
+        map.extend(chunk.iter().copied().zip(results));
     }
-    map
+    Ok(map)
 }
 
-fn main() -> Result<(), &'static str> {
-    let base_addr = ibst::get_base_address()?;
-    let fd = ibst::ibstrace_open()?;
-
-    let per_leaf_samples = sample_cpuid_known(fd);
-    print_uniq_map_accesses(&per_leaf_samples, base_addr);
+fn main() -> Result<(), ibst::Error> {
+    let arg = Args::parse();
+    let mut dev = IbstraceDevice::open()?;
+    if let Some(cpu) = arg.cpu {
+        dev.set_target_cpu(cpu)?;
+    }
+    let info = dev.info()?;
 
-    ibst::ibstrace_close(fd);
+    let per_leaf_samples = sample_cpuid_known(&mut dev, arg.batch_size)?;
+    print_uniq_map_accesses(&per_leaf_samples, &info);
     Ok(())
 }
 
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ibst::{ MockBackend, SampleBuilder };
+    use ibst::ibs::IbsMemWidth;
+    use std::convert::TryInto;
+
+    #[test]
+    fn cpuid_sweep_mock() {
+        let base = MockBackend::DEFAULT_BASE_ADDRESS;
+        let mut dev = MockBackend::new().with_generator(move |req| {
+            // Use the leaf number (from 'mov eax, imm32') as the address
+            let code = req.code();
+            let off = code.windows(2).position(|w| w == [0x0f, 0xa2]).unwrap();
+            let eax = u32::from_le_bytes(code[off-4..off].try_into().unwrap());
+            vec![SampleBuilder::new()
+                .with_rip(base + off)
+                .with_load(IbsMemWidth::Qword)
+                .with_phyad(eax as usize)
+                .build()]
+        });
+
+        let map = sample_cpuid_known(&mut dev, 4).unwrap();
+        assert_eq!(map.len(), 0x21 + 0x22);
+        for (eax, test) in &map {
+            let accs = get_uniq_accesses(&test.result, test.target_rip(base), 
+                test.layout
+            );
+            assert_eq!(accs.first().unwrap().phys, *eax as usize);
+        }
+        print_uniq_map_accesses(&map, &dev.info().unwrap());
+    }
+}
@@ -2,7 +2,8 @@
 use std::io::BufRead;
 use std::collections::*;
 use dynasmrt::AssemblyOffset;
-use ibst::Sample;
+use ibst::{ IbstraceDevice, IbstraceInfo, MeasurementBackend, RecordingBackend, 
+    ReplayBackend, Sample, SamplingConfig };
 use ibst::analysis::*;
 use itertools::*;
 use ibst::msr::*;
@@ -15,10 +16,9 @@ use clap::Parser;
 /// Use the 'ibstrace' kernel module to analyze the memory accesses produced 
 /// by 'rdmsr' for different MSR numbers.
 ///
-/// WARNING: 'ibstrace' and this tool do *not* validate input to 'rdmsr' and 
-/// do not gracefully handle any exceptions/faults produced by 'rdmsr'. 
-/// If you specify an MSR that causes an exception/fault, you will probably
-/// crash hard in the kernel. 
+/// NOTE: 'ibstrace' and this tool do *not* validate input to 'rdmsr'. 
+/// MSRs that cause an exception/fault (ie. #GP for unimplemented MSRs) are 
+/// caught by 'ibstrace', and are reported separately.
 ///
 #[derive(Parser)]
 #[command(verbatim_doc_comment)]
@@ -30,34 +30,103 @@ struct Args {
     /// Add one or more MSRs to test, ie. '--msr=c0000080,000000e7,
     #[arg(long,value_delimiter=',',num_args=1..,)]
     msr: Option<Vec<String>>,
+
+    /// Record this session to an archive file
+    #[arg(long,conflicts_with="replay")]
+    record: Option<String>,
+
+    /// Replay a session from an archive file (instead of using 'ibstrace')
+    #[arg(long,)]
+    replay: Option<String>,
+
+    /// The core used to run tests (otherwise, use the current target core)
+    #[arg(long,)]
+    cpu: Option<usize>,
+
+    /// The number of MSRs tested with a single request to 'ibstrace'
+    #[arg(long,default_value_t=64)]
+    batch_size: usize,
 }
 
 /// Test a single MSR read, returning a list of IBS samples. 
-fn sample_msr(fd: i32, msr: u32, iters: usize) -> TestResult {
-    run_test(fd, ibst::codegen::emit_msr_test(msr, iters))
+fn sample_msr<B>(dev: &mut B, msr: u32, iters: usize) 
+    -> Result<TestResult, ibst::Error> 
+    where B: MeasurementBackend + ?Sized
+{
+    run_test(dev, ibst::codegen::emit_msr_test(msr, iters))
 }
 
-/// Test a list of MSRs, returning a map from ECX values to sets of IBS samples.
-fn sample_msr_set(fd: i32, msr_list: &[u32]) -> BTreeMap<u32, TestResult> {
+/// Test a list of MSRs, returning a map from ECX values to sets of IBS samples,
+/// and a map from ECX values to the fault taken by 'rdmsr'.
+///
+/// MSRs are tested in batches of `batch_size`. When an MSR faults, the rest
+/// of its batch is tested with another batch. If a batch fails, the MSRs in
+/// that batch are tested one-by-one instead. MSRs that fail to be measured 
+/// for any other reason are reported and omitted from both maps. 
+fn sample_msr_set<B>(dev: &mut B, msr_list: &[u32], batch_size: usize) 
+    -> (BTreeMap<u32, TestResult>, BTreeMap<u32, ibst::Error>)
+    where B: MeasurementBackend + ?Sized
+{
     let mut map = BTreeMap::new();
-    for msr in msr_list.iter() {
-        eprintln!("sampling {:08x}", msr);
-        let samples = sample_msr(fd, *msr, 0x1_0000);
-        map.insert(*msr, samples);
+    let mut faults = BTreeMap::new();
+    for chunk in msr_list.chunks(batch_size.max(1)) {
+        eprintln!("sampling {:08x}..={:08x}", chunk[0], chunk[chunk.len() - 1]);
+        let mut rest = chunk;
+        while !rest.is_empty() {
+            let tests = rest.iter()
+                .map(|msr| ibst::codegen::emit_msr_test(*msr, 0x1_0000))
+                .collect();
+            match run_batch(dev, tests, &SamplingConfig::default()) {
+                Ok((results, fault)) => {
+                    map.extend(rest.iter().copied().zip(results));
+                    rest = match fault {
+                        Some(fault) => {
+                            faults.insert(rest[fault.index], fault.to_error());
+                            &rest[fault.index + 1..]
+                        },
+                        None => &[],
+                    };
+                },
+                Err(e) => {
+                    eprintln!("failed to sample batch ({}), retrying", e);
+                    for msr in rest {
+                        match sample_msr(dev, *msr, 0x1_0000) {
+                            Ok(samples) => { map.insert(*msr, samples); },
+                            Err(e @ ibst::Error::MeasuredCodeFault { .. }) => {
+                                faults.insert(*msr, e);
+                            },
+                            Err(e) => eprintln!("failed to sample {:08x}: {}", msr, e),
+                        }
+                    }
+                    rest = &[];
+                },
+            }
+        }
+    }
+    (map, faults)
+}
+
+/// Print the MSRs which caused a fault to stdout
+fn print_faults(faults: &BTreeMap<u32, ibst::Error>) {
+    if faults.is_empty() {
+        return;
+    }
+    println!("[*] MSRs which caused a fault:");
+    for (msr, e) in faults {
+        println!("  {:08x} {}", msr, e);
     }
-    map
 }
 
 /// Print results to stdout
-fn print_results(map: &BTreeMap<u32, TestResult>, buf: usize)
+fn print_results(map: &BTreeMap<u32, TestResult>, info: &IbstraceInfo)
 {
     let num_msrs = map.len();
 
     // Associate each MSR to a set of observed memory accesses. 
     let mut per_msr_accs: BTreeMap<u32, BTreeSet<MemoryAccess>> = BTreeMap::new();
     for (msr, test) in map {
-        let tgt_rip = buf + test.params.tgt_instr_off;
-        let uniq_accesses = get_uniq_accesses(&test.result, tgt_rip);
+        let tgt_rip = test.target_rip(info.code_buf);
+        let uniq_accesses = get_uniq_accesses(&test.result, tgt_rip, test.layout);
         per_msr_accs.insert(*msr, uniq_accesses);
     }
 
@@ -93,15 +162,15 @@ fn print_results(map: &BTreeMap<u32, TestResult>, buf: usize)
         } else { 
             format!("unknown")
         };
-        println!("  {:016x} {:02x} {:4?} => {:08x} ({})",  
-            acc.phys, acc.width, acc.kind, msr_num, msr_name
+        println!("  {:016x} {:02x} {:4?} => {:08x} ({}) {}",  
+            acc.phys, acc.width, acc.kind, msr_num, msr_name, acc.label(info)
         );
     }
 }
 
 
 
-fn main() -> Result<(), &'static str> {
+fn main() -> Result<(), ibst::Error> {
     let arg = Args::parse();
 
     let mut msr_set: BTreeSet<u32> = BTreeSet::new();
@@ -130,18 +199,99 @@ fn main() -> Result<(), &'static str> {
     }
 
 
-    let base_addr = ibst::get_base_address()
-        .expect("Couldn't read base address from 'ibstrace'; is the module loaded?");
-    let fd = ibst::ibstrace_open()
-        .expect("Couldn't open 'ibstrace' device"); 
+    let mut dev: Box<dyn MeasurementBackend> = match (arg.record, arg.replay) {
+        (_, Some(path)) => Box::new(ReplayBackend::open(path)?),
+        (Some(path), _) => {
+            Box::new(RecordingBackend::create(IbstraceDevice::open()?, path)?)
+        },
+        (None, None) => Box::new(IbstraceDevice::open()?),
+    };
+    if let Some(cpu) = arg.cpu {
+        dev.set_target_cpu(cpu)?;
+    }
+    let info = dev.info()?;
 
     let msr_list: Vec<u32> = msr_set.iter().map(|e| *e).collect();
-    let per_msr_samples = sample_msr_set(fd, &msr_list);
+    let (per_msr_samples, faults) = sample_msr_set(&mut *dev, &msr_list, 
+        arg.batch_size
+    );
 
-    print_results(&per_msr_samples, base_addr);
-
-    ibst::ibstrace_close(fd);
+    print_results(&per_msr_samples, &info);
+    print_faults(&faults);
     Ok(())
 }
 
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ibst::{ MockBackend, Registers, SampleBuilder };
+    use ibst::ibs::IbsMemWidth;
+    use std::convert::TryInto;
+    use nix::errno::Errno;
+
+    #[test]
+    fn msr_sweep_mock() {
+        let base = MockBackend::DEFAULT_BASE_ADDRESS;
+        let msr_of = |code: &[u8]| {
+            let off = code.windows(2).position(|w| w == [0x0f, 0x32]).unwrap();
+            (off, u32::from_le_bytes(code[off-4..off].try_into().unwrap()))
+        };
+        let mut dev = MockBackend::new().with_generator(move |req| {
+            // Use the MSR number (from 'mov ecx, imm32') as the address
+            let (off, msr) = msr_of(req.code());
+            vec![SampleBuilder::new()
+                .with_rip(base + off)
+                .with_load(IbsMemWidth::Qword)
+                .with_phyad(msr as usize)
+                .build()]
+        }).with_registers(move |req| {
+            // Pretend that 'rdmsr' returns the MSR number in EDX:EAX
+            let (_, msr) = msr_of(req.code());
+            Registers { rax: msr as usize, rdx: 0xffff_ffff, ..Default::default() }
+        });
+        // The first error fails the batch, and the second fails 0x10 when 
+        // retrying each MSR separately. Then, 0x20 faults.
+        for _ in 0..2 {
+            dev.push_error(ibst::Error::Ioctl { 
+                cmd: ibst::ioctl::CMD_MEASURE, errno: Errno::EIO 
+            });
+        }
+        let fault = ibst::Error::MeasuredCodeFault { 
+            vector: 13, rip: base + 0x20, error_code: 0 
+        };
+        dev.push_error(fault.clone());
+
+        let msrs = [0x10, 0x20, 0xc000_0080, 0xc001_0015];
+        let (map, faults) = sample_msr_set(&mut dev, &msrs, 8);
+        assert_eq!(map.len(), 2);
+        assert!(!map.contains_key(&0x10) && !map.contains_key(&0x20));
+        assert_eq!(faults.len(), 1);
+        assert_eq!(faults.get(&0x20), Some(&fault));
+        for (msr, test) in &map {
+            let accs = get_uniq_accesses(&test.result, test.target_rip(base), 
+                test.layout
+            );
+            assert_eq!(accs.first().unwrap().phys, *msr as usize);
+            assert_eq!(test.return_value, *msr as usize);
+            assert_eq!(test.regs.rdx, 0xffff_ffff);
+        }
+        print_results(&map, &dev.info().unwrap());
+        print_faults(&faults);
+
+        // A fault in the middle of a batch keeps the results before it, and
+        // only the MSRs after it are measured again
+        let before = dev.measurements();
+        dev.push_samples(vec![]);
+        dev.push_error(fault.clone());
+        let (map, faults) = sample_msr_set(&mut dev, &msrs, 8);
+        assert_eq!(dev.measurements() - before, msrs.len());
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), 
+            [0x10, 0xc000_0080, 0xc001_0015]
+        );
+        assert!(map[&0x10].result.is_empty());
+        assert_eq!(map[&0xc000_0080].return_value, 0xc000_0080);
+        assert_eq!(faults.get(&0x20), Some(&fault));
+    }
+}
@@ -10,7 +10,7 @@ use ibst::emit_test_iters_rsi;
 use ibst::codegen::*;
 use ibst::analysis::*;
 
-use ibst::Sample;
+use ibst::{ DecodedSample, IbstraceDevice, Sample };
 use std::collections::{HashMap, BTreeSet, BTreeMap};
 
 /// Emit some code we want to measure
@@ -24,9 +24,9 @@ fn emit_test(val: u32, iters: usize) -> TestParameters {
 }
 
 
-fn main() -> Result<(), &'static str> {
-    let base_addr = ibst::get_base_address()?;
-    let fd = ibst::ibstrace_open()?;
+fn main() -> Result<(), ibst::Error> {
+    let mut dev = IbstraceDevice::open()?;
+    let base_addr = dev.base_address()?;
 
     // Emit measured code
     let params = emit_test(0, 0x100000);
@@ -41,24 +41,29 @@ fn main() -> Result<(), &'static str> {
     println!("[*] target_end:   {:016x}", target_end);
 
     // Upload measured code and use the "measure" ioctl() to collect samples
-    let res = run_test(fd, params);
-    println!("[*] Collected {} samples", res.result.len());
+    let res = run_test(&mut dev, params)?;
+    println!("[*] Collected {} samples ({})", res.result.len(), res.stats);
+    if res.overflowed() {
+        println!("[!] Sample buffer overflowed, distribution is truncated");
+    }
 
     // Print sampled load/store ops for RDTSC
-    print_load_lat_dist(&res.result, target_start);
+    print_load_lat_dist(&res.result, target_start, res.layout);
 
     let mut by_pc: BTreeMap<usize, BTreeSet<SampleInfo>> = BTreeMap::new();
     for sample in res.result.iter() {
-        if let Some(mut set) = by_pc.get_mut(&sample.rip) {
-            set.insert(SampleInfo::from_sample(sample));
+        let rip = match DecodedSample::new(sample, res.layout).rip {
+            Some(rip) => rip, None => continue
+        };
+        if let Some(mut set) = by_pc.get_mut(&rip) {
+            set.insert(SampleInfo::from_sample(sample, res.layout));
         } else { 
             let mut set = BTreeSet::new();
-            set.insert(SampleInfo::from_sample(sample));
-            by_pc.insert(sample.rip, set);
+            set.insert(SampleInfo::from_sample(sample, res.layout));
+            by_pc.insert(rip, set);
         }
     }
 
-    ibst::ibstrace_close(fd);
     Ok(())
 }
 
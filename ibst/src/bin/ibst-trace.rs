@@ -10,7 +10,7 @@ use ibst::trace::*;
 use std::fs::File;
 use std::io::Write;
 
-use ibst::Sample;
+use ibst::{ IbstraceDevice, Sample };
 use std::collections::{HashMap, BTreeSet, BTreeMap};
 
 /// Measured code (try sampling the RDTSC instruction)
@@ -41,18 +41,27 @@ fn emit_test() -> TestParameters {
 }
 
 
-fn main() -> Result<(), &'static str> {
+fn main() -> Result<(), ibst::Error> {
+
+    // Re-print a trace saved by a previous run (without hardware)
+    if let Some(path) = std::env::args().nth(1) {
+        let f = File::open(&path)
+            .map_err(|e| ibst::Error::TraceFormat(format!("{}: {}", path, e)))?;
+        Trace::from_reader(std::io::BufReader::new(f))?.print();
+        return Ok(());
+    }
 
     // Emit measured code
     let params = emit_test();
 
     // Collect a trace of some range of micro-ops
-    let mut trace = Trace::collect_from(&params, 0..=128, 0)?;
+    let mut dev = IbstraceDevice::open()?;
+    let mut trace = Trace::collect_from(&mut dev, &params, 0..=128, 0)?;
 
     // Only keep ops associated with RDTSC
-    let base_addr = ibst::get_base_address()?;
+    let base_addr = dev.base_address()?;
     let tgt_rip = base_addr + params.tgt_instr_off;
-    trace.retain(|e| e.rip == tgt_rip);
+    trace.retain(|e| e.rip == Some(tgt_rip));
 
     // Convenience method for easy output
     trace.print();
@@ -60,7 +69,7 @@ fn main() -> Result<(), &'static str> {
     // Serialize this trace to JSON and write to /tmp/
     let json = trace.to_json();
     let mut f = File::create("/tmp/ibs-trace.json").unwrap();
-    f.write(json.as_bytes());
+    f.write_all(json.as_bytes()).unwrap();
 
     Ok(())
 }
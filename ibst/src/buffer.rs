@@ -0,0 +1,139 @@
+//! Decoding raw sample data.
+
+use crate::*;
+
+/// A buffer of raw sample data (ie. as returned by the `ibstrace` kernel
+/// module, or as read back from a file).
+///
+/// Each record is decoded field-by-field from its little-endian 
+/// representation, so the underlying bytes don't need to be aligned.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleBuffer<'a> {
+    bytes: &'a [u8],
+    format: SampleFormat,
+}
+impl<'a> SampleBuffer<'a> {
+    /// Create a new buffer of [SampleFormat::V1] records, validating that 
+    /// `bytes` contains a whole number of sample records.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        Self::with_format(bytes, SampleFormat::V1)
+    }
+
+    /// Create a new buffer of records in a particular [SampleFormat], 
+    /// validating that `bytes` contains a whole number of sample records.
+    pub fn with_format(bytes: &'a [u8], format: SampleFormat) 
+        -> Result<Self, Error> 
+    {
+        if !bytes.len().is_multiple_of(format.record_size()) {
+            return Err(Error::TruncatedSample { 
+                len: bytes.len(), 
+                record_size: format.record_size(),
+            });
+        }
+        Ok(Self { bytes, format })
+    }
+
+    /// Return the format of records in this buffer.
+    pub fn format(&self) -> SampleFormat {
+        self.format
+    }
+
+    /// Return the number of samples in this buffer.
+    pub fn len(&self) -> usize {
+        self.bytes.len() / self.format.record_size()
+    }
+
+    /// Returns `true` if this buffer contains no samples.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Decode the sample at the given index.
+    pub fn get(&self, idx: usize) -> Option<Sample> {
+        let size = self.format.record_size();
+        let start = idx.checked_mul(size)?;
+        let end = start.checked_add(size)?;
+        let record = self.bytes.get(start..end)?;
+        Some(Sample::from_record(self.format, record))
+    }
+
+    /// Return an iterator which decodes each sample in this buffer.
+    pub fn iter(&self) -> impl Iterator<Item = Sample> + 'a {
+        let format = self.format;
+        self.bytes.chunks_exact(format.record_size()).map(move |record| {
+            Sample::from_record(format, record)
+        })
+    }
+
+    /// Decode all samples in this buffer.
+    pub fn to_vec(&self) -> Vec<Sample> {
+        self.iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ibs::*;
+    use crate::buffer::*;
+
+    #[test]
+    fn decode_unaligned() {
+        let ctl = IbsOpCtl::new()
+            .with_max_cnt(0x1000)
+            .with_cur_cnt(0x100)
+            .with_val(true);
+        let samples: Vec<Sample> = (0..4).map(|i| SampleBuilder::new()
+            .with_ctl(ctl)
+            .with_rip(0xffff_ffff_c000_0000 + i)
+            .with_load(IbsMemWidth::Qword)
+            .with_phyad(0x1000 * i)
+            .with_tgt_rip(i)
+            .build()
+        ).collect();
+
+        // Offset all of the records by a single byte
+        let mut bytes = vec![0u8];
+        for s in samples.iter() {
+            bytes.extend_from_slice(&s.to_le_bytes());
+        }
+        let buf = SampleBuffer::new(&bytes[1..]).unwrap();
+        assert_eq!(buf.len(), 4);
+        assert_eq!(buf.to_vec(), samples);
+        assert_eq!(buf.get(3).unwrap().ctl.0, samples[3].ctl.0);
+        assert!(buf.get(4).is_none());
+
+        // Indices where the end of the record would overflow
+        assert!(buf.get(usize::MAX / Sample::SIZE).is_none());
+        assert!(buf.get(usize::MAX).is_none());
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let bytes = vec![0u8; Sample::SIZE * 2 + 8];
+        assert_eq!(SampleBuffer::new(&bytes).err(), Some(Error::TruncatedSample {
+            len: Sample::SIZE * 2 + 8, record_size: Sample::SIZE
+        }));
+        assert!(SampleBuffer::new(&bytes[..Sample::SIZE * 2]).is_ok());
+        assert_eq!(SampleBuffer::with_format(&bytes, SampleFormat::V2).err(), 
+            Some(Error::TruncatedSample { len: Sample::SIZE * 2 + 8, record_size: 96 })
+        );
+    }
+
+    #[test]
+    fn decode_v2() {
+        let samples: Vec<Sample> = (0..3).map(|i| Sample {
+            rip: 0x1000 + i,
+            meta: Some(SampleMeta { seq: i, tsc: 100 * i, intr_rip: 0x1000 }),
+            ..Default::default()
+        }).collect();
+        let bytes: Vec<u8> = samples.iter()
+            .flat_map(|s| s.to_record(SampleFormat::V2))
+            .collect();
+        let buf = SampleBuffer::with_format(&bytes, SampleFormat::V2).unwrap();
+        assert_eq!(buf.len(), 3);
+        assert_eq!(buf.get(2).unwrap().meta, samples[2].meta);
+        assert_eq!(buf.iter().map(|s| s.meta).collect::<Vec<_>>(), 
+            samples.iter().map(|s| s.meta).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Building synthetic samples.
+//!
+//! A [SampleBuilder] is used to create samples with some combination of
+//! IBS op data fields (ie. for tests, where we can't collect real samples).
+
+use crate::*;
+use crate::ibs::*;
+
+/// Builder for a [Sample].
+///
+/// The helper methods here only set the bits which are relevant to some
+/// event - use [SampleBuilder::with_data], [SampleBuilder::with_data2] and
+/// [SampleBuilder::with_data3] to set any other fields directly.
+///
+/// ```
+/// use ibst::SampleBuilder;
+/// use ibst::ibs::IbsMemWidth;
+/// let s = SampleBuilder::new()
+///     .with_rip(0x1000)
+///     .with_load(IbsMemWidth::Qword)
+///     .with_linad(0xdead_0000)
+///     .build();
+/// assert!(s.data3.ld_op() && s.data3.dc_lin_addr_valid());
+/// assert_eq!(s.data3.op_mem_width(), IbsMemWidth::Qword);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SampleBuilder {
+    sample: Sample,
+}
+impl SampleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the IBS_OP_CTL value for this sample.
+    pub fn with_ctl(mut self, ctl: IbsOpCtl) -> Self {
+        self.sample.ctl = ctl;
+        self
+    }
+    /// Set the instruction pointer for this sample.
+    pub fn with_rip(mut self, rip: usize) -> Self {
+        self.sample.rip = rip;
+        self
+    }
+    /// Set the metadata for this sample.
+    pub fn with_meta(mut self, meta: SampleMeta) -> Self {
+        self.sample.meta = Some(meta);
+        self
+    }
+
+    /// Modify the IBS_OP_DATA value for this sample.
+    pub fn with_data(mut self, f: impl FnOnce(&mut IbsOpData)) -> Self {
+        f(&mut self.sample.data);
+        self
+    }
+    /// Modify the IBS_OP_DATA2 value for this sample.
+    pub fn with_data2(mut self, f: impl FnOnce(&mut IbsOpData2)) -> Self {
+        f(&mut self.sample.data2);
+        self
+    }
+    /// Modify the IBS_OP_DATA3 value for this sample.
+    pub fn with_data3(mut self, f: impl FnOnce(&mut IbsOpData3)) -> Self {
+        f(&mut self.sample.data3);
+        self
+    }
+
+    /// Set the tag-to-retire and completion-to-retire latencies (in cycles).
+    pub fn with_latency(self, tag_to_ret: usize, comp_to_ret: usize) -> Self {
+        self.with_data(|d| {
+            d.set_tag_to_ret_ctr(tag_to_ret);
+            d.set_comp_to_ret_ctr(comp_to_ret);
+        })
+    }
+    /// Mark the sampled op as microcoded.
+    pub fn with_microcode(self) -> Self {
+        self.with_data(|d| d.set_op_microcode(true))
+    }
+    /// Mark the sampled op as a return.
+    pub fn with_return(self) -> Self {
+        self.with_data(|d| d.set_op_return(true))
+    }
+    /// Mark the sampled op as a retired branch.
+    pub fn with_branch(self, taken: bool, mispredicted: bool) -> Self {
+        self.with_data(|d| {
+            d.set_op_brn_ret(true);
+            d.set_op_brn_taken(taken);
+            d.set_op_brn_misp(mispredicted);
+        })
+    }
+    /// Set the branch target address for this sample.
+    pub fn with_tgt_rip(mut self, tgt_rip: usize) -> Self {
+        self.sample.tgt_rip = tgt_rip;
+        self
+    }
+
+    /// Mark the sampled op as a load with some width.
+    pub fn with_load(self, width: IbsMemWidth) -> Self {
+        self.with_data3(|d| {
+            d.set_ld_op(true);
+            d.set_op_mem_width(width);
+        })
+    }
+    /// Mark the sampled op as a store with some width.
+    pub fn with_store(self, width: IbsMemWidth) -> Self {
+        self.with_data3(|d| {
+            d.set_st_op(true);
+            d.set_op_mem_width(width);
+        })
+    }
+    /// Set the linear address for this sample (and mark it as valid).
+    pub fn with_linad(mut self, linad: usize) -> Self {
+        self.sample.linad = linad;
+        self.with_data3(|d| d.set_dc_lin_addr_valid(true))
+    }
+    /// Set the physical address for this sample (and mark it as valid).
+    pub fn with_phyad(mut self, phyad: usize) -> Self {
+        self.sample.phyad = phyad;
+        self.with_data3(|d| d.set_dc_phy_addr_valid(true))
+    }
+
+    /// Mark the access as a data cache miss with some latency (in cycles).
+    pub fn with_dc_miss(self, lat: usize) -> Self {
+        self.with_data3(|d| {
+            d.set_dc_miss(true);
+            d.set_dc_miss_lat(lat);
+        })
+    }
+    /// Mark the access as an L2 cache miss.
+    pub fn with_l2_miss(self) -> Self {
+        self.with_data3(|d| d.set_dc_l2_miss(true))
+    }
+    /// Set the source of the data for an access that missed in the cache
+    /// (with the encodings used before Zen 4).
+    pub fn with_data_src(self, src: NbDataSrc) -> Self {
+        self.with_data2(|d| d.set_data_src(src))
+    }
+    /// Set the source of the data for an access that missed in the cache
+    /// (with the encodings used since Zen 4).
+    pub fn with_data_src_ext(self, src: NbDataSrc) -> Self {
+        self.with_data2(|d| d.set_data_src_ext(src))
+    }
+
+    /// Mark the access as an L1 DTLB miss.
+    pub fn with_l1tlb_miss(self) -> Self {
+        self.with_data3(|d| d.set_dc_l1tlb_miss(true))
+    }
+    /// Mark the access as an L2 DTLB miss with some refill latency (in
+    /// cycles).
+    pub fn with_l2tlb_miss(self, lat: usize) -> Self {
+        self.with_data3(|d| {
+            d.set_dc_l2tlb_miss(true);
+            d.set_tlb_refill_lat(lat);
+        })
+    }
+    /// Set the page size of the L1 DTLB entry (since Zen 3).
+    pub fn with_l1tlb_pg_sz(self, sz: IbsPageSize) -> Self {
+        self.with_data3(|d| d.set_dc_l1tlb_pg_sz(sz))
+    }
+
+    pub fn build(self) -> Sample {
+        self.sample
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use crate::ibs::*;
+
+    #[test]
+    fn build_sample() {
+        let s = SampleBuilder::new()
+            .with_rip(0x1234)
+            .with_load(IbsMemWidth::Dword)
+            .with_linad(0x7fff_0008)
+            .with_phyad(0x0001_0008)
+            .with_dc_miss(0x40)
+            .with_l2_miss()
+            .with_data_src(NbDataSrc::Dram)
+            .with_l2tlb_miss(0x80)
+            .with_l1tlb_miss()
+            .with_latency(0x100, 0x10)
+            .build();
+        assert_eq!(s.rip, 0x1234);
+        assert!(s.data3.ld_op() && !s.data3.st_op());
+        assert_eq!(s.data3.op_mem_width(), IbsMemWidth::Dword);
+        assert!(s.data3.dc_lin_addr_valid() && s.data3.dc_phy_addr_valid());
+        assert_eq!((s.linad, s.phyad), (0x7fff_0008, 0x0001_0008));
+        assert!(s.data3.dc_miss() && s.data3.dc_l2_miss());
+        assert_eq!(s.data3.dc_miss_lat(), 0x40);
+        assert_eq!(s.data2.data_src(), NbDataSrc::Dram);
+        assert!(s.data3.dc_l1tlb_miss() && s.data3.dc_l2tlb_miss());
+        assert_eq!(s.data3.tlb_refill_lat(), 0x80);
+        assert_eq!(s.data.tag_to_ret_ctr(), 0x100);
+        assert_eq!(s.data.comp_to_ret_ctr(), 0x10);
+        assert!(!s.data.op_brn_ret());
+
+        let s = SampleBuilder::new()
+            .with_branch(true, false)
+            .with_tgt_rip(0x2000)
+            .build();
+        assert!(s.data.op_brn_ret() && s.data.op_brn_taken());
+        assert!(!s.data.op_brn_misp() && !s.data3.ld_op());
+        assert_eq!(s.tgt_rip, 0x2000);
+
+        // Decoded with the layout for a particular part
+        let s = SampleBuilder::new()
+            .with_store(IbsMemWidth::Yword)
+            .with_data_src_ext(NbDataSrc::ExtMem)
+            .with_l1tlb_pg_sz(IbsPageSize::Page2M)
+            .build();
+        let res = IbsLayout::Zen4.decode(&s);
+        assert_eq!(res.data_src, NbDataSrc::ExtMem);
+        assert_eq!(res.dc_l1tlb_pg_sz, Some(IbsPageSize::Page2M));
+        assert!(res.st_op && !res.ld_op);
+    }
+}
@@ -2,23 +2,35 @@
 //!
 //! # Runtime Environment
 //! The trampoline in the `ibstrace` kernel module uses `CALL` to enter some
-//! measured code. Any generated code should terminate with a `RET`.
+//! measured code. Any generated code should terminate with a `RET`. The
+//! value left in RAX (and the rest of the register state) is reported back
+//! after measurement, see [MeasureOutcome](crate::MeasureOutcome).
 //!
 //! The kernel module passes a single parameter to measured code in RDI - as 
-//! of right now, this is the physical address of some freshly-allocated page
-//! which is free-for-use by the measured code. Otherwise, assume the initial 
+//! of right now, this is the virtual address of a "scratch" page which is 
+//! free-for-use by the measured code. In the "precise" environment, RDI is 
+//! the argument from [PreciseArgs](crate::ioctl::PreciseArgs) instead, and 
+//! the scratch page is passed in RSI. Otherwise, assume the initial 
 //! state of all other registers is undefined. The current design is not 
 //! intended to support measured code with stack usage, and I haven't thought 
 //! about it at all yet. 
 //!
+//! The scratch page can be filled with input data before measurement (see 
+//! [IbstraceDevice::upload_data](crate::IbstraceDevice::upload_data)), and 
+//! any results written by measured code can be read back afterwards (see 
+//! [IbstraceDevice::read_scratch](crate::IbstraceDevice::read_scratch)). 
+//! The contents of the page persist between measurements.
+//!
 //! ## Safety
 //! This entire thing is *highly unsafe by design*, and there's practically no
-//! avoiding it - if you do something wrong in measured code, you *will* crash 
-//! in the context of the kernel. 
+//! avoiding it - measured code runs in the context of the kernel. 
 //!
-//! Eventually I'll think of some way to catch faulting instructions and 
-//! restore execution in a way that doesn't involve hard-resetting my machine,
-//! but that would involve some cooperation with the kernel module.
+//! The kernel module catches #GP, #UD and #PF in measured code and resumes 
+//! in the trampoline, which is reported as 
+//! [Error::MeasuredCodeFault](crate::Error::MeasuredCodeFault). A page fault 
+//! still causes the kernel to print an oops (and taint itself) before we 
+//! recover. Anything else (ie. clobbering the stack, or writing to arbitrary 
+//! memory) will probably still crash your machine. 
 //!
 //! ## Writing tests
 //! The [emit_test_iters_rsi!()] macro is suitable for emitting simple loops,
@@ -30,13 +42,9 @@ use dynasmrt::{
     DynasmApi, 
     DynasmLabelApi,
     Assembler, 
-    AssemblyOffset, 
     ExecutableBuffer, 
     x64::X64Relocation,
-    components::{
-        LabelRegistry,
-        StaticLabel,
-    },
+    components::StaticLabel,
 };
 
 // NOP encodings from length 1-15 (single instructions).
@@ -79,8 +87,6 @@ pub const NOP15: [u8;15] = [
 
 
 
-
-
 /// Description of the code generated/assembled for a particular test.
 ///
 /// NOTE: Right now, this model assumes that we're interested in samples
@@ -94,15 +100,6 @@ pub struct TestParameters {
     /// Offset to the end of measured code
     pub tgt_instr_end: usize, 
 }
-impl TestParameters {
-    /// Create the appropriate ioctl message for this test.
-    pub fn to_userbuf(&self) -> crate::ioctl::UserBuf {
-        crate::ioctl::UserBuf::new(
-            self.buf.ptr(AssemblyOffset(0)), 
-            self.buf.len(),
-        )
-    }
-}
 
 
 #[macro_export]
@@ -148,30 +145,6 @@ macro_rules! emit_test {
     } }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::codegen::*;
-    use crate::util::*;
-    #[test]
-    fn test() {
-        let msr: u32 = 0xc0010200;
-        let t = emit_test!(0x1000,
-            {
-                ; mov ecx, msr as _
-                ; xor ecx, ecx
-            },
-            {
-                ; mov ecx, msr as _
-                ; ->target:
-                ; rdmsr
-            }
-        );
-        disas(&t.buf);
-    }
-
-}
-
-
 
 /// Wrapper around dynasm for emitting a simple loop (decrementing RSI).
 ///
@@ -193,7 +166,7 @@ macro_rules! emit_test_iters_rsi {
 
             ; sub   rsi, 1
             ; jne   ->loop_start
-            ; mov   rax, 42
+            // Leave results from the last iteration in registers
             ; ret
         );
 
@@ -223,6 +196,25 @@ pub fn emit_msr_test(msr: u32, iters: usize) -> TestParameters {
         ; rdmsr
     )
 }
+/// Emit a test which loads 8 bytes from some virtual address (ie. from 
+/// a [RawAlloc](crate::util::RawAlloc) via the kernel direct map, see 
+/// [RawAlloc::kernel_addr](crate::util::RawAlloc::kernel_addr)).
+pub fn emit_load_test(vaddr: usize, iters: usize) -> TestParameters {
+    emit_test_iters_rsi!(iters,
+        ; mov   rdx, QWORD vaddr as _
+        ; ->target:
+        ; mov   rax, [rdx]
+    )
+}
+/// Emit a test which stores 8 bytes to some virtual address (see 
+/// [emit_load_test]).
+pub fn emit_store_test(vaddr: usize, iters: usize) -> TestParameters {
+    emit_test_iters_rsi!(iters,
+        ; mov   rdx, QWORD vaddr as _
+        ; ->target:
+        ; mov   [rdx], rsi
+    )
+}
 pub fn emit_cpuid_test(cpuid_func: u32, iters: usize) -> TestParameters {
     emit_test_iters_rsi!(iters,
         ; mov   eax, cpuid_func as _
@@ -231,3 +223,35 @@ pub fn emit_cpuid_test(cpuid_func: u32, iters: usize) -> TestParameters {
     )
 }
 
+#[cfg(test)]
+mod test {
+    use crate::MeasurementBackend;
+    use crate::codegen::*;
+    use crate::util::*;
+    #[test]
+    fn test() {
+        let msr: u32 = 0xc0010200;
+        let t = emit_test!(0x1000,
+            {
+                ; mov ecx, msr as _
+                ; xor ecx, ecx
+            },
+            {
+                ; mov ecx, msr as _
+                ; ->target:
+                ; rdmsr
+            }
+        );
+        disas(&t.buf);
+    }
+
+    #[test]
+    fn direct_map_access() {
+        let info = crate::MockBackend::new().info().unwrap();
+        let vaddr = info.direct_map_addr(0x1_0020_0000).unwrap();
+        let t = emit_load_test(vaddr, 1);
+        assert!(t.buf.windows(8).any(|w| w == vaddr.to_le_bytes()));
+        // mov rax, [rdx]
+        assert_eq!(&t.buf[t.tgt_instr_off..t.tgt_instr_end], &[0x48, 0x8b, 0x02]);
+    }
+}
@@ -0,0 +1,96 @@
+//! A view of a sample which respects the hardware valid bits.
+//!
+//! The kernel module copies IBS_OP_RIP, IBS_DC_LINADDR, IBS_DC_PHYSADDR and
+//! BP_IBSTGT_RIP unconditionally, so a [Sample] may carry stale addresses
+//! left over from some previously-tagged op. A [DecodedSample] only exposes
+//! addresses which were marked as valid.
+
+use crate::*;
+
+/// A decoded [Sample], where addresses are only present when the hardware
+/// marked them as valid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DecodedSample {
+    /// Origin instruction pointer, unless IBS_OP_DATA.RipInvalid is set.
+    pub rip: Option<usize>,
+    /// Linear address for a memory access, if IBS_OP_DATA3.DcLinAddrValid
+    /// is set.
+    pub linad: Option<usize>,
+    /// Physical address for a memory access, if IBS_OP_DATA3.DcPhyAddrValid
+    /// is set.
+    pub phyad: Option<usize>,
+    /// Branch target address, if the tagged op was a retired branch
+    /// (IBS_OP_DATA.OpBrnRet).
+    pub tgt_rip: Option<usize>,
+    /// The IBS op data registers.
+    pub op: IbsOpDecoded,
+}
+impl DecodedSample {
+    /// Decode a sample with a particular [IbsLayout].
+    pub fn new(s: &Sample, layout: IbsLayout) -> Self {
+        let op = layout.decode(s);
+        let gate = |valid: bool, addr: usize| if valid { Some(addr) } else { None };
+        Self {
+            rip: gate(!op.rip_invalid, s.rip),
+            linad: gate(op.dc_lin_addr_valid, s.linad),
+            phyad: gate(op.dc_phy_addr_valid, s.phyad),
+            tgt_rip: gate(op.op_brn_ret, s.tgt_rip),
+            op,
+        }
+    }
+
+    /// Returns `true` if the tagged op was a load and/or store.
+    pub fn is_ldst(&self) -> bool {
+        self.op.ld_op || self.op.st_op
+    }
+
+    /// Returns `true` if the tagged op was some kind of branch.
+    pub fn is_brn(&self) -> bool {
+        self.op.op_brn_fuse || self.op.op_brn_ret || self.op.op_return ||
+        self.op.op_brn_misp || self.op.op_brn_taken
+    }
+}
+
+/// Format an address which may not be valid.
+pub fn fmt_addr(addr: Option<usize>) -> String {
+    match addr {
+        Some(x) => format!("{:016x}", x),
+        None => "-".repeat(16),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decoded::*;
+    use crate::ibs::*;
+
+    #[test]
+    fn gate_valid_bits() {
+        // Leftover addresses without any valid bits
+        let s = Sample {
+            rip: 0x1000, linad: 0x2000, phyad: 0x3000, tgt_rip: 0x4000,
+            ..Default::default()
+        };
+        let d = DecodedSample::new(&s, IbsLayout::Zen2);
+        assert_eq!(d.rip, Some(0x1000));
+        assert_eq!((d.linad, d.phyad, d.tgt_rip), (None, None, None));
+        assert!(!d.is_ldst() && !d.is_brn());
+
+        let s = SampleBuilder::new()
+            .with_rip(0x1000)
+            .with_data(|d| d.set_rip_invalid(true))
+            .with_store(IbsMemWidth::Byte)
+            .with_phyad(0x3000)
+            .with_branch(true, true)
+            .with_tgt_rip(0x4000)
+            .build();
+        let d = DecodedSample::new(&s, IbsLayout::Zen3);
+        assert_eq!(d.rip, None);
+        assert_eq!((d.linad, d.phyad), (None, Some(0x3000)));
+        assert_eq!(d.tgt_rip, Some(0x4000));
+        assert!(d.is_ldst() && d.is_brn());
+        assert_eq!(fmt_addr(d.phyad), "0000000000003000");
+        assert_eq!(fmt_addr(d.linad), "----------------");
+    }
+}
@@ -0,0 +1,549 @@
+//! Owned handle to the `ibstrace` character device.
+
+use std::os::fd::{ AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd };
+
+use crate::*;
+
+nix::ioctl_write_ptr_bad! {
+    /// Submit code-to-be-measured to the kernel module. 
+    /// Takes a pointer to a [UserBuf](ioctl::UserBuf) describing the input 
+    /// buffer.
+    ibstrace_write, ioctl::CMD_WRITE, ioctl::UserBuf
+}
+
+nix::ioctl_write_ptr_bad! {
+    /// Fill the scratch page passed to user code. 
+    /// Takes a pointer to a [UserBuf](ioctl::UserBuf) describing the input 
+    /// buffer.
+    ibstrace_write_data, ioctl::CMD_WRITE_DATA, ioctl::UserBuf
+}
+
+nix::ioctl_write_ptr_bad! {
+    /// Read back the contents of the scratch page. 
+    /// Takes a pointer to a [UserBuf](ioctl::UserBuf) describing the output 
+    /// buffer.
+    ibstrace_read_data, ioctl::CMD_READ_DATA, ioctl::UserBuf
+}
+
+nix::ioctl_write_ptr_bad! {
+    /// Execute submitted user code in the "precise" environment, collecting
+    /// a single sample of a particular micro-op. 
+    /// Takes a pointer to a [PreciseArgs](ioctl::PreciseArgs) describing the 
+    /// arguments.
+    ibstrace_precise, ioctl::CMD_PRECISE, ioctl::PreciseArgs
+}
+
+nix::ioctl_write_ptr_bad! {
+    /// Execute the submitted user code and collect samples. 
+    /// Takes a pointer to a [MeasureArgs](ioctl::MeasureArgs) describing the 
+    /// sampling configuration.
+    ibstrace_measure, ioctl::CMD_MEASURE, ioctl::MeasureArgs
+}
+
+nix::ioctl_write_ptr_bad! {
+    /// Execute the submitted user code and collect fetch samples. 
+    /// Takes a pointer to a [FetchArgs](ioctl::FetchArgs) describing the 
+    /// sampling configuration.
+    ibstrace_fetch, ioctl::CMD_FETCH, ioctl::FetchArgs
+}
+
+nix::ioctl_write_ptr_bad! {
+    /// Execute submitted user code in the "precise" environment once for 
+    /// each offset in a range, collecting (at most) a single sample for each.
+    /// Takes a pointer to a [SweepArgs](ioctl::SweepArgs) describing the 
+    /// arguments.
+    ibstrace_sweep, ioctl::CMD_SWEEP, ioctl::SweepArgs
+}
+
+nix::ioctl_write_ptr_bad! {
+    /// Upload and execute a list of programs, collecting samples for each.
+    /// Takes a pointer to a [BatchArgs](ioctl::BatchArgs) describing the 
+    /// programs and the sampling configuration.
+    ibstrace_batch, ioctl::CMD_BATCH, ioctl::BatchArgs
+}
+
+nix::ioctl_none_bad! {
+    /// Return the number of currently-collected samples.
+    ibstrace_samples, ioctl::CMD_SAMPLES
+}
+
+nix::ioctl_none_bad! {
+    /// Return the maximum number of entries in the sample buffer.
+    ibstrace_capacity, ioctl::CMD_CAPACITY
+}
+
+nix::ioctl_read_bad! {
+    /// Read the fault taken by measured code during the most-recent 
+    /// measurement. Takes a pointer to [FaultInfo](ioctl::FaultInfo) to be 
+    /// filled in by the kernel module.
+    ibstrace_fault, ioctl::CMD_FAULT, ioctl::FaultInfo
+}
+
+nix::ioctl_none_bad! {
+    /// Return the version of records in the sample buffer.
+    ibstrace_version, ioctl::CMD_VERSION
+}
+
+nix::ioctl_write_int_bad! {
+    /// Change the core used to run user code. 
+    /// Takes the core number as an argument.
+    ibstrace_set_cpu, ioctl::CMD_SET_CPU
+}
+
+nix::ioctl_read_bad! {
+    /// Read the register state of the most-recently measured user code.
+    /// Takes a pointer to [Registers] to be filled in by the kernel module.
+    ibstrace_regs, ioctl::CMD_REGS, Registers
+}
+
+nix::ioctl_read_bad! {
+    /// Read statistics about samples from the most-recent measurement.
+    /// Takes a pointer to [SampleStats] to be filled in by the kernel module.
+    ibstrace_stats, ioctl::CMD_STATS, SampleStats
+}
+
+/// An open handle to the `ibstrace` character device. 
+///
+/// The underlying file descriptor is closed when this is dropped. 
+/// Operations that change the state of the kernel module take `&mut self`, 
+/// so only a single user can drive a particular handle at once.
+pub struct IbstraceDevice {
+    fd: OwnedFd,
+    format: SampleFormat,
+    layout: IbsLayout,
+}
+impl IbstraceDevice {
+    /// Try to open the `ibstrace` character device.
+    pub fn open() -> Result<Self, Error> {
+        use nix::sys::stat::Mode;
+        use nix::fcntl::{ open, OFlag };
+        use nix::errno::Errno;
+
+        let fd = match open(ioctl::IBSTRACE_CHARDEV, OFlag::O_RDWR, Mode::S_IRWXU) {
+            // SAFETY: We just opened this file descriptor, and nothing 
+            // else owns it.
+            Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
+            Err(e) => return match e {
+                Errno::ENOENT => Err(Error::DeviceMissing),
+                Errno::EACCES => Err(Error::PermissionDenied),
+                _ => Err(Error::Open(e)),
+            }
+        };
+
+        // Older versions of the module don't support this command, and 
+        // only store [SampleFormat::V1] records
+        let format = match unsafe { ibstrace_version(fd.as_raw_fd()) } {
+            Ok(version) => SampleFormat::from_version(version as usize)
+                .ok_or(Error::UnsupportedSampleVersion { version: version as usize })?,
+            Err(Errno::EINVAL) => SampleFormat::V1,
+            Err(errno) => return Err(Error::Ioctl { cmd: ioctl::CMD_VERSION, errno }),
+        };
+        let layout = IbsLayout::detect().unwrap_or_default();
+        Ok(Self { fd, format, layout })
+    }
+
+    /// Return the format of records in the sample buffer.
+    pub fn sample_format(&self) -> SampleFormat {
+        self.format
+    }
+
+    /// Return the [IbsLayout] used to decode samples (detected when the 
+    /// device was opened).
+    pub fn layout(&self) -> IbsLayout {
+        self.layout
+    }
+
+    /// Upload user code to the kernel module.
+    pub fn upload(&mut self, code: &[u8]) -> Result<(), Error> {
+        if code.len() > ioctl::CODE_BUFFER_MAX_SIZE {
+            return Err(Error::BufferTooLarge { 
+                len: code.len(), 
+                max: ioctl::CODE_BUFFER_MAX_SIZE,
+            });
+        }
+        let msg = ioctl::UserBuf::new(code.as_ptr(), code.len());
+        unsafe { 
+            ibstrace_write(self.fd.as_raw_fd(), &msg).map_err(|errno| {
+                Error::Ioctl { cmd: ioctl::CMD_WRITE, errno }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Fill the scratch page passed to user code (the rest of the page is 
+    /// zeroed). 
+    ///
+    /// The contents persist across measurements until the next upload, 
+    /// and can be read back with [IbstraceDevice::read_scratch].
+    pub fn upload_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > IbstraceInfo::SCRATCH_PAGE_SIZE {
+            return Err(Error::BufferTooLarge { 
+                len: data.len(), 
+                max: IbstraceInfo::SCRATCH_PAGE_SIZE,
+            });
+        }
+        let msg = ioctl::UserBuf::new(data.as_ptr(), data.len());
+        unsafe { 
+            ibstrace_write_data(self.fd.as_raw_fd(), &msg).map_err(|errno| {
+                Error::Ioctl { cmd: ioctl::CMD_WRITE_DATA, errno }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Read back the contents of the scratch page passed to user code.
+    pub fn read_scratch(&self) -> Result<Box<[u8]>, Error> {
+        let mut buf = vec![0u8; IbstraceInfo::SCRATCH_PAGE_SIZE];
+        let msg = ioctl::UserBuf::new(buf.as_mut_ptr(), buf.len());
+        unsafe { 
+            ibstrace_read_data(self.fd.as_raw_fd(), &msg).map_err(|errno| {
+                Error::Ioctl { cmd: ioctl::CMD_READ_DATA, errno }
+            })?;
+        }
+        Ok(buf.into_boxed_slice())
+    }
+
+    /// Sample the previously-uploaded user code, returning the collected 
+    /// [Sample] data along with the final register state of user code.
+    pub fn measure(&mut self) -> Result<MeasureOutcome, Error> {
+        self.measure_with(&SamplingConfig::default())
+    }
+
+    /// Sample the previously-uploaded user code with a particular 
+    /// [SamplingConfig]. 
+    pub fn measure_with(&mut self, cfg: &SamplingConfig) 
+        -> Result<MeasureOutcome, Error>
+    {
+        let args = cfg.to_args();
+        unsafe { 
+            ibstrace_measure(self.fd.as_raw_fd(), &args).map_err(|errno| {
+                self.measure_error(ioctl::CMD_MEASURE, errno)
+            })?;
+        }
+        let regs = self.registers()?;
+        let stats = self.stats()?;
+        Ok(MeasureOutcome::new(self.read_samples()?, regs).with_stats(stats))
+    }
+
+    /// Upload and sample a list of programs with a single request to the 
+    /// kernel module. 
+    ///
+    /// Programs are placed back-to-back in the code buffer (see 
+    /// [batch::load_offsets]), and run one after the other on the target 
+    /// core. Programs are uploaded in place of any previously-uploaded code.
+    ///
+    /// When a program faults, the batch stops and the fault is reported in
+    /// [BatchOutcome::fault] (along with the results for the programs before
+    /// it).
+    pub fn measure_batch(&mut self, progs: &[&[u8]], cfg: &SamplingConfig) 
+        -> Result<BatchOutcome, Error>
+    {
+        if progs.is_empty() {
+            return Ok(BatchOutcome::default());
+        }
+        batch::load_offsets(progs, ioctl::CODE_BUFFER_MAX_SIZE)?;
+
+        let bufs: Vec<ioctl::UserBuf> = progs.iter()
+            .map(|p| ioctl::UserBuf::new(p.as_ptr(), p.len()))
+            .collect();
+        let mut results = vec![ioctl::BatchResult::default(); progs.len()];
+        let args = ioctl::BatchArgs::new(&bufs, &mut results, cfg.to_args());
+        unsafe { 
+            ibstrace_batch(self.fd.as_raw_fd(), &args).map_err(|errno| {
+                self.measure_error(ioctl::CMD_BATCH, errno)
+            })?;
+        }
+
+        // The remaining programs aren't run after a fault
+        let fault = results.iter().position(|r| r.fault.valid != 0).map(|index| {
+            let f = results[index].fault;
+            results.truncate(index);
+            BatchFault { index, vector: f.vector, rip: f.rip, error_code: f.error_code }
+        });
+        let mut outcome = BatchOutcome::new(self.read_samples()?, 
+            results.into_boxed_slice()
+        );
+        if let Some(fault) = fault {
+            outcome = outcome.with_fault(fault);
+        }
+        outcome.validate()?;
+        Ok(outcome)
+    }
+
+    /// Sample a particular micro-op in the previously-uploaded user code, 
+    /// returning the collected [Sample] data along with the final register 
+    /// state of user code.
+    pub fn measure_precise(&mut self, arg: &ioctl::PreciseArgs) 
+        -> Result<MeasureOutcome, Error>
+    {
+        unsafe { 
+            ibstrace_precise(self.fd.as_raw_fd(), arg).map_err(|errno| {
+                self.measure_error(ioctl::CMD_PRECISE, errno)
+            })?;
+        }
+        let regs = self.registers()?;
+        let stats = self.stats()?;
+        Ok(MeasureOutcome::new(self.read_samples()?, regs).with_stats(stats))
+    }
+
+    /// Sample a range of micro-ops in the previously-uploaded user code 
+    /// with a single request to the kernel module. 
+    ///
+    /// The number of offsets must not exceed the capacity of the sample 
+    /// buffer (see [IbstraceDevice::capacity]).
+    pub fn measure_sweep(&mut self, args: &ioctl::SweepArgs) 
+        -> Result<SweepOutcome, Error>
+    {
+        unsafe { 
+            ibstrace_sweep(self.fd.as_raw_fd(), args).map_err(|errno| {
+                self.measure_error(ioctl::CMD_SWEEP, errno)
+            })?;
+        }
+        let regs = self.registers()?;
+        let stats = self.stats()?;
+
+        // The module stores one record per offset, which is left zeroed 
+        // when no sample was collected
+        let samples = self.read_samples()?.into_vec().into_iter()
+            .map(|s| if s.ctl.0 != 0 { Some(s) } else { None })
+            .collect();
+        Ok(SweepOutcome::new(args.first(), samples, regs).with_stats(stats))
+    }
+
+    /// Sample instruction fetches in the previously-uploaded user code with 
+    /// a particular [FetchConfig], returning the collected [FetchSample] data 
+    /// along with the final register state of user code.
+    pub fn measure_fetch(&mut self, cfg: &FetchConfig) 
+        -> Result<FetchOutcome, Error>
+    {
+        let args = cfg.to_args();
+        unsafe { 
+            ibstrace_fetch(self.fd.as_raw_fd(), &args).map_err(|errno| {
+                self.measure_error(ioctl::CMD_FETCH, errno)
+            })?;
+        }
+        let regs = self.registers()?;
+        let stats = self.stats()?;
+
+        // Fetch samples occupy the same records in the sample buffer
+        let samples = self.read_samples()?.iter()
+            .map(|s| FetchSample::from_le_bytes(&s.to_le_bytes()))
+            .collect();
+        Ok(FetchOutcome::new(samples, regs).with_stats(stats))
+    }
+
+    /// Change the core used to run user code.
+    pub fn set_target_cpu(&mut self, cpu: usize) -> Result<(), Error> {
+        unsafe {
+            ibstrace_set_cpu(self.fd.as_raw_fd(), cpu as nix::libc::c_int)
+                .map_err(|errno| Error::Ioctl { cmd: ioctl::CMD_SET_CPU, errno })?;
+        }
+        Ok(())
+    }
+
+    /// Return the register state of the most-recently measured user code.
+    pub fn registers(&self) -> Result<Registers, Error> {
+        let mut regs = Registers::default();
+        unsafe {
+            ibstrace_regs(self.fd.as_raw_fd(), &mut regs).map_err(|errno| {
+                Error::Ioctl { cmd: ioctl::CMD_REGS, errno }
+            })?;
+        }
+        Ok(regs)
+    }
+
+    /// Return the fault taken by measured code during the most-recent 
+    /// measurement (if any).
+    pub fn fault(&self) -> Result<Option<Error>, Error> {
+        let mut info = ioctl::FaultInfo::default();
+        unsafe {
+            ibstrace_fault(self.fd.as_raw_fd(), &mut info).map_err(|errno| {
+                Error::Ioctl { cmd: ioctl::CMD_FAULT, errno }
+            })?;
+        }
+        Ok(info.to_error())
+    }
+
+    /// Convert an error from a measurement command. 
+    ///
+    /// The module returns `EFAULT` when measured code faults, in which case
+    /// the fault is reported as [Error::MeasuredCodeFault].
+    fn measure_error(&self, cmd: usize, errno: nix::errno::Errno) -> Error {
+        if errno == nix::errno::Errno::EFAULT {
+            if let Ok(Some(fault)) = self.fault() {
+                return fault;
+            }
+        }
+        Error::Ioctl { cmd, errno }
+    }
+
+    /// Return statistics about samples collected (and lost) during the 
+    /// most-recent measurement.
+    ///
+    /// This must be read before consuming samples with 
+    /// [IbstraceDevice::read_samples].
+    pub fn stats(&self) -> Result<SampleStats, Error> {
+        let mut stats = SampleStats::default();
+        unsafe {
+            ibstrace_stats(self.fd.as_raw_fd(), &mut stats).map_err(|errno| {
+                Error::Ioctl { cmd: ioctl::CMD_STATS, errno }
+            })?;
+        }
+        Ok(stats)
+    }
+
+    /// Return the number of samples currently held by the kernel module.
+    pub fn sample_count(&self) -> Result<usize, Error> {
+        let res = unsafe { ibstrace_samples(self.fd.as_raw_fd()) };
+        res.map(|n| n as usize).map_err(|errno| {
+            Error::Ioctl { cmd: ioctl::CMD_SAMPLES, errno }
+        })
+    }
+
+    /// Return the maximum number of samples held by the kernel module.
+    pub fn capacity(&self) -> Result<usize, Error> {
+        let res = unsafe { ibstrace_capacity(self.fd.as_raw_fd()) };
+        res.map(|n| n as usize).map_err(|errno| {
+            Error::Ioctl { cmd: ioctl::CMD_CAPACITY, errno }
+        })
+    }
+
+    /// Read (and consume) all samples held by the kernel module.
+    pub fn read_samples(&mut self) -> Result<Box<[Sample]>, Error> {
+        // NOTE: Can I just use stat() to resolve the length of data available 
+        // in the character device somehow? Instead of issuing more ioctls()?
+        let count = self.sample_count()?;
+        read_records(self.fd.as_fd(), count, self.format)
+    }
+
+    /// Return a [SampleStream] which lazily reads (and consumes) all samples 
+    /// held by the kernel module.
+    pub fn stream_samples(&mut self) -> SampleStream<&mut Self> {
+        let format = self.format;
+        SampleStream::new(self).with_format(format)
+    }
+
+    /// Return information exported by the kernel module in debugfs.
+    pub fn info(&self) -> Result<IbstraceInfo, Error> {
+        IbstraceInfo::read()
+    }
+
+    /// Return the base address of the code buffer in the kernel module.
+    pub fn base_address(&self) -> Result<usize, Error> {
+        IbstraceInfo::read_code_buf(IbstraceInfo::DEFAULT_DEBUGFS_ROOT)
+    }
+}
+
+/// Read some number of records in a particular [SampleFormat] from a file 
+/// descriptor.
+fn read_records(fd: BorrowedFd, count: usize, format: SampleFormat)
+    -> Result<Box<[Sample]>, Error>
+{
+    use nix::errno::Errno;
+
+    let record_size = format.record_size();
+    let bytes = count * record_size;
+    let mut samples: Vec<Sample> = Vec::with_capacity(count);
+
+    // Read the raw records directly into the sample buffer (which is 
+    // at least as large as the records).
+    // Reading the records may take multiple reads
+    const _: () = assert!(
+        std::mem::size_of::<Sample>() >= SampleFormat::MAX_RECORD_SIZE
+    );
+    let buf = samples.as_mut_ptr() as *mut u8;
+    let mut done = 0;
+    while done < bytes {
+        let res = unsafe { 
+            nix::libc::read(fd.as_raw_fd(), 
+                buf.add(done) as *mut nix::libc::c_void, 
+                bytes - done
+            )
+        };
+        match Errno::result(res).map_err(Error::Read)? as usize {
+            0 => break,
+            n => done += n,
+        }
+    }
+    if !done.is_multiple_of(record_size) {
+        return Err(Error::TruncatedSample { len: done, record_size });
+    }
+    if done != bytes {
+        return Err(Error::ShortRead { expected: bytes, actual: done });
+    }
+
+    // Decode the records in place, starting from the last record. 
+    // Each decoded sample only overwrites the record it came from and 
+    // records which have already been decoded.
+    let mut record = [0u8; SampleFormat::MAX_RECORD_SIZE];
+    for idx in (0..count).rev() {
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.add(idx * record_size), 
+                record.as_mut_ptr(), record_size
+            );
+            let s = Sample::from_record(format, &record[..record_size]);
+            samples.as_mut_ptr().add(idx).write(s);
+        }
+    }
+
+    // SAFETY: We've initialized exactly this many samples
+    unsafe { samples.set_len(count); }
+    Ok(samples.into_boxed_slice())
+}
+
+/// Reading from the device consumes raw sample data from the kernel module.
+impl std::io::Read for IbstraceDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        nix::unistd::read(self.fd.as_raw_fd(), buf).map_err(|e| e.into())
+    }
+}
+
+impl AsFd for IbstraceDevice {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for IbstraceDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use crate::device::*;
+
+    #[test]
+    fn read_records_in_place() {
+        let samples: Vec<Sample> = (0..5).map(|i| SampleBuilder::new()
+            .with_rip(0x1000 + i)
+            .with_phyad(0x2000 * i)
+            .with_meta(SampleMeta { seq: i, tsc: 0x100 * i, intr_rip: i })
+            .build()
+        ).collect();
+        for format in [SampleFormat::V1, SampleFormat::V2] {
+            let (mut tx, rx) = UnixStream::pair().unwrap();
+            for s in samples.iter() {
+                tx.write_all(&s.to_record(format)).unwrap();
+            }
+            let res = read_records(rx.as_fd(), samples.len(), format).unwrap();
+            assert_eq!(&res[..], &samples[..]);
+            assert!(res.iter().zip(samples.iter()).all(|(a, b)| {
+                a.meta == if format == SampleFormat::V2 { b.meta } else { None }
+            }));
+
+            // Fewer records than expected
+            tx.write_all(&samples[0].to_record(format)).unwrap();
+            drop(tx);
+            assert_eq!(read_records(rx.as_fd(), 2, format).err(),
+                Some(Error::ShortRead { 
+                    expected: 2 * format.record_size(), 
+                    actual: format.record_size() 
+                })
+            );
+        }
+    }
+}
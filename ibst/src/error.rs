@@ -0,0 +1,254 @@
+//! Errors returned while interacting with the `ibstrace` kernel module.
+
+use std::sync::Arc;
+
+use nix::errno::Errno;
+
+/// An error produced by the `ibst` library.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The character device doesn't exist (is the kernel module loaded?)
+    DeviceMissing,
+
+    /// Permission denied while opening the character device (are you root?)
+    PermissionDenied,
+
+    /// Opening the character device failed for some other reason.
+    Open(Errno),
+
+    /// An ioctl() command issued to the character device failed.
+    Ioctl {
+        /// The ioctl() command number (see [crate::ioctl])
+        cmd: usize,
+        /// The error returned by the kernel module
+        errno: Errno
+    },
+
+    /// Reading samples from the character device failed.
+    Read(Errno),
+
+    /// Reading samples from a [crate::SampleStream] failed.
+    StreamRead(IoError),
+
+    /// Reading samples returned fewer bytes than the module reported.
+    ShortRead {
+        /// The number of bytes we expected to read
+        expected: usize,
+        /// The number of bytes we actually read
+        actual: usize
+    },
+
+    /// Raw sample data doesn't contain a whole number of records.
+    TruncatedSample {
+        /// Length of the raw sample data (in bytes)
+        len: usize,
+        /// Size of a single sample record (in bytes)
+        record_size: usize
+    },
+
+    /// A file exported by the module in debugfs couldn't be read.
+    DebugfsUnreadable {
+        /// Path to the debugfs file
+        path: String,
+        /// The error returned when reading the file
+        source: IoError
+    },
+
+    /// A file exported by the module in debugfs had unexpected contents.
+    DebugfsMalformed {
+        /// Path to the debugfs file
+        path: String,
+        /// The contents of the file
+        contents: String
+    },
+
+    /// User code doesn't fit in the code buffer of the kernel module.
+    BufferTooLarge {
+        /// Length of the user code (in bytes)
+        len: usize,
+        /// Maximum size of the code buffer (in bytes)
+        max: usize
+    },
+
+    /// A requested range of counter offsets is empty, or is not below
+    /// [crate::ioctl::MAX_OFFSET] (which aliases offset 0).
+    InvalidOffsets {
+        /// The first requested offset
+        first: usize,
+        /// The last requested offset
+        last: usize
+    },
+
+    /// A field in some sampling configuration is out of range, or is not 
+    /// aligned to the granularity of the field.
+    InvalidConfig {
+        /// Name of the field
+        field: &'static str,
+        /// The requested value
+        value: usize
+    },
+
+    /// The kernel module stores sample records in an unsupported format
+    /// (see [crate::SampleFormat]).
+    UnsupportedSampleVersion {
+        /// The record version reported by the kernel module
+        version: usize
+    },
+
+    /// Measured code caused an exception, and the measurement was stopped.
+    MeasuredCodeFault {
+        /// Exception vector (ie. 6 for #UD, 13 for #GP, 14 for #PF)
+        vector: usize,
+        /// Address of the faulting instruction
+        rip: usize,
+        /// Error code pushed by the exception (if any)
+        error_code: usize
+    },
+
+    /// A session archive couldn't be read or written.
+    Archive(String),
+
+    /// A replayed request doesn't match the next request in the archive.
+    ReplayMismatch {
+        /// Index of the mismatched exchange in the archive
+        index: usize,
+        /// Description of the mismatch
+        reason: &'static str
+    },
+
+    /// There are no more exchanges left in the archive.
+    ReplayExhausted,
+
+    /// A saved trace couldn't be read or parsed (see [crate::trace::Trace]).
+    TraceFormat(String),
+
+    /// A saved trace uses an unsupported schema version.
+    UnsupportedTraceVersion {
+        /// The schema version found in the saved trace
+        version: usize
+    },
+}
+
+impl Error {
+    /// Return the underlying [Errno] for this error (if one exists).
+    pub fn errno(&self) -> Option<Errno> {
+        match self {
+            Self::DeviceMissing => Some(Errno::ENOENT),
+            Self::PermissionDenied => Some(Errno::EACCES),
+            Self::Open(errno) |
+            Self::Read(errno) |
+            Self::Ioctl { errno, .. } => Some(*errno),
+            Self::StreamRead(source) |
+            Self::DebugfsUnreadable { source, .. } =>
+                source.raw_os_error().map(Errno::from_raw),
+            Self::MeasuredCodeFault { .. } => Some(Errno::EFAULT),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::DeviceMissing =>
+                write!(f, "ibstrace device not found (kernel module not loaded?)"),
+            Self::PermissionDenied =>
+                write!(f, "permission denied opening ibstrace device (are you root?)"),
+            Self::Open(errno) =>
+                write!(f, "couldn't open ibstrace device: {}", errno),
+            Self::Ioctl { cmd, errno } =>
+                write!(f, "ioctl() command {:#x} failed: {}", cmd, errno),
+            Self::Read(errno) =>
+                write!(f, "couldn't read samples: {}", errno),
+            Self::StreamRead(source) =>
+                write!(f, "couldn't read samples: {}", source),
+            Self::ShortRead { expected, actual } =>
+                write!(f, "expected to read {} bytes, but only read {}",
+                    expected, actual),
+            Self::TruncatedSample { len, record_size } =>
+                write!(f, "{} bytes of sample data is not a multiple of {}",
+                    len, record_size),
+            Self::DebugfsUnreadable { path, source } =>
+                write!(f, "couldn't read '{}': {}", path, source),
+            Self::DebugfsMalformed { path, contents } =>
+                write!(f, "unexpected contents in '{}': {:?}", path, contents),
+            Self::BufferTooLarge { len, max } =>
+                write!(f, "user code is {} bytes (maximum is {} bytes)", len, max),
+            Self::InvalidOffsets { first, last } =>
+                write!(f, "invalid range of offsets {}..={} (must be below {})",
+                    first, last, crate::ioctl::MAX_OFFSET),
+            Self::InvalidConfig { field, value } =>
+                write!(f, "invalid {} {:#x}", field, value),
+            Self::UnsupportedSampleVersion { version } =>
+                write!(f, "unsupported sample record version {}", version),
+            Self::MeasuredCodeFault { vector, rip, error_code } =>
+                write!(f, "measured code faulted ({}) at {:#x} (error code {:#x})",
+                    exception_name(*vector), rip, error_code),
+            Self::Archive(reason) =>
+                write!(f, "session archive error: {}", reason),
+            Self::ReplayMismatch { index, reason } =>
+                write!(f, "replay mismatch at exchange {}: {}", index, reason),
+            Self::ReplayExhausted =>
+                write!(f, "no more exchanges in session archive"),
+            Self::TraceFormat(reason) =>
+                write!(f, "couldn't parse trace: {}", reason),
+            Self::UnsupportedTraceVersion { version } =>
+                write!(f, "unsupported trace schema version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A [std::io::Error] which can be cloned and compared (so that it can be
+/// carried by an [Error]).
+///
+/// Two of these are equal when they have the same [std::io::ErrorKind] and
+/// OS error code.
+#[derive(Clone, Debug)]
+pub struct IoError(Arc<std::io::Error>);
+impl IoError {
+    /// Return the underlying [std::io::Error].
+    pub fn inner(&self) -> &std::io::Error {
+        &self.0
+    }
+    pub fn kind(&self) -> std::io::ErrorKind {
+        self.0.kind()
+    }
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.0.raw_os_error()
+    }
+}
+impl From<std::io::Error> for IoError {
+    fn from(e: std::io::Error) -> Self {
+        Self(Arc::new(e))
+    }
+}
+impl From<Errno> for IoError {
+    fn from(errno: Errno) -> Self {
+        Self::from(std::io::Error::from(errno))
+    }
+}
+impl PartialEq for IoError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind() == other.kind() && self.raw_os_error() == other.raw_os_error()
+    }
+}
+impl Eq for IoError {}
+impl std::fmt::Display for IoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Return the mnemonic for an exception vector.
+fn exception_name(vector: usize) -> String {
+    match vector {
+        0 => "#DE".to_string(),
+        6 => "#UD".to_string(),
+        13 => "#GP".to_string(),
+        14 => "#PF".to_string(),
+        17 => "#AC".to_string(),
+        _ => format!("vector {}", vector),
+    }
+}
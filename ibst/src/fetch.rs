@@ -0,0 +1,271 @@
+//! IBS fetch sampling.
+//!
+//! Fetch sampling tags instruction fetches (rather than micro-ops), which is
+//! useful for studying the behavior of the front-end (ie. instruction cache
+//! and ITLB misses, and fetch latency) while running user code.
+
+use std::convert::TryInto;
+
+use crate::*;
+use crate::ibs::*;
+
+/// A fetch sample taken by the `ibstrace` kernel module.
+///
+/// WARNING: This struct must mirror the original definition in C code, see
+/// `struct fetch_sample` in `include/ibstrace.h`. Fetch samples are padded to
+/// the size of a [Sample], since both share the sample buffer in the module.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize)]
+#[repr(C)]
+pub struct FetchSample {
+    /// IBS fetch control register (IBS_FETCH_CTL).
+    pub ctl: IbsFetchCtl,
+    /// Linear address of the tagged fetch (IBS_FETCH_LINADDR).
+    pub linad: IbsFetchLinAd,
+    /// Physical address of the tagged fetch (IBS_FETCH_PHYSADDR).
+    pub phyad: IbsFetchPhysAd,
+    /// IBS fetch extended control register (IC_IBS_EXTD_CTL).
+    pub ext_ctl: IbsFetchExtCtl,
+    #[serde(skip)]
+    _reserved: [usize; 4],
+}
+
+const _: () = assert!(std::mem::size_of::<FetchSample>() == Sample::SIZE);
+
+impl FetchSample {
+    /// The size of a fetch sample record (in bytes).
+    pub const SIZE: usize = std::mem::size_of::<FetchSample>();
+
+    pub fn new(ctl: IbsFetchCtl, linad: IbsFetchLinAd, phyad: IbsFetchPhysAd,
+        ext_ctl: IbsFetchExtCtl) -> Self
+    {
+        Self { ctl, linad, phyad, ext_ctl, _reserved: [0; 4] }
+    }
+
+    /// Return the raw little-endian representation of this sample (matching
+    /// `struct fetch_sample` in the kernel module).
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        let words = [self.ctl.0, self.linad.0, self.phyad.0, self.ext_ctl.0];
+        let mut res = [0u8; Self::SIZE];
+        for (chunk, word) in res.chunks_exact_mut(8).zip(words.iter()) {
+            chunk.copy_from_slice(&(*word as u64).to_le_bytes());
+        }
+        res
+    }
+
+    /// Create a sample from its raw little-endian representation.
+    pub fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut words = bytes.chunks_exact(8).map(|chunk| {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(chunk);
+            u64::from_le_bytes(word) as usize
+        });
+        let mut next = || words.next().unwrap();
+        Self::new(
+            IbsFetchCtl(next()),
+            IbsFetchLinAd(next()),
+            IbsFetchPhysAd(next()),
+            IbsFetchExtCtl(next()),
+        )
+    }
+
+    /// Decode raw sample data into a list of fetch samples.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Box<[Self]>, Error> {
+        if !bytes.len().is_multiple_of(Self::SIZE) {
+            return Err(Error::TruncatedSample {
+                len: bytes.len(), record_size: Self::SIZE
+            });
+        }
+        Ok(bytes.chunks_exact(Self::SIZE)
+            .map(|chunk| Self::from_le_bytes(chunk.try_into().unwrap()))
+            .collect())
+    }
+}
+
+impl std::fmt::Debug for FetchSample {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("FetchSample")
+            .field("lin", &self.linad.addr())
+            .field("phy", &self.phyad.addr())
+            .field("lat", &self.ctl.fetch_lat())
+            .field("ic_miss", &self.ctl.ic_miss())
+            .field("l1tlb_miss", &self.ctl.l1tlb_miss())
+            .field("l2tlb_miss", &self.ctl.l2tlb_miss())
+            .field("pg_sz", &self.ctl.l1tlb_pg_sz())
+            .field("itlb_refill_lat", &self.ext_ctl.itlb_refill_lat())
+            .finish()
+    }
+}
+
+/// Parameters used to sample user code with
+/// [MeasurementBackend::measure_fetch].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FetchConfig {
+    /// Number of fetches between samples
+    max_count: usize,
+    /// Randomize the fetch counter (IBS_FETCH_CTL.IbsRandEn)
+    randomize: bool,
+    /// Number of times to run user code before sampling
+    warmup: usize,
+}
+impl FetchConfig {
+    /// Default number of fetches between samples.
+    pub const DEFAULT_MAX_COUNT: usize = 0x0000_1000;
+
+    /// Minimum number of fetches between samples.
+    pub const MIN_MAX_COUNT: usize = 0x0000_0010;
+
+    /// Maximum number of fetches between samples.
+    pub const MAX_MAX_COUNT: usize = 0x000f_fff0;
+
+    const RAND_EN_BIT: usize = 0x0200_0000_0000_0000;
+
+    pub fn new() -> Self {
+        Self {
+            max_count: Self::DEFAULT_MAX_COUNT,
+            randomize: false,
+            warmup: 0,
+        }
+    }
+
+    /// Set the number of fetches between samples.
+    ///
+    /// The bottom 4 bits of the counter are not programmable, so this must
+    /// be a multiple of 16 between [FetchConfig::MIN_MAX_COUNT] and
+    /// [FetchConfig::MAX_MAX_COUNT].
+    pub fn with_max_count(mut self, max_count: usize) -> Self {
+        assert!(max_count.is_multiple_of(16),
+            "Max count {:#x} must be a multiple of 16", max_count
+        );
+        assert!((Self::MIN_MAX_COUNT..=Self::MAX_MAX_COUNT).contains(&max_count),
+            "Max count {:#x} must be between {:#x} and {:#x}",
+            max_count, Self::MIN_MAX_COUNT, Self::MAX_MAX_COUNT
+        );
+        self.max_count = max_count;
+        self
+    }
+
+    /// Let the hardware randomize the bottom 4 bits of the fetch counter.
+    pub fn with_randomize(mut self, randomize: bool) -> Self {
+        self.randomize = randomize;
+        self
+    }
+
+    /// Set the number of times to run user code (without sampling) before
+    /// user code is sampled.
+    pub fn with_warmup(mut self, warmup: usize) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Return the number of fetches between samples.
+    pub fn max_count(&self) -> usize { self.max_count }
+
+    /// Return `true` if the fetch counter is randomized.
+    pub fn randomize(&self) -> bool { self.randomize }
+
+    /// Return the number of times user code runs before sampling.
+    pub fn warmup(&self) -> usize { self.warmup }
+
+    /// Encode this configuration as a value for IBS_FETCH_CTL.
+    ///
+    /// This only includes IbsFetchMaxCnt and IbsRandEn (the kernel module is
+    /// responsible for setting IbsFetchEn).
+    pub fn fetch_ctl(&self) -> IbsFetchCtl {
+        let mut res = self.max_count >> 4;
+        if self.randomize {
+            res |= Self::RAND_EN_BIT;
+        }
+        IbsFetchCtl(res)
+    }
+
+    /// Return the argument passed to [ioctl::CMD_FETCH].
+    pub fn to_args(&self) -> ioctl::FetchArgs {
+        ioctl::FetchArgs::new(self.fetch_ctl().0, self.warmup)
+    }
+
+    /// Decode the argument passed to [ioctl::CMD_FETCH].
+    pub fn from_args(args: &ioctl::FetchArgs) -> Self {
+        let ctl = IbsFetchCtl(args.fetch_ctl());
+        Self {
+            max_count: ctl.fetch_max_cnt() << 4,
+            randomize: ctl.rand_en(),
+            warmup: args.warmup(),
+        }
+    }
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The result of measuring user code with fetch sampling.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FetchOutcome {
+    /// Fetch samples collected while running user code
+    pub samples: Box<[FetchSample]>,
+    /// The value returned by user code (in RAX)
+    pub return_value: usize,
+    /// Register state after user code returned
+    pub regs: Registers,
+    /// Statistics about the collected samples
+    pub stats: SampleStats,
+}
+impl FetchOutcome {
+    /// Create a new outcome, taking the return value from RAX.
+    ///
+    /// This assumes that no samples were lost (see 
+    /// [FetchOutcome::with_stats]).
+    pub fn new(samples: Box<[FetchSample]>, regs: Registers) -> Self {
+        let stats = SampleStats::new(samples.len());
+        Self { samples, return_value: regs.rax, regs, stats }
+    }
+
+    /// Use the statistics reported for this measurement.
+    pub fn with_stats(mut self, stats: SampleStats) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fetch::*;
+
+    #[test]
+    fn decode_fetch_sample() {
+        let s = FetchSample::new(
+            IbsFetchCtl(0x023f_0123_0000_0100),
+            IbsFetchLinAd(0xffff_c900_0000_0040),
+            IbsFetchPhysAd(0xffff_0001_0000_0040),
+            IbsFetchExtCtl(0x0000_0000_0000_0042),
+        );
+        assert!(s.ctl.fetch_val() && s.ctl.fetch_comp() && s.ctl.fetch_en());
+        assert!(s.ctl.ic_miss() && s.ctl.rand_en() && s.ctl.phy_addr_valid());
+        assert!(!s.ctl.l1tlb_miss() && !s.ctl.fetch_l2_miss());
+        assert_eq!(s.ctl.l1tlb_pg_sz(), IbsPageSize::Page2M);
+        assert_eq!(s.ctl.fetch_lat(), 0x0123);
+        assert_eq!(s.ctl.fetch_max_cnt(), 0x100);
+        assert_eq!(s.phyad.addr(), 0x0001_0000_0040);
+        assert_eq!(s.ext_ctl.itlb_refill_lat(), 0x42);
+
+        let bytes: Vec<u8> = [s; 2].iter()
+            .flat_map(|s| s.to_le_bytes().to_vec())
+            .collect();
+        assert_eq!(FetchSample::from_bytes(&bytes).unwrap()[1], s);
+        assert!(FetchSample::from_bytes(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn encode_fetch_ctl() {
+        let cfg = FetchConfig::new()
+            .with_max_count(0x000f_fff0)
+            .with_randomize(true)
+            .with_warmup(2);
+        assert_eq!(cfg.fetch_ctl().0, 0x0200_0000_0000_ffff);
+        assert_eq!(FetchConfig::from_args(&cfg.to_args()), cfg);
+        assert_eq!(FetchConfig::default().fetch_ctl().0, 0x100);
+    }
+}
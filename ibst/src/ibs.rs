@@ -2,6 +2,10 @@
 //!
 //! See "PPR for AMD Family 17h Model 71h B0".
 //!
+//! The accessors here follow the Family 17h layout. Some fields are defined
+//! differently on later families - use an [IbsLayout](crate::IbsLayout) to 
+//! decode samples from a particular part.
+//!
 //! NOTE: You're deriving `Hash` for all of these, but there are some fields
 //! that you might not want to hash when distinguishing between unique 
 //! samples.
@@ -9,24 +13,228 @@
 use std::hash::{Hash, Hasher};
 use serde; 
 
+use crate::Error;
+
+/// Set or clear some bit in a register value.
+fn set_bit(reg: &mut usize, bit: usize, x: bool) {
+    if x { *reg |= bit } else { *reg &= !bit }
+}
+
+/// Write a value into the bits selected by some mask in a register value.
+///
+/// Panics if the value doesn't fit in the field.
+fn set_field(reg: &mut usize, mask: usize, x: usize) {
+    let shift = mask.trailing_zeros();
+    assert!(x <= (mask >> shift), "Value {:#x} doesn't fit in mask {:#x}", x, mask);
+    *reg = (*reg & !mask) | (x << shift);
+}
+
 /// MSRC001_1033 [IBS Execution Control] (Core::X86::Msr::IBS_OP_CTL)
-#[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd)]
-#[derive(serde::Serialize)]
+///
+/// The `with_*` methods can be used to build a value for the register, ie.
+///
+/// ```
+/// use ibst::ibs::IbsOpCtl;
+/// let ctl = IbsOpCtl::new().with_max_cnt(0x1000).with_cnt_ctl(true);
+/// assert_eq!(ctl.0, 0x0008_0100);
+/// ```
+#[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[repr(transparent)]
 pub struct IbsOpCtl(pub usize);
 impl IbsOpCtl {
-    const RES_63_59_MASK:   usize = 0xf100_0000_0000_0000;
+    // NOTE: Bits [63:59] are reserved before Zen 4
+    const LD_LAT_EN_BIT:    usize = 0x8000_0000_0000_0000;
+    const LD_LAT_THRSH_MASK: 
+                            usize = 0x7800_0000_0000_0000;
     const CUR_CNT_MASK:     usize = 0x07ff_ffff_0000_0000;
-    const RES_31_27_MASK:   usize = 0x0000_0000_f100_0000;
+    const RES_31_27_MASK:   usize = 0x0000_0000_f800_0000;
+    const MAX_CNT_26_20_MASK: 
+                            usize = 0x0000_0000_07f0_0000;
+    const CNT_CTL_BIT:      usize = 0x0000_0000_0008_0000;
+    const VAL_BIT:          usize = 0x0000_0000_0004_0000;
+    const EN_BIT:           usize = 0x0000_0000_0002_0000;
+    // NOTE: Bit 16 is reserved before Zen 4
+    const L3_MISS_ONLY_BIT: usize = 0x0000_0000_0001_0000;
+    const MAX_CNT_19_4_MASK: 
+                            usize = 0x0000_0000_0000_ffff;
+
+    /// The largest max count (the bottom 4 bits are not programmable).
+    pub const MAX_MAX_CNT:  usize = 0x07ff_fff0;
 
+    /// The largest value of the current count.
+    pub const MAX_CUR_CNT:  usize = 0x07ff_ffff;
+
+    /// The largest raw value of the load latency threshold.
+    pub const MAX_LD_LAT_THRSH: usize = 0xf;
+
+    /// An empty value for the register (sampling disabled).
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    /// Value of the reserved bits [31:27]
+    pub fn res_31_27(&self) -> usize {
+        (self.0 & Self::RES_31_27_MASK) >> 27
+    }
+    /// Value of the reserved bits [63:59] (used for load latency filtering 
+    /// since Zen 4)
+    pub fn res_63_59(&self) -> usize {
+        (self.0 & (Self::LD_LAT_EN_BIT | Self::LD_LAT_THRSH_MASK)) >> 59
+    }
+
+    /// The number of events between samples (IbsOpMaxCnt, with the 
+    /// extended bits [26:20]). 
+    pub fn max_cnt(&self) -> usize {
+        ((self.0 & Self::MAX_CNT_19_4_MASK) << 4) 
+            | (self.0 & Self::MAX_CNT_26_20_MASK)
+    }
+    /// The current value of the counter (IbsOpCurCnt)
     pub fn cur_cnt(&self) -> usize { 
         (self.0 & Self::CUR_CNT_MASK) >> 32 
     }
+    /// Dispatched micro-ops are counted instead of cycles (IbsOpCntCtl)
+    pub fn cnt_ctl(&self) -> bool {
+        (self.0 & Self::CNT_CTL_BIT) != 0
+    }
+    /// A sample is valid (IbsOpVal)
+    pub fn val(&self) -> bool {
+        (self.0 & Self::VAL_BIT) != 0
+    }
+    /// Op sampling is enabled (IbsOpEn)
+    pub fn en(&self) -> bool {
+        (self.0 & Self::EN_BIT) != 0
+    }
+    /// Only ops which miss in the L3 cache are sampled (since Zen 4)
+    pub fn l3_miss_only(&self) -> bool {
+        (self.0 & Self::L3_MISS_ONLY_BIT) != 0
+    }
+    /// Only loads with a latency above the threshold are sampled 
+    pub fn ld_lat_en(&self) -> bool {
+        (self.0 & Self::LD_LAT_EN_BIT) != 0
+    }
+    /// Raw value of the load latency threshold
+    pub fn ld_lat_thrsh(&self) -> usize {
+        (self.0 & Self::LD_LAT_THRSH_MASK) >> 59
+    }
+    /// The load latency threshold (in cycles)
+    pub fn ld_lat_threshold(&self) -> usize {
+        (self.ld_lat_thrsh() + 1) * 128
+    }
+
+    /// The number of events remaining until the counter reaches the max 
+    /// count (ie. until the next op is tagged).
+    pub fn remaining(&self) -> usize {
+        self.max_cnt().saturating_sub(self.cur_cnt())
+    }
+
+    fn set_bit(&mut self, bit: usize, x: bool) {
+        set_bit(&mut self.0, bit, x)
+    }
+
+    /// Set the number of events between samples. 
+    ///
+    /// This must be a multiple of 16 no larger than [IbsOpCtl::MAX_MAX_CNT].
+    pub fn try_set_max_cnt(&mut self, max_cnt: usize) -> Result<(), Error> {
+        if !max_cnt.is_multiple_of(16) || max_cnt > Self::MAX_MAX_CNT {
+            return Err(Error::InvalidConfig { field: "max count", value: max_cnt });
+        }
+        self.0 &= !(Self::MAX_CNT_19_4_MASK | Self::MAX_CNT_26_20_MASK);
+        self.0 |= ((max_cnt >> 4) & Self::MAX_CNT_19_4_MASK)
+            | (max_cnt & Self::MAX_CNT_26_20_MASK);
+        Ok(())
+    }
+    /// Set the current value of the counter.
+    ///
+    /// This must be no larger than [IbsOpCtl::MAX_CUR_CNT].
+    pub fn try_set_cur_cnt(&mut self, cur_cnt: usize) -> Result<(), Error> {
+        if cur_cnt > Self::MAX_CUR_CNT {
+            return Err(Error::InvalidConfig { field: "current count", value: cur_cnt });
+        }
+        self.0 = (self.0 & !Self::CUR_CNT_MASK) | (cur_cnt << 32);
+        Ok(())
+    }
+    /// Like [IbsOpCtl::try_set_max_cnt], but panics on an invalid value.
+    pub fn set_max_cnt(&mut self, max_cnt: usize) {
+        self.try_set_max_cnt(max_cnt).unwrap_or_else(|e| panic!("{}", e))
+    }
+    /// Like [IbsOpCtl::try_set_cur_cnt], but panics on an invalid value.
+    pub fn set_cur_cnt(&mut self, cur_cnt: usize) {
+        self.try_set_cur_cnt(cur_cnt).unwrap_or_else(|e| panic!("{}", e))
+    }
+    pub fn set_cnt_ctl(&mut self, x: bool) { self.set_bit(Self::CNT_CTL_BIT, x) }
+    pub fn set_val(&mut self, x: bool) { self.set_bit(Self::VAL_BIT, x) }
+    pub fn set_en(&mut self, x: bool) { self.set_bit(Self::EN_BIT, x) }
+    pub fn set_l3_miss_only(&mut self, x: bool) { 
+        self.set_bit(Self::L3_MISS_ONLY_BIT, x) 
+    }
+    pub fn set_ld_lat_en(&mut self, x: bool) { 
+        self.set_bit(Self::LD_LAT_EN_BIT, x) 
+    }
+    /// Set the raw value of the load latency threshold.
+    ///
+    /// This must be no larger than [IbsOpCtl::MAX_LD_LAT_THRSH].
+    pub fn try_set_ld_lat_thrsh(&mut self, thrsh: usize) -> Result<(), Error> {
+        if thrsh > Self::MAX_LD_LAT_THRSH {
+            return Err(Error::InvalidConfig { 
+                field: "load latency threshold", value: thrsh 
+            });
+        }
+        self.0 = (self.0 & !Self::LD_LAT_THRSH_MASK) | (thrsh << 59);
+        Ok(())
+    }
+    /// Like [IbsOpCtl::try_set_ld_lat_thrsh], but panics on an invalid value.
+    pub fn set_ld_lat_thrsh(&mut self, thrsh: usize) {
+        self.try_set_ld_lat_thrsh(thrsh).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn with_max_cnt(mut self, x: usize) -> Self { self.set_max_cnt(x); self }
+    pub fn with_cur_cnt(mut self, x: usize) -> Self { self.set_cur_cnt(x); self }
+    pub fn with_cnt_ctl(mut self, x: bool) -> Self { self.set_cnt_ctl(x); self }
+    pub fn with_val(mut self, x: bool) -> Self { self.set_val(x); self }
+    pub fn with_en(mut self, x: bool) -> Self { self.set_en(x); self }
+    pub fn with_l3_miss_only(mut self, x: bool) -> Self { 
+        self.set_l3_miss_only(x); self 
+    }
+    pub fn with_ld_lat_en(mut self, x: bool) -> Self { 
+        self.set_ld_lat_en(x); self 
+    }
+    pub fn with_ld_lat_thrsh(mut self, x: usize) -> Self { 
+        self.set_ld_lat_thrsh(x); self 
+    }
+
+    /// The value written by the precise trampoline in the kernel module to 
+    /// tag the micro-op at some offset (see [PreciseArgs](crate::ioctl::PreciseArgs)).
+    ///
+    /// Like the trampoline, any high bits in the offset are masked off.
+    pub fn precise(offset: usize) -> Self {
+        let offset = offset & (crate::ioctl::MAX_OFFSET - 1);
+        Self::new()
+            .with_max_cnt(crate::ioctl::MAX_OFFSET)
+            .with_cnt_ctl(true)
+            .with_en(true)
+            .with_cur_cnt(crate::ioctl::MAX_OFFSET - offset)
+    }
+}
+
+impl std::fmt::Debug for IbsOpCtl {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("IbsOpCtl")
+            .field("max_cnt", &self.max_cnt())
+            .field("cur_cnt", &self.cur_cnt())
+            .field("cnt_ctl", &self.cnt_ctl())
+            .field("val", &self.val())
+            .field("en", &self.en())
+            .field("l3_miss_only", &self.l3_miss_only())
+            .field("ld_lat_en", &self.ld_lat_en())
+            .field("ld_lat_thrsh", &self.ld_lat_thrsh())
+            .finish()
+    }
 }
 
 /// MSRC001_1035 [IBS Op Data] (Core::X86::Msr::IBS_OP_DATA)
 #[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[repr(transparent)]
 pub struct IbsOpData(pub usize);
 impl IbsOpData {
@@ -87,6 +295,41 @@ impl IbsOpData {
     pub fn comp_to_ret_ctr(&self) -> usize { 
         self.0 & Self::COMP_TO_RET_CTR_MASK 
     }
+
+    /// An empty value for the register.
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn set_op_microcode(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::OP_MICROCODE_BIT, x) 
+    }
+    pub fn set_op_brn_fuse(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::OP_BRN_FUSE_BIT, x) 
+    }
+    pub fn set_rip_invalid(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::RIP_INVALID_BIT, x) 
+    }
+    pub fn set_op_brn_ret(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::OP_BRN_RET_BIT, x) 
+    }
+    pub fn set_op_brn_misp(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::OP_BRN_MISP_BIT, x) 
+    }
+    pub fn set_op_brn_taken(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::OP_BRN_TAKEN_BIT, x) 
+    }
+    pub fn set_op_return(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::OP_RETURN_BIT, x) 
+    }
+    /// Set the number of cycles from tagging to retirement (16 bits).
+    pub fn set_tag_to_ret_ctr(&mut self, x: usize) { 
+        set_field(&mut self.0, Self::TAG_TO_REG_CTR_MASK, x) 
+    }
+    /// Set the number of cycles from completion to retirement (16 bits).
+    pub fn set_comp_to_ret_ctr(&mut self, x: usize) { 
+        set_field(&mut self.0, Self::COMP_TO_RET_CTR_MASK, x) 
+    }
 }
 
 impl std::fmt::Debug for IbsOpData {
@@ -104,40 +347,108 @@ impl std::fmt::Debug for IbsOpData {
 }
 
 
+/// The source of data for a load (IBS_OP_DATA2.DataSrc).
+///
+/// Zen 4 extends this to a 5-bit field with different encodings, see 
+/// [NbDataSrc::from_ext]. 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub enum NbDataSrc {
     Invalid,
-    Reserved1,
+    /// Local cache (before Zen 4)
     Cache,
+    /// DRAM
     Dram,
-    Reserved4,
-    Reserved5,
-    Reserved6,
-    Other,
+    /// Remote cache (before Zen 4)
+    RemoteCache,
+    /// MMIO, config space, PCI or APIC
+    Io,
+    /// Local L3, or another L1/L2 in the same CCX (since Zen 4)
+    LocalCcx,
+    /// Another CCX cache in the same NUMA node (since Zen 4)
+    NearCcx,
+    /// Another CCX cache in a different NUMA node (since Zen 4)
+    FarCcx,
+    /// Long-latency DIMM (since Zen 4)
+    Pmem,
+    /// Extension memory, ie. CXL (since Zen 4)
+    ExtMem,
+    /// Coherent memory attached to a different processor type (since Zen 4)
+    PeerAgentMem,
+    /// Some reserved encoding
+    Reserved(u8),
+}
+impl NbDataSrc {
+    /// Decode the 5-bit DataSrc field used since Zen 4 (DataSrcHi and 
+    /// DataSrcLo).
+    pub fn from_ext(x: usize) -> Self {
+        match x {
+            0  => Self::Invalid,
+            1  => Self::LocalCcx,
+            2  => Self::NearCcx,
+            3  => Self::Dram,
+            5  => Self::FarCcx,
+            6  => Self::Pmem,
+            7  => Self::Io,
+            8  => Self::ExtMem,
+            12 => Self::PeerAgentMem,
+            _  => Self::Reserved((x & 0x1f) as u8),
+        }
+    }
+
+    /// Return the 3-bit encoding used before Zen 4 (see [NbDataSrc::from]), 
+    /// or `None` if this source can't be represented.
+    pub fn encoding(&self) -> Option<usize> {
+        match self {
+            Self::Invalid       => Some(0),
+            Self::Cache         => Some(2),
+            Self::Dram          => Some(3),
+            Self::RemoteCache   => Some(4),
+            Self::Io            => Some(7),
+            Self::Reserved(x) if *x < 0x8 => Some(*x as usize),
+            _ => None,
+        }
+    }
+
+    /// Return the 5-bit encoding used since Zen 4 (see [NbDataSrc::from_ext]),
+    /// or `None` if this source can't be represented.
+    pub fn ext_encoding(&self) -> Option<usize> {
+        match self {
+            Self::Invalid       => Some(0),
+            Self::LocalCcx      => Some(1),
+            Self::NearCcx       => Some(2),
+            Self::Dram          => Some(3),
+            Self::FarCcx        => Some(5),
+            Self::Pmem          => Some(6),
+            Self::Io            => Some(7),
+            Self::ExtMem        => Some(8),
+            Self::PeerAgentMem  => Some(12),
+            Self::Reserved(x) if *x < 0x20 => Some(*x as usize),
+            _ => None,
+        }
+    }
 }
 impl From<usize> for NbDataSrc{  
     fn from(x: usize) -> Self {
         match x {
             0 => Self::Invalid,
-            1 => Self::Reserved1,
             2 => Self::Cache,
             3 => Self::Dram,
-            4 => Self::Reserved4,
-            5 => Self::Reserved5,
-            6 => Self::Reserved6,
-            7 => Self::Other,
-            _ => unimplemented!(),
+            4 => Self::RemoteCache,
+            7 => Self::Io,
+            _ => Self::Reserved((x & 0x7) as u8),
         }
     }
 }
 
 /// MSRC001_1036 [IBS Op Data 2] (Core::X86::Msr::IBS_OP_DATA2)
 #[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[repr(transparent)]
 pub struct IbsOpData2(pub usize);
 impl IbsOpData2 {
+    // NOTE: Bits [7:6] are reserved before Zen 4
+    const DATA_SRC_HI_MASK:     usize = 0x0000_0000_0000_00c0;
     const CACHE_HIT_ST_BIT:     usize = (1 << 5);
     const RMT_NODE_BIT:         usize = (1 << 4);
     const DATA_SRC_MASK:        usize = 0x0000_0000_0000_0007;
@@ -150,11 +461,49 @@ impl IbsOpData2 {
     pub fn data_src(&self) -> NbDataSrc {
         NbDataSrc::from(self.0 & Self::DATA_SRC_MASK)
     }
+    /// Upper bits of the data source (since Zen 4)
+    pub fn data_src_hi(&self) -> usize {
+        (self.0 & Self::DATA_SRC_HI_MASK) >> 6
+    }
+    /// The data source, using the extended encodings (since Zen 4)
+    pub fn data_src_ext(&self) -> NbDataSrc {
+        NbDataSrc::from_ext((self.data_src_hi() << 3) | (self.0 & Self::DATA_SRC_MASK))
+    }
+
+    /// An empty value for the register.
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn set_cache_hit_st(&mut self, x: bool) {
+        set_bit(&mut self.0, Self::CACHE_HIT_ST_BIT, x)
+    }
+    pub fn set_rmt_node(&mut self, x: bool) {
+        set_bit(&mut self.0, Self::RMT_NODE_BIT, x)
+    }
+    /// Set the data source with the encodings used before Zen 4. 
+    ///
+    /// Panics if the source can't be represented (see [NbDataSrc::encoding]).
+    pub fn set_data_src(&mut self, src: NbDataSrc) {
+        let x = src.encoding()
+            .unwrap_or_else(|| panic!("No encoding for data source {:?}", src));
+        set_field(&mut self.0, Self::DATA_SRC_MASK, x)
+    }
+    /// Set the data source with the extended encodings used since Zen 4.
+    ///
+    /// Panics if the source can't be represented (see 
+    /// [NbDataSrc::ext_encoding]).
+    pub fn set_data_src_ext(&mut self, src: NbDataSrc) {
+        let x = src.ext_encoding()
+            .unwrap_or_else(|| panic!("No extended encoding for data source {:?}", src));
+        set_field(&mut self.0, Self::DATA_SRC_HI_MASK, x >> 3);
+        set_field(&mut self.0, Self::DATA_SRC_MASK, x & 0x7);
+    }
 }
 
 /// MSRC001_1037 [IBS Op Data 3] (Core::X86::Msr::IBS_OP_DATA3)
 #[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[repr(transparent)]
 pub struct IbsOpData3(pub usize);
 impl IbsOpData3 {
@@ -192,13 +541,14 @@ impl IbsOpData3 {
     const DC_L2TLB_HIT_2M_BIT:  usize = 0x0000_0000_0000_0040;
 
     // NOTE: Valid when physaddr is valid? 
-    // NOTE: 19h PPR says this is a 2-bit field
+    // NOTE: 19h PPR says this is a 2-bit field (see [IbsPageSize])
     //  - 0b00 - 4K
     //  - 0b01 - 2M
     //  - 0b10 - 1G
     //  - 0b11 - Reserved
     const DC_L1TLB_HIT_1G_BIT:  usize = 0x0000_0000_0000_0020;
     const DC_L1TLB_HIT_2M_BIT:  usize = 0x0000_0000_0000_0010;
+    const DC_L1TLB_PG_SZ_MASK:  usize = 0x0000_0000_0000_0030;
 
     const DC_L2TLB_MISS_BIT:    usize = 0x0000_0000_0000_0008;
     const DC_L1TLB_MISS_BIT:    usize = 0x0000_0000_0000_0004;
@@ -253,6 +603,20 @@ impl IbsOpData3 {
         (self.0 & Self::DC_WC_MEM_ACC_BIT) != 0 
     }
 
+    // NOTE: These are reserved in the PPR (see the note above)
+    pub fn cancelled(&self) -> bool { 
+        (self.0 & Self::CANCELLED) != 0 
+    }
+    pub fn forwarded(&self) -> bool { 
+        (self.0 & Self::FORWARDED) != 0 
+    }
+    pub fn bank_conf_st(&self) -> bool { 
+        (self.0 & Self::BANK_CONF_ST_BIT) != 0 
+    }
+    pub fn bank_conf_ld(&self) -> bool { 
+        (self.0 & Self::BANK_CONF_LD_BIT) != 0 
+    }
+
     pub fn dc_mis_acc(&self) -> bool { 
         (self.0 & Self::DC_MIS_ACC_BIT) != 0 
     }
@@ -268,6 +632,11 @@ impl IbsOpData3 {
     pub fn dc_l1tlb_hit_2m(&self) -> bool { 
         (self.0 & Self::DC_L1TLB_HIT_2M_BIT) != 0 
     }
+    /// Page size of the L1 DTLB entry (the Family 19h interpretation of 
+    /// bits [5:4])
+    pub fn dc_l1tlb_pg_sz(&self) -> IbsPageSize { 
+        IbsPageSize::from((self.0 & Self::DC_L1TLB_PG_SZ_MASK) >> 4)
+    }
     pub fn dc_l2tlb_miss(&self) -> bool { 
         (self.0 & Self::DC_L2TLB_MISS_BIT) != 0 
     }
@@ -280,21 +649,117 @@ impl IbsOpData3 {
     pub fn ld_op(&self) -> bool { 
         (self.0 & Self::LD_OP_BIT) != 0 
     }
+
+    /// An empty value for the register.
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    /// Set the number of cycles spent on a TLB refill (16 bits).
+    pub fn set_tlb_refill_lat(&mut self, x: usize) {
+        set_field(&mut self.0, Self::TLB_REFILL_LAT_MASK, x)
+    }
+    /// Set the number of cycles spent on a data cache miss (16 bits).
+    pub fn set_dc_miss_lat(&mut self, x: usize) {
+        set_field(&mut self.0, Self::DC_MISS_LAT_MASK, x)
+    }
+    /// Set the number of open memory requests on a data cache miss (6 bits).
+    pub fn set_op_dc_miss_open_mem_reqs(&mut self, x: usize) {
+        set_field(&mut self.0, Self::OP_DC_MISS_OPEN_MEM_REQS_MASK, x)
+    }
+    /// Set the width of the access (see [IbsMemWidth::encoding]).
+    pub fn set_op_mem_width(&mut self, width: IbsMemWidth) {
+        set_field(&mut self.0, Self::OP_MEM_WIDTH_MASK, width.encoding())
+    }
+
+    pub fn set_sw_pf(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::SW_PF_BIT, x) 
+    }
+    pub fn set_dc_l2_miss(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_L2_MISS_BIT, x) 
+    }
+    pub fn set_dc_l2tlb_hit_1g(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_L2TLB_HIT_1G_BIT, x) 
+    }
+    pub fn set_dc_phy_addr_valid(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_PHY_ADDR_VAL_BIT, x) 
+    }
+    pub fn set_dc_lin_addr_valid(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_LIN_ADDR_VAL_BIT, x) 
+    }
+    pub fn set_dc_miss_no_mab_alloc(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_MISS_NO_MAB_ALLOC_BIT, x) 
+    }
+    pub fn set_dc_locked_op(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_LOCKED_OP_BIT, x) 
+    }
+    pub fn set_dc_uc_mem_acc(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_UC_MEM_ACC_BIT, x) 
+    }
+    pub fn set_dc_wc_mem_acc(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_WC_MEM_ACC_BIT, x) 
+    }
+    pub fn set_cancelled(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::CANCELLED, x) 
+    }
+    pub fn set_forwarded(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::FORWARDED, x) 
+    }
+    pub fn set_bank_conf_st(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::BANK_CONF_ST_BIT, x) 
+    }
+    pub fn set_bank_conf_ld(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::BANK_CONF_LD_BIT, x) 
+    }
+    pub fn set_dc_mis_acc(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_MIS_ACC_BIT, x) 
+    }
+    pub fn set_dc_miss(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_MISS_BIT, x) 
+    }
+    pub fn set_dc_l2tlb_hit_2m(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_L2TLB_HIT_2M_BIT, x) 
+    }
+    pub fn set_dc_l1tlb_hit_1g(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_L1TLB_HIT_1G_BIT, x) 
+    }
+    pub fn set_dc_l1tlb_hit_2m(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_L1TLB_HIT_2M_BIT, x) 
+    }
+    /// Set the page size of the L1 DTLB entry (the Family 19h interpretation
+    /// of bits [5:4])
+    pub fn set_dc_l1tlb_pg_sz(&mut self, sz: IbsPageSize) { 
+        set_field(&mut self.0, Self::DC_L1TLB_PG_SZ_MASK, sz.encoding())
+    }
+    pub fn set_dc_l2tlb_miss(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_L2TLB_MISS_BIT, x) 
+    }
+    pub fn set_dc_l1tlb_miss(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::DC_L1TLB_MISS_BIT, x) 
+    }
+    pub fn set_st_op(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::ST_OP_BIT, x) 
+    }
+    pub fn set_ld_op(&mut self, x: bool) { 
+        set_bit(&mut self.0, Self::LD_OP_BIT, x) 
+    }
 }
 
 /// Representing load/store width values captured by IBS.
 ///
 /// See the IBS_OP_DATA3 entry in the PPR for Family 17h Model 71h.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
-#[repr(usize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub enum IbsMemWidth {
-    None    = 0,
-    Byte    = 8,
-    Word    = 16,
-    Dword   = 32,
-    Qword   = 64,
-    Oword   = 128,
-    Yword   = 256,
+    None,
+    Byte,
+    Word,
+    Dword,
+    Qword,
+    Oword,
+    Yword,
+    /// Some undefined encoding (0x7..=0xf)
+    Unknown(u8),
 }
 impl From<usize> for IbsMemWidth {
     fn from(x: usize) -> Self {
@@ -306,9 +771,433 @@ impl From<usize> for IbsMemWidth {
             0x4 => IbsMemWidth::Qword,
             0x5 => IbsMemWidth::Oword,
             0x6 => IbsMemWidth::Yword,
-            _ => panic!("Invalid/unsupported access width value {}", x),
+            _ => IbsMemWidth::Unknown((x & 0xf) as u8),
         }
     }
 }
+impl IbsMemWidth {
+    /// Return the value of the OpMemWidth field for this width.
+    ///
+    /// Panics if an [IbsMemWidth::Unknown] encoding isn't in 0x7..=0xf 
+    /// (since it would alias a defined width, or not fit in the field).
+    pub fn encoding(&self) -> usize {
+        match self {
+            IbsMemWidth::None   => 0x0,
+            IbsMemWidth::Byte   => 0x1,
+            IbsMemWidth::Word   => 0x2,
+            IbsMemWidth::Dword  => 0x3,
+            IbsMemWidth::Qword  => 0x4,
+            IbsMemWidth::Oword  => 0x5,
+            IbsMemWidth::Yword  => 0x6,
+            IbsMemWidth::Unknown(x) => {
+                assert!((0x7..=0xf).contains(x), 
+                    "Invalid undefined memory width {:#x}", x
+                );
+                *x as usize
+            },
+        }
+    }
+
+    /// Return the width in bits, or `None` for an unknown encoding.
+    pub fn bits(&self) -> Option<usize> {
+        match self {
+            IbsMemWidth::None   => Some(0),
+            IbsMemWidth::Byte   => Some(8),
+            IbsMemWidth::Word   => Some(16),
+            IbsMemWidth::Dword  => Some(32),
+            IbsMemWidth::Qword  => Some(64),
+            IbsMemWidth::Oword  => Some(128),
+            IbsMemWidth::Yword  => Some(256),
+            IbsMemWidth::Unknown(_) => None,
+        }
+    }
+}
+
 
 
+
+/// MSRC001_1030 [IBS Fetch Control] (Core::X86::Msr::IBS_FETCH_CTL)
+#[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[repr(transparent)]
+pub struct IbsFetchCtl(pub usize);
+impl IbsFetchCtl {
+    const RES_63_59_MASK:       usize = 0xf800_0000_0000_0000;
+    const FETCH_L2_MISS_BIT:    usize = 0x0400_0000_0000_0000;
+    const RAND_EN_BIT:          usize = 0x0200_0000_0000_0000;
+    const L2TLB_MISS_BIT:       usize = 0x0100_0000_0000_0000;
+    const L1TLB_MISS_BIT:       usize = 0x0080_0000_0000_0000;
+    const L1TLB_PG_SZ_MASK:     usize = 0x0060_0000_0000_0000;
+    const PHY_ADDR_VALID_BIT:   usize = 0x0010_0000_0000_0000;
+    const IC_MISS_BIT:          usize = 0x0008_0000_0000_0000;
+    const FETCH_COMP_BIT:       usize = 0x0004_0000_0000_0000;
+    const FETCH_VAL_BIT:        usize = 0x0002_0000_0000_0000;
+    const FETCH_EN_BIT:         usize = 0x0001_0000_0000_0000;
+    const FETCH_LAT_MASK:       usize = 0x0000_ffff_0000_0000;
+    const FETCH_CNT_MASK:       usize = 0x0000_0000_ffff_0000;
+    const FETCH_MAX_CNT_MASK:   usize = 0x0000_0000_0000_ffff;
+
+    pub fn res_hi(&self) -> usize {
+        (self.0 & Self::RES_63_59_MASK) >> 59
+    }
+
+    /// The fetch missed in the L2 cache
+    pub fn fetch_l2_miss(&self) -> bool {
+        (self.0 & Self::FETCH_L2_MISS_BIT) != 0
+    }
+    /// Randomization of the fetch counter is enabled
+    pub fn rand_en(&self) -> bool {
+        (self.0 & Self::RAND_EN_BIT) != 0
+    }
+    /// The fetch missed in the L2 ITLB
+    pub fn l2tlb_miss(&self) -> bool {
+        (self.0 & Self::L2TLB_MISS_BIT) != 0
+    }
+    /// The fetch missed in the L1 ITLB
+    pub fn l1tlb_miss(&self) -> bool {
+        (self.0 & Self::L1TLB_MISS_BIT) != 0
+    }
+    /// Page size of the L1 ITLB entry used for the fetch
+    pub fn l1tlb_pg_sz(&self) -> IbsPageSize {
+        IbsPageSize::from((self.0 & Self::L1TLB_PG_SZ_MASK) >> 53)
+    }
+    /// The physical address in IBS_FETCH_PHYSAD is valid
+    pub fn phy_addr_valid(&self) -> bool {
+        (self.0 & Self::PHY_ADDR_VALID_BIT) != 0
+    }
+    /// The fetch missed in the instruction cache
+    pub fn ic_miss(&self) -> bool {
+        (self.0 & Self::IC_MISS_BIT) != 0
+    }
+    /// The fetch completed (data was delivered to the decoder)
+    pub fn fetch_comp(&self) -> bool {
+        (self.0 & Self::FETCH_COMP_BIT) != 0
+    }
+    /// A fetch was tagged and the sample is valid
+    pub fn fetch_val(&self) -> bool {
+        (self.0 & Self::FETCH_VAL_BIT) != 0
+    }
+    /// Fetch sampling is enabled
+    pub fn fetch_en(&self) -> bool {
+        (self.0 & Self::FETCH_EN_BIT) != 0
+    }
+    /// Cycles from when the fetch was issued to when it was completed
+    pub fn fetch_lat(&self) -> usize {
+        (self.0 & Self::FETCH_LAT_MASK) >> 32
+    }
+    /// Current value of the fetch counter (bits [19:4])
+    pub fn fetch_cnt(&self) -> usize {
+        (self.0 & Self::FETCH_CNT_MASK) >> 16
+    }
+    /// Maximum value of the fetch counter (bits [19:4])
+    pub fn fetch_max_cnt(&self) -> usize {
+        self.0 & Self::FETCH_MAX_CNT_MASK
+    }
+}
+
+impl std::fmt::Debug for IbsFetchCtl {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("IbsFetchCtl")
+            .field("val", &self.fetch_val())
+            .field("comp", &self.fetch_comp())
+            .field("lat", &self.fetch_lat())
+            .field("ic_miss", &self.ic_miss())
+            .field("l2_miss", &self.fetch_l2_miss())
+            .field("l1tlb_miss", &self.l1tlb_miss())
+            .field("l2tlb_miss", &self.l2tlb_miss())
+            .field("l1tlb_pg_sz", &self.l1tlb_pg_sz())
+            .field("phy_addr_valid", &self.phy_addr_valid())
+            .finish()
+    }
+}
+
+/// MSRC001_1031 [IBS Fetch Linear Address] (Core::X86::Msr::IBS_FETCH_LINADDR)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[repr(transparent)]
+pub struct IbsFetchLinAd(pub usize);
+impl IbsFetchLinAd {
+    /// Linear address of the tagged fetch
+    pub fn addr(&self) -> usize {
+        self.0
+    }
+}
+
+/// MSRC001_1032 [IBS Fetch Physical Address] (Core::X86::Msr::IBS_FETCH_PHYSADDR)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[repr(transparent)]
+pub struct IbsFetchPhysAd(pub usize);
+impl IbsFetchPhysAd {
+    const PHYS_ADDR_MASK:       usize = 0x0000_ffff_ffff_ffff;
+
+    /// Physical address of the tagged fetch (only valid when
+    /// [IbsFetchCtl::phy_addr_valid] is set)
+    pub fn addr(&self) -> usize {
+        self.0 & Self::PHYS_ADDR_MASK
+    }
+}
+
+/// MSRC001_103C [IBS Fetch Control Extended] (Core::X86::Msr::IC_IBS_EXTD_CTL)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[repr(transparent)]
+pub struct IbsFetchExtCtl(pub usize);
+impl IbsFetchExtCtl {
+    const ITLB_REFILL_LAT_MASK: usize = 0x0000_0000_0000_ffff;
+
+    /// Cycles spent handling an ITLB miss for the tagged fetch
+    pub fn itlb_refill_lat(&self) -> usize {
+        self.0 & Self::ITLB_REFILL_LAT_MASK
+    }
+}
+
+/// Page sizes reported by IBS for TLB entries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum IbsPageSize {
+    Page4K,
+    Page2M,
+    Page1G,
+    Reserved,
+}
+impl From<usize> for IbsPageSize {
+    fn from(x: usize) -> Self {
+        match x & 0x3 {
+            0 => Self::Page4K,
+            1 => Self::Page2M,
+            2 => Self::Page1G,
+            _ => Self::Reserved,
+        }
+    }
+}
+impl IbsPageSize {
+    /// Return the 2-bit encoding for this page size.
+    pub fn encoding(&self) -> usize {
+        match self {
+            Self::Page4K    => 0,
+            Self::Page2M    => 1,
+            Self::Page1G    => 2,
+            Self::Reserved  => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ibs::*;
+    use crate::ioctl::MAX_OFFSET;
+
+    #[test]
+    fn op_ctl_round_trip() {
+        let ctl = IbsOpCtl::new()
+            .with_max_cnt(0x07ff_fff0)
+            .with_cur_cnt(0x0123_4567)
+            .with_cnt_ctl(true)
+            .with_val(true)
+            .with_en(true)
+            .with_l3_miss_only(true)
+            .with_ld_lat_en(true)
+            .with_ld_lat_thrsh(0xf);
+        assert_eq!(ctl.0, 0xf923_4567_07ff_ffff);
+        assert_eq!(ctl.max_cnt(), 0x07ff_fff0);
+        assert_eq!(ctl.cur_cnt(), 0x0123_4567);
+        assert!(ctl.cnt_ctl() && ctl.val() && ctl.en() && ctl.l3_miss_only());
+        assert_eq!(ctl.ld_lat_threshold(), 2048);
+        assert_eq!(ctl.res_31_27(), 0);
+        assert_eq!(ctl.res_63_59(), 0x1f);
+
+        // Clearing every field yields an empty value
+        let mut res = ctl;
+        res.set_max_cnt(0);
+        res.set_cur_cnt(0);
+        res.set_cnt_ctl(false);
+        res.set_val(false);
+        res.set_en(false);
+        res.set_l3_miss_only(false);
+        res.set_ld_lat_en(false);
+        res.set_ld_lat_thrsh(0);
+        assert_eq!(res, IbsOpCtl::new());
+
+        // Fields don't clobber each other
+        for max_cnt in [0x10, 0x0010_0000, 0x0012_3450, 0x07ff_fff0] {
+            let res = ctl.with_max_cnt(max_cnt);
+            assert_eq!(res.max_cnt(), max_cnt);
+            assert_eq!(res.with_max_cnt(0x07ff_fff0), ctl);
+        }
+        assert_eq!(IbsOpCtl(0x0000_0000_f800_0000).res_31_27(), 0x1f);
+    }
+
+    #[test]
+    fn op_ctl_precise() {
+        // Matches the value written by the precise trampoline
+        let ctl = IbsOpCtl::precise(3);
+        assert_eq!(ctl.0, ((MAX_OFFSET - 3) << 32) | MAX_OFFSET | 0x000a_0000);
+        assert_eq!(ctl.max_cnt(), MAX_OFFSET);
+        assert_eq!(ctl.remaining(), 3);
+        assert_eq!(IbsOpCtl::precise(MAX_OFFSET - 1).remaining(), MAX_OFFSET - 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn op_ctl_unaligned_max_cnt() {
+        let _ = IbsOpCtl::new().with_max_cnt(0x1001);
+    }
+
+    #[test]
+    fn op_ctl_invalid_fields() {
+        let mut ctl = IbsOpCtl::new().with_max_cnt(0x1000).with_cur_cnt(0x10);
+        assert_eq!(ctl.try_set_max_cnt(0x1001), 
+            Err(Error::InvalidConfig { field: "max count", value: 0x1001 })
+        );
+        assert!(ctl.try_set_max_cnt(IbsOpCtl::MAX_MAX_CNT + 0x10).is_err());
+        assert!(ctl.try_set_cur_cnt(IbsOpCtl::MAX_CUR_CNT + 1).is_err());
+        assert_eq!(ctl.try_set_ld_lat_thrsh(IbsOpCtl::MAX_LD_LAT_THRSH + 1), 
+            Err(Error::InvalidConfig { 
+                field: "load latency threshold", value: IbsOpCtl::MAX_LD_LAT_THRSH + 1
+            })
+        );
+
+        // Failing to set a field leaves the register untouched
+        assert_eq!(ctl, IbsOpCtl::new().with_max_cnt(0x1000).with_cur_cnt(0x10));
+        assert_eq!(ctl.try_set_ld_lat_thrsh(IbsOpCtl::MAX_LD_LAT_THRSH), Ok(()));
+        assert_eq!(ctl.ld_lat_thrsh(), IbsOpCtl::MAX_LD_LAT_THRSH);
+    }
+
+    /// Set each flag in an empty register, checking that only a single bit
+    /// is set and that clearing it yields an empty register again.
+    macro_rules! check_flags {
+        ($reg:ident, $($set:ident => $get:ident),* $(,)?) => {{
+            $(
+                let mut r = $reg::new();
+                r.$set(true);
+                assert!(r.$get(), stringify!($get));
+                assert_eq!(r.0.count_ones(), 1, stringify!($set));
+                r.$set(false);
+                assert_eq!(r.0, 0, stringify!($set));
+            )*
+        }}
+    }
+
+    #[test]
+    fn op_data_round_trip() {
+        check_flags!(IbsOpData,
+            set_op_microcode => op_microcode,
+            set_op_brn_fuse => op_brn_fuse,
+            set_rip_invalid => rip_invalid,
+            set_op_brn_ret => op_brn_ret,
+            set_op_brn_misp => op_brn_misp,
+            set_op_brn_taken => op_brn_taken,
+            set_op_return => op_return,
+        );
+        let mut d = IbsOpData::new();
+        d.set_tag_to_ret_ctr(0xffff);
+        d.set_comp_to_ret_ctr(0x1234);
+        assert_eq!(d.0, 0x0000_0000_ffff_1234);
+        assert_eq!((d.tag_to_ret_ctr(), d.comp_to_ret_ctr()), (0xffff, 0x1234));
+        d.set_tag_to_ret_ctr(0x10);
+        assert_eq!(d.0, 0x0000_0000_0010_1234);
+        assert!(!d.op_return() && d.res_hi() == 0 && d.res_lo() == 0);
+    }
+
+    #[test]
+    fn op_data2_round_trip() {
+        check_flags!(IbsOpData2,
+            set_cache_hit_st => cache_hit_st,
+            set_rmt_node => rmt_node,
+        );
+        for src in [NbDataSrc::Invalid, NbDataSrc::Cache, NbDataSrc::Dram,
+            NbDataSrc::RemoteCache, NbDataSrc::Io, NbDataSrc::Reserved(5)] 
+        {
+            let mut d = IbsOpData2::new();
+            d.set_rmt_node(true);
+            d.set_data_src(src);
+            assert_eq!(d.data_src(), src);
+            assert!(d.rmt_node());
+        }
+        for src in [NbDataSrc::Invalid, NbDataSrc::LocalCcx, NbDataSrc::NearCcx,
+            NbDataSrc::Dram, NbDataSrc::FarCcx, NbDataSrc::Pmem, NbDataSrc::Io,
+            NbDataSrc::ExtMem, NbDataSrc::PeerAgentMem, NbDataSrc::Reserved(0x1f)]
+        {
+            let mut d = IbsOpData2::new();
+            d.set_cache_hit_st(true);
+            d.set_data_src_ext(src);
+            assert_eq!(d.data_src_ext(), src);
+            assert!(d.cache_hit_st());
+        }
+        assert_eq!(NbDataSrc::ExtMem.encoding(), None);
+        assert_eq!(NbDataSrc::Cache.ext_encoding(), None);
+    }
+
+    #[test]
+    fn op_data3_round_trip() {
+        check_flags!(IbsOpData3,
+            set_sw_pf => sw_pf,
+            set_dc_l2_miss => dc_l2_miss,
+            set_dc_l2tlb_hit_1g => dc_l2tlb_hit_1g,
+            set_dc_phy_addr_valid => dc_phy_addr_valid,
+            set_dc_lin_addr_valid => dc_lin_addr_valid,
+            set_dc_miss_no_mab_alloc => dc_miss_no_mab_alloc,
+            set_dc_locked_op => dc_locked_op,
+            set_dc_uc_mem_acc => dc_uc_mem_acc,
+            set_dc_wc_mem_acc => dc_wc_mem_acc,
+            set_cancelled => cancelled,
+            set_forwarded => forwarded,
+            set_bank_conf_st => bank_conf_st,
+            set_bank_conf_ld => bank_conf_ld,
+            set_dc_mis_acc => dc_mis_acc,
+            set_dc_miss => dc_miss,
+            set_dc_l2tlb_hit_2m => dc_l2tlb_hit_2m,
+            set_dc_l1tlb_hit_1g => dc_l1tlb_hit_1g,
+            set_dc_l1tlb_hit_2m => dc_l1tlb_hit_2m,
+            set_dc_l2tlb_miss => dc_l2tlb_miss,
+            set_dc_l1tlb_miss => dc_l1tlb_miss,
+            set_st_op => st_op,
+            set_ld_op => ld_op,
+        );
+        let mut d = IbsOpData3::new();
+        d.set_tlb_refill_lat(0xffff);
+        d.set_dc_miss_lat(0x1234);
+        d.set_op_dc_miss_open_mem_reqs(0x3f);
+        d.set_op_mem_width(IbsMemWidth::Yword);
+        assert_eq!(d.0, 0xffff_1234_fd80_0000);
+        assert_eq!(d.tlb_refill_lat(), 0xffff);
+        assert_eq!(d.dc_miss_lat(), 0x1234);
+        assert_eq!(d.op_dc_miss_open_mem_reqs(), 0x3f);
+        for width in [IbsMemWidth::None, IbsMemWidth::Byte, IbsMemWidth::Word,
+            IbsMemWidth::Dword, IbsMemWidth::Qword, IbsMemWidth::Oword, 
+            IbsMemWidth::Yword]
+        {
+            d.set_op_mem_width(width);
+            assert_eq!(d.op_mem_width(), width);
+        }
+        for sz in [IbsPageSize::Page4K, IbsPageSize::Page2M, 
+            IbsPageSize::Page1G, IbsPageSize::Reserved] 
+        {
+            d.set_dc_l1tlb_pg_sz(sz);
+            assert_eq!(d.dc_l1tlb_pg_sz(), sz);
+        }
+        assert_eq!(d.0, 0xffff_1234_fd80_0030);
+        assert_eq!(d.res_lo(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn op_data3_latency_overflow() {
+        IbsOpData3::new().set_dc_miss_lat(0x10000);
+    }
+
+    #[test]
+    fn op_data3_unknown_width() {
+        let mut d = IbsOpData3::new();
+        d.set_op_mem_width(IbsMemWidth::Unknown(0x7));
+        assert_eq!(d.op_mem_width(), IbsMemWidth::Unknown(0x7));
+        assert_eq!(IbsMemWidth::from(0xf), IbsMemWidth::Unknown(0xf));
+
+        // Encodings which alias a defined width, or which don't fit
+        for x in [0x3, 0x20] {
+            let res = std::panic::catch_unwind(|| IbsMemWidth::Unknown(x).encoding());
+            assert!(res.is_err(), "{:#x}", x);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Information exported by the `ibstrace` kernel module via debugfs.
+
+use std::path::{ Path, PathBuf };
+
+use crate::*;
+
+/// Values exported by the `ibstrace` kernel module in debugfs
+/// (see `/sys/kernel/debug/ibstrace/`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IbstraceInfo {
+    /// Virtual address of the code buffer
+    pub code_buf: usize,
+    /// Virtual address of the sample buffer
+    pub sample_buf: usize,
+    /// Virtual address of the scratch page passed to user code
+    pub scratch_page: usize,
+    /// Physical address of the scratch page passed to user code
+    pub scratch_page_paddr: usize,
+    /// Maximum number of entries in the sample buffer
+    pub sample_capacity: usize,
+    /// Maximum size of the code buffer (in bytes)
+    pub code_buf_max_size: usize,
+    /// The core used to run user code
+    pub target_cpu: usize,
+    /// Base virtual address of the kernel direct map (`PAGE_OFFSET`), if
+    /// exported by the module (older versions don't export this)
+    pub page_offset: Option<usize>,
+}
+impl IbstraceInfo {
+    /// Default mount point for debugfs.
+    pub const DEFAULT_DEBUGFS_ROOT: &'static str = "/sys/kernel/debug";
+
+    /// Size of the scratch page (in bytes).
+    pub const SCRATCH_PAGE_SIZE: usize = 0x1000;
+
+    /// Read all values from the default debugfs mount point.
+    pub fn read() -> Result<Self, Error> {
+        Self::read_from(Self::DEFAULT_DEBUGFS_ROOT)
+    }
+
+    /// Read all values, given the path where debugfs is mounted.
+    pub fn read_from(root: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = Self::dir(root);
+        Ok(Self {
+            code_buf: read_value(&dir, "code_buf")?,
+            sample_buf: read_value(&dir, "sample_buf")?,
+            scratch_page: read_value(&dir, "scratch_page")?,
+            scratch_page_paddr: read_value(&dir, "scratch_page_paddr")?,
+            sample_capacity: read_value(&dir, "sample_capacity")?,
+            code_buf_max_size: read_value(&dir, "code_buf_max_size")?,
+            target_cpu: read_value(&dir, "target_cpu")?,
+            page_offset: read_optional_value(&dir, "page_offset")?,
+        })
+    }
+
+    /// Read only the base address of the code buffer, given the path where
+    /// debugfs is mounted.
+    pub fn read_code_buf(root: impl AsRef<Path>) -> Result<usize, Error> {
+        read_value(&Self::dir(root), "code_buf")
+    }
+
+    /// Returns `true` if the given physical address lies in the scratch page.
+    pub fn scratch_page_contains(&self, paddr: usize) -> bool {
+        let base = self.scratch_page_paddr;
+        (base..base + Self::SCRATCH_PAGE_SIZE).contains(&paddr)
+    }
+
+    /// Return the virtual address of some physical address in the kernel 
+    /// direct map. 
+    ///
+    /// This is how measured code can refer to memory we've allocated in 
+    /// userspace (see [RawAlloc::kernel_addr](crate::util::RawAlloc::kernel_addr)).
+    /// Returns `None` if the module doesn't export `PAGE_OFFSET`.
+    pub fn direct_map_addr(&self, paddr: usize) -> Option<usize> {
+        Some(self.page_offset? + paddr)
+    }
+
+    fn dir(root: impl AsRef<Path>) -> PathBuf {
+        root.as_ref().join("ibstrace")
+    }
+}
+
+/// Parse a value exported by the module.
+///
+/// The module uses `debugfs_create_x64()` and `debugfs_create_x32()`, which
+/// format values like `0x%016llx` and `0x%08x`.
+fn parse_value(s: &str) -> Option<usize> {
+    let x = s.trim().strip_prefix("0x")?;
+    usize::from_str_radix(x, 16).ok()
+}
+
+/// Read and parse a single value exported by the module.
+fn read_value(dir: &Path, name: &str) -> Result<usize, Error> {
+    let path = dir.join(name);
+    let s = std::fs::read_to_string(&path).map_err(|e| {
+        Error::DebugfsUnreadable {
+            path: path.display().to_string(),
+            source: e.into(),
+        }
+    })?;
+    parse_value(&s).ok_or_else(|| Error::DebugfsMalformed {
+        path: path.display().to_string(),
+        contents: s,
+    })
+}
+
+/// Read and parse a single value which may not be exported by the module
+/// (ie. when it was added in a later version).
+fn read_optional_value(dir: &Path, name: &str) -> Result<Option<usize>, Error> {
+    match read_value(dir, name) {
+        Ok(x) => Ok(Some(x)),
+        Err(Error::DebugfsUnreadable { source, .. }) 
+            if source.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::info::*;
+
+    /// Create a fake debugfs directory with the given files.
+    fn fake_debugfs(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir()
+            .join(format!("ibst-test-{}-{}", name, std::process::id()));
+        let dir = root.join("ibstrace");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn read_fake_debugfs() {
+        let root = fake_debugfs("ok", &[
+            ("code_buf", "0xffffc90000a45000\n"),
+            ("sample_buf", "0xffffc90000c00000\n"),
+            ("scratch_page", "0xffff888100200000\n"),
+            ("scratch_page_paddr", "0x0000000100200000\n"),
+            ("sample_capacity", "0x0000000000040000\n"),
+            ("code_buf_max_size", "0x0000000000020000\n"),
+            ("target_cpu", "0x0000000f\n"),
+            ("page_offset", "0xffff888000000000\n"),
+        ]);
+        let info = IbstraceInfo::read_from(&root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(info.code_buf, 0xffff_c900_00a4_5000);
+        assert_eq!(info.sample_capacity, 0x40000);
+        assert_eq!(info.code_buf_max_size, ioctl::CODE_BUFFER_MAX_SIZE);
+        assert_eq!(info.target_cpu, 15);
+        assert!(info.scratch_page_contains(0x1_0020_0ff8));
+        assert!(!info.scratch_page_contains(0x1_0020_1000));
+        assert_eq!(info.direct_map_addr(info.scratch_page_paddr), 
+            Some(info.scratch_page)
+        );
+    }
+
+    #[test]
+    fn read_older_debugfs() {
+        // Without 'page_offset'
+        let root = fake_debugfs("older", &[
+            ("code_buf", "0xffffc90000a45000\n"),
+            ("sample_buf", "0xffffc90000c00000\n"),
+            ("scratch_page", "0xffff888100200000\n"),
+            ("scratch_page_paddr", "0x0000000100200000\n"),
+            ("sample_capacity", "0x0000000000040000\n"),
+            ("code_buf_max_size", "0x0000000000020000\n"),
+            ("target_cpu", "0x00000000\n"),
+        ]);
+        let info = IbstraceInfo::read_from(&root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(info.page_offset, None);
+        assert_eq!(info.direct_map_addr(info.scratch_page_paddr), None);
+    }
+
+    #[test]
+    fn read_malformed_debugfs() {
+        let root = fake_debugfs("malformed", &[
+            ("code_buf", "0xzzzz\n"),
+            ("sample_buf", "12345\n"),
+        ]);
+        let code_buf = IbstraceInfo::read_code_buf(&root);
+        let info = IbstraceInfo::read_from(&root);
+        let sample_buf = read_value(&IbstraceInfo::dir(&root), "sample_buf");
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(matches!(code_buf, Err(Error::DebugfsMalformed { .. })));
+        assert!(matches!(info, Err(Error::DebugfsMalformed { .. })));
+        assert!(matches!(sample_buf, Err(Error::DebugfsMalformed { .. })));
+        let err = IbstraceInfo::read_from(&root).unwrap_err();
+        assert!(matches!(err, Error::DebugfsUnreadable { .. }));
+        assert_eq!(err.errno(), Some(nix::errno::Errno::ENOENT));
+    }
+}
@@ -1,3 +1,4 @@
+use std::ops::RangeInclusive;
 
 /// Path to the 'ibstrace' character device.
 pub const IBSTRACE_CHARDEV: &str = "/dev/ibstrace";
@@ -17,13 +18,57 @@ pub const CMD_CAPACITY: usize = 0x0000_8000;
 /// The "precise" ioctl() command
 pub const CMD_PRECISE:  usize = 0x0002_0000;
 
+/// The "regs" ioctl() command
+pub const CMD_REGS:     usize = 0x0004_0000;
+
+/// The "set CPU" ioctl() command
+pub const CMD_SET_CPU:  usize = 0x0008_0000;
+
+/// The "fetch" ioctl() command
+pub const CMD_FETCH:    usize = 0x0010_0000;
+
+/// The "sweep" ioctl() command
+pub const CMD_SWEEP:    usize = 0x0020_0000;
+
+/// The "batch" ioctl() command
+pub const CMD_BATCH:    usize = 0x0040_0000;
+
+/// The "stats" ioctl() command
+pub const CMD_STATS:    usize = 0x0080_0000;
+
+/// The "version" ioctl() command
+pub const CMD_VERSION:  usize = 0x0100_0000;
+
+/// The "fault" ioctl() command
+pub const CMD_FAULT:    usize = 0x0200_0000;
+
+/// The "write data" ioctl() command
+pub const CMD_WRITE_DATA: usize = 0x0400_0000;
+
+/// The "read data" ioctl() command
+pub const CMD_READ_DATA:  usize = 0x0800_0000;
+
 /// The maximum supported offset in "precise" sampling mode. 
 ///
 /// NOTE: This is also defined as a constant in the kernel module. 
 /// This *must* match the definition in `include/asm/ibstrace_asm.h`. 
 pub const MAX_OFFSET:   usize = 0x0040_0000;
 
+/// The maximum size of user code (in bytes). 
+///
+/// NOTE: This *must* match `CODE_BUFFER_MAX_SIZE` in `ibstrace/state.h`
+/// (32 pages).
+pub const CODE_BUFFER_MAX_SIZE: usize = 0x0002_0000;
+
+/// The alignment of each program in the code buffer for [`CMD_BATCH`].
+///
+/// NOTE: This *must* match `BATCH_ALIGN` in `ibstrace/state.h`.
+pub const BATCH_ALIGN:  usize = 0x0000_0040;
+
 /// Argument to [`CMD_WRITE`], used to upload user code. 
+///
+/// This is also used to describe the buffer for [`CMD_WRITE_DATA`] and 
+/// [`CMD_READ_DATA`].
 #[repr(C)]
 pub struct UserBuf { 
     /// Pointer to buffer with user code
@@ -35,23 +80,240 @@ impl UserBuf {
     pub fn new(ptr: *const u8, len: usize) -> Self {
         Self { ptr, len }
     }
+
+    /// Returns the length of the user code (in bytes).
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Argument to [`CMD_MEASURE`], used to configure sampling. 
+/// See [SamplingConfig](crate::SamplingConfig).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MeasureArgs {
+    /// Value for IBS_OP_CTL (only IbsOpMaxCnt, IbsOpCntCtl and the filters 
+    /// in [SamplingConfig](crate::SamplingConfig))
+    op_ctl: usize,
+    /// Flags (see [`MeasureArgs::RANDOMIZE`])
+    flags: usize,
+    /// Number of times to run user code before sampling
+    warmup: usize,
+}
+impl MeasureArgs {
+    /// Randomize the initial value of the counter.
+    pub const RANDOMIZE: usize = 1 << 0;
+
+    pub fn new(op_ctl: usize, flags: usize, warmup: usize) -> Self {
+        Self { op_ctl, flags, warmup }
+    }
+
+    /// Returns the value for IBS_OP_CTL.
+    pub fn op_ctl(&self) -> usize {
+        self.op_ctl
+    }
+
+    /// Returns the flags.
+    pub fn flags(&self) -> usize {
+        self.flags
+    }
+
+    /// Returns the number of times to run user code before sampling.
+    pub fn warmup(&self) -> usize {
+        self.warmup
+    }
+}
+
+/// Argument to [`CMD_FETCH`], used to configure fetch sampling. 
+/// See [FetchConfig](crate::FetchConfig).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FetchArgs {
+    /// Value for IBS_FETCH_CTL (only IbsFetchMaxCnt and IbsRandEn)
+    fetch_ctl: usize,
+    /// Number of times to run user code before sampling
+    warmup: usize,
+}
+impl FetchArgs {
+    pub fn new(fetch_ctl: usize, warmup: usize) -> Self {
+        Self { fetch_ctl, warmup }
+    }
+
+    /// Returns the value for IBS_FETCH_CTL.
+    pub fn fetch_ctl(&self) -> usize {
+        self.fetch_ctl
+    }
+
+    /// Returns the number of times to run user code before sampling.
+    pub fn warmup(&self) -> usize {
+        self.warmup
+    }
+}
+
+/// Argument to [`CMD_BATCH`], used to measure a list of programs back-to-back.
+#[repr(C)]
+pub struct BatchArgs {
+    /// Pointer to a list of programs
+    progs: *const UserBuf,
+    /// Number of programs
+    count: usize,
+    /// Pointer to a list of results (one for each program), filled in by 
+    /// the kernel module
+    results: *mut BatchResult,
+    /// Sampling configuration used for all programs
+    config: MeasureArgs,
+}
+impl BatchArgs {
+    pub fn new(progs: &[UserBuf], results: &mut [BatchResult], 
+        config: MeasureArgs) -> Self 
+    {
+        assert!(progs.len() == results.len(),
+            "Expected {} results, got {}", progs.len(), results.len()
+        );
+        Self { 
+            progs: progs.as_ptr(), 
+            count: progs.len(),
+            results: results.as_mut_ptr(),
+            config,
+        }
+    }
+
+    /// Returns the number of programs.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// The result of measuring a single program with [`CMD_BATCH`]. 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatchResult {
+    /// Offset of the program in the code buffer
+    pub load_offset: usize,
+    /// Index of the first sample collected for this program
+    pub first: usize,
+    /// Number of samples collected for this program
+    pub count: usize,
+    /// Number of samples dropped while running this program
+    pub dropped: usize,
+    /// Number of spurious NMIs while running this program
+    pub spurious: usize,
+    /// Register state after the program returned
+    pub regs: crate::Registers,
+    /// Set when this program faulted (the remaining programs in the batch
+    /// are not run)
+    pub fault: FaultInfo,
+}
+
+/// The fault taken by measured code, returned by [`CMD_FAULT`]. 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultInfo {
+    /// Non-zero when measured code faulted during the most-recent 
+    /// measurement
+    pub valid: usize,
+    /// Exception vector
+    pub vector: usize,
+    /// Address of the faulting instruction
+    pub rip: usize,
+    /// Error code pushed by the exception (if any)
+    pub error_code: usize,
+}
+impl FaultInfo {
+    /// Convert this into an [Error::MeasuredCodeFault](crate::Error), if 
+    /// measured code faulted.
+    pub fn to_error(&self) -> Option<crate::Error> {
+        if self.valid == 0 {
+            return None;
+        }
+        Some(crate::Error::MeasuredCodeFault { 
+            vector: self.vector, 
+            rip: self.rip, 
+            error_code: self.error_code 
+        })
+    }
 }
 
 /// Argument to [`CMD_PRECISE`], used to sample a particular micro-op. 
 #[repr(C)]
 pub struct PreciseArgs { 
-    /// Argument passed through to user code (in RDI)
+    /// Argument passed through to user code (in RDI). 
+    /// The scratch page is passed in RSI.
     arg: usize,
     /// Counter offset
     offset: usize,
 }
 impl PreciseArgs {
-    pub fn new(arg: usize, offset: usize) -> Self {
-        assert!(offset <= MAX_OFFSET,
-            "Requested offset {} would exceeds maximum offset {}",
-            offset, MAX_OFFSET
-        );
-        Self { arg, offset }
+    /// Fails with [Error::InvalidOffsets](crate::Error::InvalidOffsets) if
+    /// the offset is not less than [MAX_OFFSET].
+    pub fn new(arg: usize, offset: usize) -> Result<Self, crate::Error> {
+        if offset >= MAX_OFFSET {
+            return Err(crate::Error::InvalidOffsets { 
+                first: offset, last: offset
+            });
+        }
+        Ok(Self { arg, offset })
+    }
+
+    /// Returns the argument passed through to user code.
+    pub fn arg(&self) -> usize {
+        self.arg
+    }
+
+    /// Returns the counter offset.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// Argument to [`CMD_SWEEP`], used to sample a range of micro-ops (with one 
+/// precise measurement per offset). 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SweepArgs {
+    /// Argument passed through to user code (in RDI)
+    arg: usize,
+    /// The first counter offset
+    first: usize,
+    /// The number of offsets
+    count: usize,
+}
+impl SweepArgs {
+    /// Fails with [Error::InvalidOffsets](crate::Error::InvalidOffsets) if
+    /// the range is empty, or if it reaches [MAX_OFFSET].
+    pub fn new(arg: usize, offsets: RangeInclusive<usize>)
+        -> Result<Self, crate::Error>
+    {
+        let (first, last) = offsets.into_inner();
+        if first > last || last >= MAX_OFFSET {
+            return Err(crate::Error::InvalidOffsets { first, last });
+        }
+        Ok(Self { arg, first, count: last - first + 1 })
+    }
+
+    /// Returns the argument passed through to user code.
+    pub fn arg(&self) -> usize {
+        self.arg
+    }
+
+    /// Returns the first counter offset.
+    pub fn first(&self) -> usize {
+        self.first
+    }
+
+    /// Returns the number of offsets.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the range of counter offsets.
+    pub fn offsets(&self) -> RangeInclusive<usize> {
+        self.first..=(self.first + self.count - 1)
     }
 }
 
@@ -0,0 +1,411 @@
+//! Decoding IBS op samples for a particular processor family.
+//!
+//! The accessors in [ibs](crate::ibs) follow the layout from the PPR for
+//! Family 17h Model 71h. Later parts redefine some fields (ie. on Family 19h,
+//! DATA3 bits [5:4] are a 2-bit L1 DTLB page size instead of two separate
+//! flags), so an [IbsLayout] is used to decode samples into an [IbsOpDecoded]
+//! where fields which don't exist on a particular part are `None`.
+
+use crate::*;
+use crate::ibs::*;
+
+/// The layout of the IBS op data registers on a particular part.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum IbsLayout {
+    /// Family 17h (see "PPR for AMD Family 17h Model 71h B0")
+    #[default]
+    Zen2,
+    /// Family 19h Models 00h-0Fh and 20h-5Fh
+    Zen3,
+    /// Family 19h Models 10h-1Fh, 60h-7Fh and A0h-AFh (with extended
+    /// data source encodings, see [NbDataSrc::from_ext])
+    Zen4,
+}
+impl IbsLayout {
+    /// The most-recent layout.
+    pub const LATEST: Self = Self::Zen4;
+
+    /// Return the layout for a particular family and model, or `None` if
+    /// the part predates Family 17h.
+    ///
+    /// Unknown models in Family 19h (and later families) are assumed to use
+    /// the most-recent layout (see [IbsLayout::LATEST]).
+    pub fn from_family_model(family: usize, model: usize) -> Option<Self> {
+        match (family, model) {
+            (0x17, _) => Some(Self::Zen2),
+            (0x19, 0x00..=0x0f) | (0x19, 0x20..=0x5f) => Some(Self::Zen3),
+            (0x19, 0x10..=0x1f) | (0x19, 0x60..=0x7f) |
+            (0x19, 0xa0..=0xaf) => Some(Self::Zen4),
+            (0x19.., _) => Some(Self::LATEST),
+            _ => None,
+        }
+    }
+
+    /// Return the layout for the current machine (from CPUID), or `None` if
+    /// the part predates Family 17h.
+    pub fn detect() -> Option<Self> {
+        let eax = std::arch::x86_64::__cpuid(1).eax;
+        let (family, model) = family_model(eax);
+        Self::from_family_model(family, model)
+    }
+
+    /// Decode the IBS op data registers from a sample.
+    pub fn decode(&self, s: &Sample) -> IbsOpDecoded {
+        let zen2 = *self == Self::Zen2;
+        let zen4 = *self == Self::Zen4;
+        let only = |present: bool, x: bool| if present { Some(x) } else { None };
+        IbsOpDecoded {
+            comp_to_ret_ctr: s.data.comp_to_ret_ctr(),
+            tag_to_ret_ctr: s.data.tag_to_ret_ctr(),
+            op_return: s.data.op_return(),
+            op_brn_taken: s.data.op_brn_taken(),
+            op_brn_misp: s.data.op_brn_misp(),
+            op_brn_ret: s.data.op_brn_ret(),
+            rip_invalid: s.data.rip_invalid(),
+            op_brn_fuse: s.data.op_brn_fuse(),
+            op_microcode: s.data.op_microcode(),
+
+            data_src: if zen4 { s.data2.data_src_ext() } else { s.data2.data_src() },
+            rmt_node: s.data2.rmt_node(),
+            cache_hit_st: s.data2.cache_hit_st(),
+
+            ld_op: s.data3.ld_op(),
+            st_op: s.data3.st_op(),
+            dc_l1tlb_miss: s.data3.dc_l1tlb_miss(),
+            dc_l2tlb_miss: s.data3.dc_l2tlb_miss(),
+            dc_l1tlb_hit_2m: only(zen2, s.data3.dc_l1tlb_hit_2m()),
+            dc_l1tlb_hit_1g: only(zen2, s.data3.dc_l1tlb_hit_1g()),
+            dc_l1tlb_pg_sz: if zen2 { None } else { Some(s.data3.dc_l1tlb_pg_sz()) },
+            dc_l2tlb_hit_2m: s.data3.dc_l2tlb_hit_2m(),
+            dc_miss: s.data3.dc_miss(),
+            dc_mis_acc: s.data3.dc_mis_acc(),
+            bank_conf_ld: only(zen2, s.data3.bank_conf_ld()),
+            bank_conf_st: only(zen2, s.data3.bank_conf_st()),
+            forwarded: only(zen2, s.data3.forwarded()),
+            cancelled: only(zen2, s.data3.cancelled()),
+            dc_wc_mem_acc: s.data3.dc_wc_mem_acc(),
+            dc_uc_mem_acc: s.data3.dc_uc_mem_acc(),
+            dc_locked_op: s.data3.dc_locked_op(),
+            dc_miss_no_mab_alloc: s.data3.dc_miss_no_mab_alloc(),
+            dc_lin_addr_valid: s.data3.dc_lin_addr_valid(),
+            dc_phy_addr_valid: s.data3.dc_phy_addr_valid(),
+            dc_l2tlb_hit_1g: s.data3.dc_l2tlb_hit_1g(),
+            dc_l2_miss: s.data3.dc_l2_miss(),
+            sw_pf: s.data3.sw_pf(),
+            op_mem_width: s.data3.op_mem_width(),
+            op_dc_miss_open_mem_reqs: s.data3.op_dc_miss_open_mem_reqs(),
+            dc_miss_lat: s.data3.dc_miss_lat(),
+            tlb_refill_lat: s.data3.tlb_refill_lat(),
+        }
+    }
+}
+
+impl IbsLayout {
+    /// Return a list of the reserved or undefined encodings in a sample. 
+    ///
+    /// These still decode (ie. to [IbsMemWidth::Unknown] or 
+    /// [NbDataSrc::Reserved]), but probably indicate that the layout for 
+    /// this part is wrong or incomplete.
+    pub fn warnings(&self, s: &Sample) -> DecodeWarnings {
+        let mut res = DecodeWarnings::default();
+        let d = self.decode(s);
+        if let IbsMemWidth::Unknown(x) = d.op_mem_width {
+            res.push(DecodeWarning::MemWidth(x));
+        }
+        if let NbDataSrc::Reserved(x) = d.data_src {
+            res.push(DecodeWarning::DataSrc(x));
+        }
+        if d.dc_l1tlb_pg_sz == Some(IbsPageSize::Reserved) {
+            res.push(DecodeWarning::L1TlbPageSize);
+        }
+
+        if s.data.res_hi() != 0 {
+            res.push(DecodeWarning::ReservedBits { 
+                reg: IbsOpReg::Data, bits: s.data.0 & 0xffff_fe00_0000_0000,
+            });
+        }
+        // DataSrcHi is only defined since Zen 4
+        let data2_res = if *self == Self::Zen4 { 
+            0xffff_ffff_ffff_ff00 
+        } else { 
+            0xffff_ffff_ffff_ffc0 
+        };
+        if s.data2.0 & data2_res != 0 {
+            res.push(DecodeWarning::ReservedBits { 
+                reg: IbsOpReg::Data2, bits: s.data2.0 & data2_res,
+            });
+        }
+        // Bits [12:9] are only defined (by uProf) on Zen 2
+        if *self != Self::Zen2 && s.data3.res_lo() != 0 {
+            res.push(DecodeWarning::ReservedBits { 
+                reg: IbsOpReg::Data3, bits: s.data3.0 & 0x0000_0000_0000_1e00,
+            });
+        }
+        res
+    }
+}
+
+/// One of the IBS op data registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum IbsOpReg {
+    Data,
+    Data2,
+    Data3,
+}
+impl std::fmt::Display for IbsOpReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Data => write!(f, "IBS_OP_DATA"),
+            Self::Data2 => write!(f, "IBS_OP_DATA2"),
+            Self::Data3 => write!(f, "IBS_OP_DATA3"),
+        }
+    }
+}
+
+/// A reserved or undefined encoding found while decoding a sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum DecodeWarning {
+    /// Undefined OpMemWidth encoding
+    MemWidth(u8),
+    /// Reserved DataSrc encoding
+    DataSrc(u8),
+    /// Reserved L1 DTLB page size (since [IbsLayout::Zen3])
+    L1TlbPageSize,
+    /// Reserved bits are set in some register
+    ReservedBits { reg: IbsOpReg, bits: usize },
+}
+impl std::fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::MemWidth(x) => write!(f, "undefined memory width {:#x}", x),
+            Self::DataSrc(x) => write!(f, "reserved data source {:#x}", x),
+            Self::L1TlbPageSize => write!(f, "reserved L1 DTLB page size"),
+            Self::ReservedBits { reg, bits } => 
+                write!(f, "reserved bits {:#x} set in {}", bits, reg),
+        }
+    }
+}
+
+/// The list of [DecodeWarning] for a sample (see [IbsLayout::warnings]).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DecodeWarnings(Vec<DecodeWarning>);
+impl DecodeWarnings {
+    fn push(&mut self, w: DecodeWarning) {
+        self.0.push(w)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &DecodeWarning> {
+        self.0.iter()
+    }
+    pub fn contains(&self, w: &DecodeWarning) -> bool {
+        self.0.contains(w)
+    }
+}
+
+/// Return the family and model from the signature in CPUID Fn0000_0001 EAX.
+pub fn family_model(eax: u32) -> (usize, usize) {
+    let eax = eax as usize;
+    let base_family = (eax >> 8) & 0xf;
+    let base_model = (eax >> 4) & 0xf;
+    if base_family == 0xf {
+        let ext_family = (eax >> 20) & 0xff;
+        let ext_model = (eax >> 16) & 0xf;
+        (base_family + ext_family, (ext_model << 4) | base_model)
+    } else {
+        (base_family, base_model)
+    }
+}
+
+/// The contents of the IBS op data registers (IBS_OP_DATA, IBS_OP_DATA2 and
+/// IBS_OP_DATA3), decoded with some [IbsLayout].
+///
+/// Fields which aren't defined for a particular layout are `None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct IbsOpDecoded {
+    pub comp_to_ret_ctr: usize,
+    pub tag_to_ret_ctr: usize,
+    pub op_return: bool,
+    pub op_brn_taken: bool,
+    pub op_brn_misp: bool,
+    pub op_brn_ret: bool,
+    pub rip_invalid: bool,
+    pub op_brn_fuse: bool,
+    pub op_microcode: bool,
+
+    /// The data source (decoded with [NbDataSrc::from_ext] on 
+    /// [IbsLayout::Zen4])
+    pub data_src: NbDataSrc,
+    pub rmt_node: bool,
+    pub cache_hit_st: bool,
+
+    pub ld_op: bool,
+    pub st_op: bool,
+    pub dc_l1tlb_miss: bool,
+    pub dc_l2tlb_miss: bool,
+    /// The L1 DTLB hit a 2M page (only on [IbsLayout::Zen2])
+    pub dc_l1tlb_hit_2m: Option<bool>,
+    /// The L1 DTLB hit a 1G page (only on [IbsLayout::Zen2])
+    pub dc_l1tlb_hit_1g: Option<bool>,
+    /// Page size of the L1 DTLB entry (since [IbsLayout::Zen3])
+    pub dc_l1tlb_pg_sz: Option<IbsPageSize>,
+    pub dc_l2tlb_hit_2m: bool,
+    pub dc_miss: bool,
+    pub dc_mis_acc: bool,
+    /// Bank conflict on a load (seemingly defined by uProf, only on
+    /// [IbsLayout::Zen2])
+    pub bank_conf_ld: Option<bool>,
+    /// Bank conflict on a store (seemingly defined by uProf, only on
+    /// [IbsLayout::Zen2])
+    pub bank_conf_st: Option<bool>,
+    /// Data was forwarded from a store (seemingly defined by uProf, only on
+    /// [IbsLayout::Zen2])
+    pub forwarded: Option<bool>,
+    /// The op was cancelled (seemingly defined by uProf, only on
+    /// [IbsLayout::Zen2])
+    pub cancelled: Option<bool>,
+    pub dc_wc_mem_acc: bool,
+    pub dc_uc_mem_acc: bool,
+    pub dc_locked_op: bool,
+    pub dc_miss_no_mab_alloc: bool,
+    pub dc_lin_addr_valid: bool,
+    pub dc_phy_addr_valid: bool,
+    pub dc_l2tlb_hit_1g: bool,
+    pub dc_l2_miss: bool,
+    pub sw_pf: bool,
+    pub op_mem_width: IbsMemWidth,
+    pub op_dc_miss_open_mem_reqs: usize,
+    pub dc_miss_lat: usize,
+    pub tlb_refill_lat: usize,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layout::*;
+
+    #[test]
+    fn detect_layout() {
+        // Ryzen 7 3950X, Ryzen 5 PRO 5650GE
+        assert_eq!(family_model(0x0087_0f10), (0x17, 0x71));
+        assert_eq!(family_model(0x00a5_0f00), (0x19, 0x50));
+        assert_eq!(IbsLayout::from_family_model(0x17, 0x71), Some(IbsLayout::Zen2));
+        assert_eq!(IbsLayout::from_family_model(0x19, 0x50), Some(IbsLayout::Zen3));
+        assert_eq!(IbsLayout::from_family_model(0x19, 0x61), Some(IbsLayout::Zen4));
+        assert_eq!(IbsLayout::from_family_model(0x15, 0x02), None);
+
+        // Unlisted models and newer families use the most-recent layout
+        assert_eq!(IbsLayout::from_family_model(0x19, 0x90), Some(IbsLayout::Zen4));
+        assert_eq!(IbsLayout::from_family_model(0x1a, 0x02), Some(IbsLayout::LATEST));
+    }
+
+    #[test]
+    fn decode_l1tlb_pg_sz() {
+        let s = Sample {
+            data3: IbsOpData3(0x0000_0000_0104_0031),
+            ..Default::default()
+        };
+        let zen2 = IbsLayout::Zen2.decode(&s);
+        assert_eq!(zen2.dc_l1tlb_hit_2m, Some(true));
+        assert_eq!(zen2.dc_l1tlb_hit_1g, Some(true));
+        assert_eq!(zen2.dc_l1tlb_pg_sz, None);
+        assert!(zen2.bank_conf_ld.is_some());
+
+        let zen3 = IbsLayout::Zen3.decode(&s);
+        assert_eq!(zen3.dc_l1tlb_hit_2m, None);
+        assert_eq!(zen3.dc_l1tlb_pg_sz, Some(IbsPageSize::Reserved));
+        assert_eq!(zen3.bank_conf_ld, None);
+        assert!(zen3.ld_op && zen3.dc_phy_addr_valid);
+        assert_eq!(zen3.op_mem_width, IbsMemWidth::Qword);
+
+        let s = Sample { data3: IbsOpData3(0x20), ..Default::default() };
+        assert_eq!(IbsLayout::Zen4.decode(&s).dc_l1tlb_pg_sz, Some(IbsPageSize::Page1G));
+    }
+
+    #[test]
+    fn decode_data_src() {
+        // DataSrcHi=1, DataSrcLo=0 (extension memory on Zen 4)
+        let s = Sample { data2: IbsOpData2(0x40), ..Default::default() };
+        assert_eq!(IbsLayout::Zen3.decode(&s).data_src, NbDataSrc::Invalid);
+        assert_eq!(IbsLayout::Zen4.decode(&s).data_src, NbDataSrc::ExtMem);
+
+        let s = Sample { data2: IbsOpData2(0x02), ..Default::default() };
+        assert_eq!(IbsLayout::Zen2.decode(&s).data_src, NbDataSrc::Cache);
+        assert_eq!(IbsLayout::Zen4.decode(&s).data_src, NbDataSrc::NearCcx);
+        assert_eq!(NbDataSrc::from(4), NbDataSrc::RemoteCache);
+        assert_eq!(NbDataSrc::from(7), NbDataSrc::Io);
+        assert_eq!(NbDataSrc::from_ext(0x1f), NbDataSrc::Reserved(0x1f));
+    }
+
+    #[test]
+    fn decode_undefined_encodings() {
+        // OpMemWidth=0xf
+        let s = Sample { data3: IbsOpData3(0x0000_0000_03c0_0001), ..Default::default() };
+        assert_eq!(s.data3.op_mem_width(), IbsMemWidth::Unknown(0xf));
+        assert_eq!(s.data3.op_mem_width().bits(), None);
+        assert!(format!("{:?}", s).contains("Unknown(15)"));
+        assert_eq!(trace::LdstProps::from_sample(&s, IbsLayout::Zen2).width, 0);
+        let w = IbsLayout::Zen2.warnings(&s);
+        assert_eq!(w.len(), 1);
+        assert!(w.contains(&DecodeWarning::MemWidth(0xf)));
+
+        // Reserved page size is only a warning since Zen 3
+        let s = SampleBuilder::new()
+            .with_load(IbsMemWidth::Byte)
+            .with_l1tlb_pg_sz(IbsPageSize::Reserved)
+            .build();
+        assert!(IbsLayout::Zen2.warnings(&s).is_empty());
+        assert!(IbsLayout::Zen3.warnings(&s).contains(&DecodeWarning::L1TlbPageSize));
+
+        // DataSrcHi is reserved before Zen 4
+        let s = Sample { data2: IbsOpData2(0x41), ..Default::default() };
+        let w = IbsLayout::Zen3.warnings(&s);
+        assert!(w.contains(&DecodeWarning::DataSrc(1)));
+        assert!(w.contains(&DecodeWarning::ReservedBits { 
+            reg: IbsOpReg::Data2, bits: 0x40 
+        }));
+        assert!(IbsLayout::Zen4.warnings(&s).contains(&DecodeWarning::DataSrc(9)));
+        let s = Sample { data2: IbsOpData2(0x40), ..Default::default() };
+        assert!(IbsLayout::Zen4.warnings(&s).is_empty());
+
+        // Bits [12:9] of DATA3 are reserved since Zen 3
+        let s = Sample { data3: IbsOpData3(0x0000_0000_0000_0a01), ..Default::default() };
+        assert!(IbsLayout::Zen2.warnings(&s).is_empty());
+        assert_eq!(IbsLayout::Zen3.warnings(&s).iter().next(), 
+            Some(&DecodeWarning::ReservedBits { reg: IbsOpReg::Data3, bits: 0xa00 })
+        );
+        assert_eq!(IbsLayout::Zen4.warnings(&s).len(), 1);
+
+        let s = Sample { data: IbsOpData(0x8000_0000_0000_0000), ..Default::default() };
+        assert_eq!(IbsLayout::Zen4.warnings(&s).iter().next(), 
+            Some(&DecodeWarning::ReservedBits { 
+                reg: IbsOpReg::Data, bits: 0x8000_0000_0000_0000,
+            })
+        );
+        assert_eq!(IbsLayout::Zen4.warnings(&s).iter().next().unwrap().to_string(),
+            "reserved bits 0x8000000000000000 set in IBS_OP_DATA");
+        assert_eq!(IbsPageSize::from(0x7), IbsPageSize::Reserved);
+    }
+
+    #[test]
+    fn warnings_json() {
+        let s = Sample { 
+            data: IbsOpData(0x8000_0000_0000_0000), 
+            data2: IbsOpData2(0x41),
+            ..Default::default() 
+        };
+        let w = IbsLayout::Zen3.warnings(&s);
+        assert_eq!(w.len(), 3);
+        let json = serde_json::to_string(&w).unwrap();
+        let res: DecodeWarnings = serde_json::from_str(&json).unwrap();
+        assert_eq!(res, w);
+    }
+}
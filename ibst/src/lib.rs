@@ -11,16 +11,72 @@ pub mod ioctl;
 pub mod analysis;
 pub mod msr; 
 pub mod trace; 
-
+pub mod error;
+pub mod device;
+pub mod backend;
+pub mod archive;
+pub mod buffer;
+pub mod stream;
+pub mod info;
+pub mod regs;
+pub mod sampling;
+pub mod fetch;
+pub mod sweep;
+pub mod batch;
+pub mod stats;
+pub mod record;
+pub mod layout;
+pub mod builder;
+pub mod decoded;
+
+pub use error::Error;
+pub use device::IbstraceDevice;
+pub use backend::{ MeasurementBackend, MockBackend };
+pub use archive::{ RecordingBackend, ReplayBackend };
+pub use buffer::SampleBuffer;
+pub use stream::SampleStream;
+pub use info::IbstraceInfo;
+pub use regs::{ MeasureOutcome, Registers };
+pub use sampling::{ CountMode, SamplingConfig };
+pub use fetch::{ FetchConfig, FetchOutcome, FetchSample };
+pub use sweep::SweepOutcome;
+pub use batch::{ BatchFault, BatchOutcome };
+pub use stats::SampleStats;
+pub use record::{ SampleFormat, SampleMeta };
+pub use layout::{ DecodeWarning, DecodeWarnings, IbsLayout, IbsOpDecoded, IbsOpReg };
+pub use builder::SampleBuilder;
+pub use decoded::DecodedSample;
+
+use std::convert::TryInto;
 use std::hash::{Hash, Hasher};
 
+/// Encode a list of 64-bit words as little-endian bytes (ie. for a struct
+/// shared with the kernel module).
+///
+/// Panics if `N` is not the size of the words (in bytes).
+pub(crate) fn words_to_le_bytes<const N: usize>(words: &[usize]) -> [u8; N] {
+    assert_eq!(words.len() * 8, N);
+    let mut res = [0u8; N];
+    for (chunk, word) in res.chunks_exact_mut(8).zip(words.iter()) {
+        chunk.copy_from_slice(&(*word as u64).to_le_bytes());
+    }
+    res
+}
+
+/// Decode little-endian bytes into 64-bit words.
+pub(crate) fn le_bytes_to_words(bytes: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    bytes.chunks_exact(8).map(|chunk| {
+        u64::from_le_bytes(chunk.try_into().unwrap()) as usize
+    })
+}
+
 /// A sample taken by the `ibstrace` kernel module.
 ///
-/// WARNING: This struct must mirror the original definition in C code, see 
-/// `ibstrace/ibstrace.h` in the source code for the `ibstrace` kernel module.
+/// WARNING: The IBS register fields must mirror the original definition in 
+/// C code, see `struct sample` in `include/ibstrace.h`. Records in the sample
+/// buffer are decoded with [Sample::from_record] (see [SampleFormat]).
 #[derive(Clone, Default, Ord, PartialOrd)]
-#[derive(serde::Serialize)]
-#[repr(C)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct Sample {
     /// IBS OP sampling status register (IBS_OP_CTL).
     pub ctl:   ibs::IbsOpCtl, 
@@ -38,6 +94,9 @@ pub struct Sample {
     pub phyad: usize,
     /// Sampled branch target address (BP_IBSTGT_RIP).
     pub tgt_rip: usize,
+    /// Metadata recorded by the NMI handler (only for [SampleFormat::V2]).
+    /// This is not considered when comparing samples.
+    pub meta: Option<SampleMeta>,
 }
 impl PartialEq for Sample {
     fn eq(&self, other: &Self) -> bool {
@@ -64,6 +123,39 @@ impl Hash for Sample {
     }
 }
 
+impl Sample {
+    /// The size of the IBS register fields in a sample record, ie. the size 
+    /// of a [SampleFormat::V1] record (in bytes).
+    pub const SIZE: usize = 8 * 8;
+
+    /// Return the raw little-endian representation of this sample (matching 
+    /// `struct sample` in the kernel module).
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        words_to_le_bytes(&[
+            self.ctl.0, self.rip, self.data.0, self.data2.0, self.data3.0,
+            self.linad, self.phyad, self.tgt_rip,
+        ])
+    }
+
+    /// Create a sample from its raw little-endian representation (without 
+    /// any [SampleMeta]).
+    pub fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut words = le_bytes_to_words(bytes);
+        let mut next = || words.next().unwrap();
+        Self {
+            ctl: ibs::IbsOpCtl(next()),
+            rip: next(),
+            data: ibs::IbsOpData(next()),
+            data2: ibs::IbsOpData2(next()),
+            data3: ibs::IbsOpData3(next()),
+            linad: next(),
+            phyad: next(),
+            tgt_rip: next(),
+            meta: None,
+        }
+    }
+}
+
 impl std::fmt::Debug for Sample {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         fmt.debug_struct("Sample")
@@ -78,158 +170,7 @@ impl std::fmt::Debug for Sample {
             .field("lin", &self.linad)
             .field("phy", &self.phyad)
             .field("width", &self.data3.op_mem_width())
+            .field("meta", &self.meta)
             .finish()
     }
 }
-
-
-nix::ioctl_write_ptr_bad! {
-    /// Submit code-to-be-measured to the kernel module. 
-    /// Takes a pointer to a [UserBuf] describing the input buffer.
-    ibstrace_write, ioctl::CMD_WRITE, ioctl::UserBuf
-}
-
-nix::ioctl_write_ptr_bad! {
-    /// Execute submitted user code in the "precise" environment, collecting
-    /// a single sample of a particular micro-op. 
-    /// Takes a pointer to a [PreciseArgs] describing the arguments.
-    ibstrace_precise, ioctl::CMD_PRECISE, ioctl::PreciseArgs
-}
-
-nix::ioctl_none_bad! {
-    /// Execute the submitted user code and collect samples.
-    ibstrace_measure, ioctl::CMD_MEASURE
-}
-
-nix::ioctl_none_bad! {
-    /// Return the number of currently-collected samples.
-    ibstrace_samples, ioctl::CMD_SAMPLES
-}
-
-nix::ioctl_none_bad! {
-    /// Return the maximum number of entries in the sample buffer.
-    ibstrace_capacity, ioctl::CMD_CAPACITY
-}
-
-
-/// Try to get a file descriptor for the ibstrace character device.
-pub fn ibstrace_open() -> Result<i32, &'static str> {
-    use nix::sys::stat::Mode;
-    use nix::fcntl::{ open, OFlag };
-    use nix::errno::Errno;
-
-    match open(ioctl::IBSTRACE_CHARDEV, OFlag::O_RDWR, Mode::S_IRWXU) {
-        Ok(fd) => Ok(fd),
-        Err(e) => match e {
-            Errno::ENOENT => Err("Kernel module not loaded?"),
-            Errno::EACCES => Err("Permission denied - are you root?"),
-            _ => panic!("{}", e),
-        }
-    }
-}
-
-/// Read sample data back from the ibstrace character device.
-pub unsafe fn ibstrace_read(fd: i32) -> Result<Box<[u8]>, &'static str> {
-    use nix::unistd::read;
-    let mut buf: Vec<u8>;
-
-    // NOTE: Can I just use stat() to resolve the length of data available in 
-    // the character device somehow? Instead of issuing more ioctls()?
-
-    let samples = ibstrace_samples(fd).unwrap() as usize;
-    let bytes = samples * std::mem::size_of::<Sample>();
-    buf = vec![0; bytes];
-
-    match read(fd, &mut buf){ 
-        Ok(res) => {
-            if res != bytes {
-                return Err("Read unexpected number of bytes");
-            } else {
-                return Ok(buf.into_boxed_slice());
-            }
-        },
-        Err(e) => panic!("{}", e),
-    }
-}
-
-/// Close the file descriptor bound to the ibstrace character device.
-pub fn ibstrace_close(fd: i32) {
-    use nix::unistd::close;
-    match close(fd) {
-        Ok(_) => {},
-        Err(e) => panic!("{}", e),
-    }
-}
-
-/// Return the base address of the code buffer.
-///
-/// NOTE: This is not very pretty, but I didn't want to add more ioctls.
-pub fn get_base_address() -> Result<usize, &'static str> {
-    use std::fs::read_to_string;
-    match read_to_string("/sys/kernel/debug/ibstrace/code_buf") {
-        Ok(s) => {
-            let x = s[2..].strip_suffix("\n").unwrap();
-            Ok(usize::from_str_radix(x, 16).unwrap())
-        }
-        Err(e) => panic!("Couldn't read ibstrace base address? - {}", e),
-    }
-}
-
-/// Upload and sample user code, returning a [Box] of [Sample] data.
-pub fn measure(fd: i32, msg: &ioctl::UserBuf) -> Box<[Sample]> {
-    unsafe { 
-        match ibstrace_write(fd, msg as *const ioctl::UserBuf) {
-            Ok(res) => if res < 0 { 
-                panic!("write failed with {}", res);
-            },
-            Err(e) => panic!("{}", e),
-        }
-        match ibstrace_measure(fd) { 
-            Ok(res) => if res < 0 { 
-                panic!("measure failed with {}", res);
-            },
-            Err(e) => panic!("{}", e),
-        }
-        let data = match ibstrace_read(fd) {
-            Ok(buf) => buf,
-            Err(e) => panic!("{}", e),
-        };
-
-        std::slice::from_raw_parts(
-            data.as_ptr() as *mut Sample,
-            data.len() / std::mem::size_of::<Sample>()
-        ).to_owned().into_boxed_slice()
-    }
-}
-
-/// Upload and sample a particular micro-op in user code, returning a [Box] 
-/// of [Sample] data.
-pub fn measure_precise(fd: i32, msg: &ioctl::UserBuf, arg: &ioctl::PreciseArgs) 
-    -> Box<[Sample]> 
-{
-    unsafe { 
-        match ibstrace_write(fd, msg as *const ioctl::UserBuf) {
-            Ok(res) => if res < 0 { 
-                panic!("write failed with {}", res);
-            },
-            Err(e) => panic!("{}", e),
-        }
-        match ibstrace_precise(fd, arg as *const ioctl::PreciseArgs) {
-            Ok(res) => if res < 0 { 
-                panic!("precise measurement failed with {}", res);
-            },
-            Err(e) => panic!("{}", e),
-        }
-        let data = match ibstrace_read(fd) {
-            Ok(buf) => buf,
-            Err(e) => panic!("{}", e),
-        };
-
-        std::slice::from_raw_parts(
-            data.as_ptr() as *mut Sample,
-            data.len() / std::mem::size_of::<Sample>()
-        ).to_owned().into_boxed_slice()
-    }
-}
-
-
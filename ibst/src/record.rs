@@ -0,0 +1,180 @@
+//! Versioned sample records.
+//!
+//! Older versions of the kernel module store a bare `struct sample` for each
+//! sample ([SampleFormat::V1]). Since version 2, each record begins with a
+//! header filled in by the NMI handler (see [SampleMeta]), followed by the
+//! same sample data ([SampleFormat::V2]).
+
+use std::convert::TryInto;
+use std::ops::Range;
+
+use crate::*;
+
+/// The format of records in the sample buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SampleFormat {
+    /// A bare `struct sample` (only the IBS registers)
+    #[default]
+    V1,
+    /// A `struct sample_v2` (a header with [SampleMeta], then the IBS
+    /// registers)
+    V2,
+}
+impl SampleFormat {
+    /// The most-recent record format.
+    pub const LATEST: Self = Self::V2;
+
+    /// The size of the header in a [SampleFormat::V2] record (in bytes).
+    const HEADER_SIZE: usize = 4 * 8;
+
+    /// The size of the largest record in any format (in bytes).
+    pub(crate) const MAX_RECORD_SIZE: usize = Self::HEADER_SIZE + Sample::SIZE;
+
+    /// Return the format for a particular record version.
+    pub fn from_version(version: usize) -> Option<Self> {
+        match version {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            _ => None,
+        }
+    }
+
+    /// Return the record version for this format.
+    pub fn version(&self) -> usize {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+        }
+    }
+
+    /// Return the size of a record in this format (in bytes).
+    pub fn record_size(&self) -> usize {
+        match self {
+            Self::V1 => Sample::SIZE,
+            Self::V2 => Self::HEADER_SIZE + Sample::SIZE,
+        }
+    }
+}
+
+/// Metadata recorded by the NMI handler for each sample (since
+/// [SampleFormat::V2]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SampleMeta {
+    /// Sequence number, starting from zero for each measurement.
+    ///
+    /// Dropped samples also consume a sequence number, so gaps indicate
+    /// that samples were lost (see [missing_seqs]).
+    pub seq: usize,
+    /// Timestamp counter value when the NMI was handled
+    pub tsc: usize,
+    /// Instruction pointer interrupted by the NMI
+    pub intr_rip: usize,
+}
+
+impl Sample {
+    /// Decode a sample from a record in a particular [SampleFormat].
+    ///
+    /// [SampleFormat::V2] records with an unexpected version (ie. unused
+    /// records, which are left zeroed) decode without [SampleMeta].
+    ///
+    /// Panics if `bytes` is not exactly [SampleFormat::record_size] bytes.
+    pub fn from_record(format: SampleFormat, bytes: &[u8]) -> Self {
+        assert_eq!(bytes.len(), format.record_size(), "Invalid record size");
+        match format {
+            SampleFormat::V1 => Self::from_le_bytes(bytes.try_into().unwrap()),
+            SampleFormat::V2 => {
+                let (header, data) = bytes.split_at(SampleFormat::HEADER_SIZE);
+                let word: Vec<usize> = le_bytes_to_words(header).collect();
+                let mut res = Self::from_le_bytes(data.try_into().unwrap());
+                if word[0] == SampleFormat::V2.version() {
+                    res.meta = Some(SampleMeta {
+                        seq: word[1], tsc: word[2], intr_rip: word[3]
+                    });
+                }
+                res
+            },
+        }
+    }
+
+    /// Encode this sample as a record in a particular [SampleFormat].
+    ///
+    /// [SampleMeta] is discarded for [SampleFormat::V1].
+    pub fn to_record(&self, format: SampleFormat) -> Vec<u8> {
+        let mut res = Vec::with_capacity(format.record_size());
+        if format == SampleFormat::V2 {
+            let header = match self.meta {
+                Some(m) => [format.version(), m.seq, m.tsc, m.intr_rip],
+                None => [0; 4],
+            };
+            let header: [u8; SampleFormat::HEADER_SIZE] =
+                words_to_le_bytes(&header);
+            res.extend_from_slice(&header);
+        }
+        res.extend_from_slice(&self.to_le_bytes());
+        res
+    }
+}
+
+/// Return the ranges of sequence numbers missing between samples (ie.
+/// samples which were taken but not stored).
+///
+/// Samples are expected to be in the order they were collected. Samples
+/// without [SampleMeta] are ignored.
+pub fn missing_seqs(samples: &[Sample]) -> Vec<Range<usize>> {
+    let mut res = Vec::new();
+    let mut next = 0;
+    for meta in samples.iter().filter_map(|s| s.meta) {
+        if meta.seq > next {
+            res.push(next..meta.seq);
+        }
+        next = next.max(meta.seq + 1);
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ibs::*;
+    use crate::record::*;
+
+    #[test]
+    fn decode_v1_and_v2() {
+        let sample = SampleBuilder::new()
+            .with_ctl(IbsOpCtl::new()
+                .with_max_cnt(0x1000)
+                .with_cur_cnt(0x100)
+                .with_val(true))
+            .with_rip(0xffff_ffff_c000_0000)
+            .with_phyad(0x1000)
+            .with_meta(SampleMeta { seq: 7, tsc: 0x1234_5678, intr_rip: 0x1001 })
+            .build();
+
+        let v1 = sample.to_record(SampleFormat::V1);
+        assert_eq!(v1.len(), 64);
+        assert_eq!(Sample::from_record(SampleFormat::V1, &v1).meta, None);
+
+        let v2 = sample.to_record(SampleFormat::V2);
+        assert_eq!(v2.len(), 96);
+        assert_eq!(&v2[..8], &2u64.to_le_bytes());
+        let res = Sample::from_record(SampleFormat::V2, &v2);
+        assert_eq!(res, sample);
+        assert_eq!(res.meta, sample.meta);
+        assert_eq!(res.ctl.0, sample.ctl.0);
+
+        // Unused records are zeroed
+        let res = Sample::from_record(SampleFormat::V2, &[0u8; 96]);
+        assert_eq!(res.meta, None);
+    }
+
+    #[test]
+    fn detect_gaps() {
+        let samples: Vec<Sample> = [0, 1, 4, 5, 9].iter().map(|&seq| Sample {
+            meta: Some(SampleMeta { seq, ..Default::default() }),
+            ..Default::default()
+        }).collect();
+        assert_eq!(missing_seqs(&samples), [2..4, 6..9]);
+        assert!(missing_seqs(&samples[..2]).is_empty());
+        assert_eq!(missing_seqs(&samples[2..]), [0..4, 6..9]);
+    }
+}
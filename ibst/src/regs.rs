@@ -0,0 +1,109 @@
+//! Register state and return values from measured code.
+
+use crate::*;
+
+/// Register state captured after measured code returns to the trampoline.
+///
+/// WARNING: This struct must mirror the original definition in C code, see
+/// `struct ibstrace_regs` in `include/ibstrace.h`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: usize,
+    pub rbx: usize,
+    pub rcx: usize,
+    pub rdx: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    pub rbp: usize,
+    pub rsp: usize,
+    pub r8: usize,
+    pub r9: usize,
+    pub r10: usize,
+    pub r11: usize,
+    pub r12: usize,
+    pub r13: usize,
+    pub r14: usize,
+    pub r15: usize,
+    pub rflags: usize,
+}
+
+const _: () = assert!(std::mem::size_of::<Registers>() == 17 * 8);
+
+impl Registers {
+    /// The size of a register snapshot (in bytes).
+    pub const SIZE: usize = std::mem::size_of::<Registers>();
+
+    /// Return the raw little-endian representation of these registers
+    /// (matching `struct ibstrace_regs` in the kernel module).
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        words_to_le_bytes(&[
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi,
+            self.rbp, self.rsp, self.r8, self.r9, self.r10, self.r11,
+            self.r12, self.r13, self.r14, self.r15, self.rflags,
+        ])
+    }
+
+    /// Create a register snapshot from its raw little-endian representation.
+    pub fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut words = le_bytes_to_words(bytes);
+        let mut next = || words.next().unwrap();
+        Self {
+            rax: next(), rbx: next(), rcx: next(), rdx: next(),
+            rsi: next(), rdi: next(), rbp: next(), rsp: next(),
+            r8: next(), r9: next(), r10: next(), r11: next(),
+            r12: next(), r13: next(), r14: next(), r15: next(),
+            rflags: next(),
+        }
+    }
+}
+
+/// The result of measuring user code.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MeasureOutcome {
+    /// Samples collected while running user code
+    pub samples: Box<[Sample]>,
+    /// The value returned by user code (in RAX)
+    pub return_value: usize,
+    /// Register state after user code returned
+    pub regs: Registers,
+    /// Statistics about the collected samples
+    pub stats: SampleStats,
+}
+impl MeasureOutcome {
+    /// Create a new outcome, taking the return value from RAX.
+    ///
+    /// This assumes that no samples were lost (see 
+    /// [MeasureOutcome::with_stats]).
+    pub fn new(samples: Box<[Sample]>, regs: Registers) -> Self {
+        let stats = SampleStats::new(samples.len());
+        Self { samples, return_value: regs.rax, regs, stats }
+    }
+
+    /// Use the statistics reported for this measurement.
+    pub fn with_stats(mut self, stats: SampleStats) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::regs::*;
+
+    #[test]
+    fn regs_le_bytes() {
+        let regs = Registers {
+            rax: 0x1122_3344_5566_7788, r15: 0xdead, rflags: 0x246,
+            ..Default::default()
+        };
+        let bytes = regs.to_le_bytes();
+        assert_eq!(&bytes[0..8], &0x1122_3344_5566_7788u64.to_le_bytes());
+        assert_eq!(&bytes[0x80..0x88], &0x246u64.to_le_bytes());
+        assert_eq!(Registers::from_le_bytes(&bytes), regs);
+
+        let json = serde_json::to_string(&regs).unwrap();
+        assert_eq!(serde_json::from_str::<Registers>(&json).unwrap(), regs);
+    }
+}
@@ -0,0 +1,299 @@
+//! Configuration for IBS op sampling.
+
+use crate::*;
+
+/// The event counted by the IBS op counter (IBS_OP_CTL.IbsOpCntCtl).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CountMode {
+    /// Count clock cycles
+    Cycles,
+    /// Count dispatched micro-ops
+    DispatchedOps,
+}
+
+/// Parameters used to sample user code with [MeasurementBackend::measure_with].
+///
+/// The defaults match the behavior of [MeasurementBackend::measure] (a new
+/// sample is tagged every 4096 dispatched micro-ops).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplingConfig {
+    /// Number of events between samples
+    max_count: usize,
+    /// The event being counted
+    count_mode: CountMode,
+    /// Randomize the initial value of the counter
+    randomize: bool,
+    /// Number of times to run user code before sampling
+    warmup: usize,
+    /// Only sample ops which miss in the L3 cache
+    l3_miss_only: bool,
+    /// Only sample loads with at least this latency (in cycles)
+    load_latency: Option<usize>,
+}
+impl SamplingConfig {
+    /// Default number of events between samples.
+    pub const DEFAULT_MAX_COUNT: usize = 0x0000_1000;
+
+    /// Minimum number of events between samples.
+    pub const MIN_MAX_COUNT: usize = 0x0000_0010;
+
+    /// Maximum number of events between samples.
+    pub const MAX_MAX_COUNT: usize = ibs::IbsOpCtl::MAX_MAX_CNT;
+
+    /// Granularity of the load latency threshold (in cycles).
+    pub const LOAD_LATENCY_STEP: usize = 128;
+
+    /// Maximum load latency threshold (in cycles).
+    pub const MAX_LOAD_LATENCY: usize = 2048;
+
+    pub fn new() -> Self {
+        Self {
+            max_count: Self::DEFAULT_MAX_COUNT,
+            count_mode: CountMode::DispatchedOps,
+            randomize: false,
+            warmup: 0,
+            l3_miss_only: false,
+            load_latency: None,
+        }
+    }
+
+    /// Set the number of events between samples.
+    ///
+    /// The bottom 4 bits of the counter are not programmable, so this must
+    /// be a multiple of 16 between [SamplingConfig::MIN_MAX_COUNT] and
+    /// [SamplingConfig::MAX_MAX_COUNT].
+    pub fn try_with_max_count(mut self, max_count: usize) -> Result<Self, Error> {
+        if !max_count.is_multiple_of(16) || 
+            !(Self::MIN_MAX_COUNT..=Self::MAX_MAX_COUNT).contains(&max_count)
+        {
+            return Err(Error::InvalidConfig { field: "max count", value: max_count });
+        }
+        self.max_count = max_count;
+        Ok(self)
+    }
+
+    /// Like [SamplingConfig::try_with_max_count], but panics on an invalid 
+    /// value.
+    pub fn with_max_count(self, max_count: usize) -> Self {
+        self.try_with_max_count(max_count).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set the event being counted.
+    pub fn with_count_mode(mut self, count_mode: CountMode) -> Self {
+        self.count_mode = count_mode;
+        self
+    }
+
+    /// Randomize the initial value of the counter before sampling.
+    pub fn with_randomize(mut self, randomize: bool) -> Self {
+        self.randomize = randomize;
+        self
+    }
+
+    /// Set the number of times to run user code (without sampling) before
+    /// user code is sampled.
+    pub fn with_warmup(mut self, warmup: usize) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Only sample ops which miss in the L3 cache (IbsOpL3MissOnly). 
+    ///
+    /// This is only supported on Zen 4 and later (otherwise, measurement 
+    /// fails with `EOPNOTSUPP`).
+    pub fn with_l3_miss_only(mut self, l3_miss_only: bool) -> Self {
+        self.l3_miss_only = l3_miss_only;
+        self
+    }
+
+    /// Only sample loads with a latency of at least `cycles` 
+    /// (IbsOpLdLatEn and IbsOpLdLatThrsh), or disable the filter with `None`. 
+    ///
+    /// The threshold must be a multiple of 
+    /// [SamplingConfig::LOAD_LATENCY_STEP] no larger than 
+    /// [SamplingConfig::MAX_LOAD_LATENCY]. This is only supported on parts 
+    /// which report load latency filtering in CPUID (otherwise, measurement 
+    /// fails with `EOPNOTSUPP`).
+    pub fn try_with_load_latency(mut self, cycles: Option<usize>) 
+        -> Result<Self, Error>
+    {
+        if let Some(cycles) = cycles {
+            if !cycles.is_multiple_of(Self::LOAD_LATENCY_STEP) || 
+                !(Self::LOAD_LATENCY_STEP..=Self::MAX_LOAD_LATENCY).contains(&cycles)
+            {
+                return Err(Error::InvalidConfig { 
+                    field: "load latency", value: cycles 
+                });
+            }
+        }
+        self.load_latency = cycles;
+        Ok(self)
+    }
+
+    /// Like [SamplingConfig::try_with_load_latency], but panics on an 
+    /// invalid value.
+    pub fn with_load_latency(self, cycles: Option<usize>) -> Self {
+        self.try_with_load_latency(cycles).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Return the number of events between samples.
+    pub fn max_count(&self) -> usize { self.max_count }
+
+    /// Return the event being counted.
+    pub fn count_mode(&self) -> CountMode { self.count_mode }
+
+    /// Return `true` if the counter is randomized before sampling.
+    pub fn randomize(&self) -> bool { self.randomize }
+
+    /// Return the number of times user code runs before sampling.
+    pub fn warmup(&self) -> usize { self.warmup }
+
+    /// Return `true` if only ops which miss in the L3 cache are sampled.
+    pub fn l3_miss_only(&self) -> bool { self.l3_miss_only }
+
+    /// Return the load latency threshold (in cycles), if any.
+    pub fn load_latency(&self) -> Option<usize> { self.load_latency }
+
+    /// Encode the counter configuration as a value for IBS_OP_CTL.
+    ///
+    /// This only includes IbsOpMaxCnt, IbsOpCntCtl and the filters 
+    /// (the kernel module is responsible for setting IbsOpEn and 
+    /// IbsOpCurCnt).
+    pub fn op_ctl(&self) -> ibs::IbsOpCtl {
+        let res = ibs::IbsOpCtl::new()
+            .with_max_cnt(self.max_count)
+            .with_cnt_ctl(self.count_mode == CountMode::DispatchedOps)
+            .with_l3_miss_only(self.l3_miss_only);
+        match self.load_latency {
+            Some(cycles) => res.with_ld_lat_en(true)
+                .with_ld_lat_thrsh(cycles / Self::LOAD_LATENCY_STEP - 1),
+            None => res,
+        }
+    }
+
+    /// Return the argument passed to [ioctl::CMD_MEASURE].
+    pub fn to_args(&self) -> ioctl::MeasureArgs {
+        let mut flags = 0;
+        if self.randomize {
+            flags |= ioctl::MeasureArgs::RANDOMIZE;
+        }
+        ioctl::MeasureArgs::new(self.op_ctl().0, flags, self.warmup)
+    }
+
+    /// Decode the argument passed to [ioctl::CMD_MEASURE].
+    pub fn from_args(args: &ioctl::MeasureArgs) -> Self {
+        let ctl = ibs::IbsOpCtl(args.op_ctl());
+        let count_mode = if ctl.cnt_ctl() {
+            CountMode::DispatchedOps
+        } else {
+            CountMode::Cycles
+        };
+        Self {
+            max_count: ctl.max_cnt(),
+            count_mode,
+            randomize: args.flags() & ioctl::MeasureArgs::RANDOMIZE != 0,
+            warmup: args.warmup(),
+            l3_miss_only: ctl.l3_miss_only(),
+            load_latency: ctl.ld_lat_en().then(|| ctl.ld_lat_threshold()),
+        }
+    }
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sampling::*;
+
+    #[test]
+    fn default_op_ctl() {
+        // Matches the value previously hardcoded in the trampoline
+        // (without IbsOpEn)
+        assert_eq!(SamplingConfig::default().op_ctl().0, 0x0008_0100);
+    }
+
+    #[test]
+    fn encode_op_ctl() {
+        let cfg = SamplingConfig::new()
+            .with_max_count(0x07ff_fff0)
+            .with_count_mode(CountMode::Cycles);
+        assert_eq!(cfg.op_ctl().0, 0x07f0_ffff);
+
+        let cfg = SamplingConfig::new().with_max_count(0x0012_3450);
+        assert_eq!(cfg.op_ctl().0, 0x0018_2345);
+
+        let cfg = SamplingConfig::new().with_max_count(0x10);
+        assert_eq!(cfg.op_ctl().0, 0x0008_0001);
+    }
+
+    #[test]
+    fn args_round_trip() {
+        for cfg in [
+            SamplingConfig::new(),
+            SamplingConfig::new().with_max_count(0x0540_0010).with_warmup(3),
+            SamplingConfig::new()
+                .with_count_mode(CountMode::Cycles)
+                .with_randomize(true),
+            SamplingConfig::new()
+                .with_l3_miss_only(true)
+                .with_load_latency(Some(2048)),
+        ] {
+            assert_eq!(SamplingConfig::from_args(&cfg.to_args()), cfg);
+        }
+        let args = SamplingConfig::new()
+            .with_randomize(true)
+            .with_warmup(5)
+            .to_args();
+        assert_eq!(args.flags(), ioctl::MeasureArgs::RANDOMIZE);
+        assert_eq!(args.warmup(), 5);
+    }
+
+    #[test]
+    fn encode_filters() {
+        let cfg = SamplingConfig::new()
+            .with_l3_miss_only(true)
+            .with_load_latency(Some(256));
+        let ctl = cfg.op_ctl();
+        assert_eq!(ctl.0, 0x8800_0000_0009_0100);
+        assert!(ctl.l3_miss_only() && ctl.ld_lat_en());
+        assert_eq!(ctl.ld_lat_threshold(), 256);
+        assert_eq!(cfg.with_load_latency(None).op_ctl().0, 0x0009_0100);
+    }
+
+    #[test]
+    #[should_panic]
+    fn load_latency_unaligned() {
+        let _ = SamplingConfig::new().with_load_latency(Some(200));
+    }
+
+    #[test]
+    #[should_panic]
+    fn max_count_unaligned() {
+        let _ = SamplingConfig::new().with_max_count(0x1001);
+    }
+
+    #[test]
+    fn invalid_config() {
+        for max_count in [0x1001, 0, SamplingConfig::MAX_MAX_COUNT + 0x10] {
+            assert_eq!(SamplingConfig::new().try_with_max_count(max_count), 
+                Err(Error::InvalidConfig { field: "max count", value: max_count })
+            );
+        }
+        for cycles in [0, 200, SamplingConfig::MAX_LOAD_LATENCY + 128] {
+            assert_eq!(SamplingConfig::new().try_with_load_latency(Some(cycles)),
+                Err(Error::InvalidConfig { field: "load latency", value: cycles })
+            );
+        }
+        let cfg = SamplingConfig::new()
+            .try_with_max_count(0x2000).unwrap()
+            .try_with_load_latency(Some(128)).unwrap();
+        assert_eq!(cfg, SamplingConfig::new()
+            .with_max_count(0x2000)
+            .with_load_latency(Some(128))
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Accounting for samples collected (and lost) by the kernel module.
+
+use crate::{ le_bytes_to_words, words_to_le_bytes };
+
+/// Statistics reported by the kernel module for a measurement.
+///
+/// WARNING: This struct must mirror the original definition in C code, see
+/// `struct ibstrace_stats` in `include/ibstrace.h`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[repr(C)]
+pub struct SampleStats {
+    /// Number of samples stored in the sample buffer
+    pub collected: usize,
+    /// Number of samples dropped because the sample buffer was full
+    pub dropped: usize,
+    /// Number of "hanging" NMIs (delivered after sampling was disabled)
+    /// which were rejected by the kernel module
+    pub spurious: usize,
+}
+
+const _: () = assert!(std::mem::size_of::<SampleStats>() == 3 * 8);
+
+impl SampleStats {
+    /// The size of an encoded [SampleStats] (in bytes).
+    pub const SIZE: usize = std::mem::size_of::<SampleStats>();
+
+    /// Statistics for a measurement where no samples were lost.
+    pub fn new(collected: usize) -> Self {
+        Self { collected, dropped: 0, spurious: 0 }
+    }
+
+    /// Returns `true` if the sample buffer overflowed (and the collected
+    /// samples are incomplete).
+    pub fn overflowed(&self) -> bool {
+        self.dropped != 0
+    }
+
+    /// Return the raw little-endian representation of these statistics.
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        words_to_le_bytes(&[self.collected, self.dropped, self.spurious])
+    }
+
+    /// Create statistics from their raw little-endian representation.
+    pub fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut words = le_bytes_to_words(bytes);
+        let mut next = || words.next().unwrap();
+        Self { collected: next(), dropped: next(), spurious: next() }
+    }
+}
+
+impl std::fmt::Display for SampleStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} samples collected, {} dropped, {} spurious NMIs",
+            self.collected, self.dropped, self.spurious
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use crate::stats::*;
+    use crate::analysis::*;
+    use crate::codegen::emit_msr_test;
+
+    #[test]
+    fn stats_le_bytes() {
+        let stats = SampleStats { collected: 0x40000, dropped: 3, spurious: 1 };
+        assert!(stats.overflowed());
+        assert_eq!(SampleStats::from_le_bytes(&stats.to_le_bytes()), stats);
+        assert!(!SampleStats::new(5).overflowed());
+
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(serde_json::from_str::<SampleStats>(&json).unwrap(), stats);
+    }
+
+    #[test]
+    fn mock_overflow() {
+        let mut dev = MockBackend::new().with_capacity(4)
+            .with_generator(|_| vec![Sample::default(); 6]);
+        let test = run_test(&mut dev, emit_msr_test(0x10, 1)).unwrap();
+        assert_eq!(test.result.len(), 4);
+        assert_eq!(test.stats, SampleStats { collected: 4, dropped: 2, spurious: 0 });
+        assert!(test.overflowed());
+
+        let tests = vec![emit_msr_test(0x10, 1), emit_msr_test(0x20, 1)];
+        let (res, fault) = run_batch(&mut dev, tests, &SamplingConfig::default())
+            .unwrap();
+        assert!(fault.is_none());
+        assert!(res.iter().all(|t| t.stats.dropped == 2));
+    }
+}
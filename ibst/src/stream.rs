@@ -0,0 +1,221 @@
+//! Lazily reading samples in fixed-size chunks.
+
+use std::io::{ ErrorKind, Read };
+
+use crate::*;
+
+/// An iterator which reads samples from some reader in fixed-size chunks.
+///
+/// Short reads are handled by retaining partial records until the rest of 
+/// the record has been read. Iteration stops at the end of the input or on 
+/// the first error. The error can be recovered with [SampleStream::finish] 
+/// after iteration, ie.
+///
+/// ```ignore
+/// let layout = dev.layout();
+/// let mut stream = dev.stream_samples();
+/// let accesses = get_uniq_accesses(&mut stream, tgt_rip, layout);
+/// stream.finish()?;
+/// ```
+pub struct SampleStream<R: Read> {
+    reader: R,
+    /// Buffer for raw sample data
+    buf: Box<[u8]>,
+    /// Offset to the next undecoded byte in the buffer
+    head: usize,
+    /// Offset to the end of valid data in the buffer
+    tail: usize,
+    /// The format of records in the input
+    format: SampleFormat,
+    /// The number of samples decoded so far
+    count: usize,
+    /// Set when we've reached the end of the input
+    done: bool,
+    /// The first error encountered while reading
+    error: Option<Error>,
+}
+impl<R: Read> SampleStream<R> {
+    /// Default number of samples read at a time.
+    pub const DEFAULT_CHUNK_SAMPLES: usize = 0x1000;
+
+    /// Read samples from `reader` in chunks of [Self::DEFAULT_CHUNK_SAMPLES].
+    pub fn new(reader: R) -> Self {
+        Self::with_chunk_samples(reader, Self::DEFAULT_CHUNK_SAMPLES)
+    }
+
+    /// Read samples from `reader` in chunks of `n` samples.
+    pub fn with_chunk_samples(reader: R, n: usize) -> Self {
+        assert!(n != 0, "Chunk size must be non-zero");
+        Self {
+            reader,
+            buf: vec![0u8; n * Sample::SIZE].into_boxed_slice(),
+            head: 0,
+            tail: 0,
+            format: SampleFormat::V1,
+            count: 0,
+            done: false,
+            error: None,
+        }
+    }
+
+    /// Decode records in a particular [SampleFormat] (instead of 
+    /// [SampleFormat::V1]). This must be used before reading any samples.
+    pub fn with_format(mut self, format: SampleFormat) -> Self {
+        let n = self.buf.len() / self.format.record_size();
+        self.buf = vec![0u8; n * format.record_size()].into_boxed_slice();
+        self.format = format;
+        self
+    }
+
+    /// Stop reading samples, returning the number of samples decoded or the
+    /// first error encountered.
+    pub fn finish(self) -> Result<usize, Error> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.count),
+        }
+    }
+
+    /// Refill the buffer, retaining any partial record.
+    fn fill(&mut self) -> Result<(), Error> {
+        self.buf.copy_within(self.head..self.tail, 0);
+        self.tail -= self.head;
+        self.head = 0;
+
+        let size = self.format.record_size();
+        while self.tail < size {
+            match self.reader.read(&mut self.buf[self.tail..]) {
+                Ok(0) => {
+                    self.done = true;
+                    if self.tail != 0 {
+                        return Err(Error::TruncatedSample { 
+                            len: self.count * size + self.tail,
+                            record_size: size,
+                        });
+                    }
+                    break;
+                },
+                Ok(n) => self.tail += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::StreamRead(e.into())),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for SampleStream<R> {
+    type Item = Sample;
+    fn next(&mut self) -> Option<Sample> {
+        if self.error.is_some() {
+            return None;
+        }
+        let size = self.format.record_size();
+        if self.tail - self.head < size {
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                self.error = Some(e);
+                return None;
+            }
+            if self.tail < size {
+                return None;
+            }
+        }
+        let record = &self.buf[self.head..self.head + size];
+        let sample = Sample::from_record(self.format, record);
+        self.head += size;
+        self.count += 1;
+        Some(sample)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use crate::stream::*;
+
+    /// Returns at most 'n' bytes from each read.
+    struct ShortReader<'a> { data: &'a [u8], n: usize }
+    impl Read for ShortReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.n.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn samples(n: usize) -> (Vec<Sample>, Vec<u8>) {
+        let samples: Vec<Sample> = (0..n).map(|i| Sample {
+            rip: 0x1000 + i,
+            linad: i,
+            ..Default::default()
+        }).collect();
+        let bytes = samples.iter().flat_map(|s| s.to_le_bytes().to_vec())
+            .collect();
+        (samples, bytes)
+    }
+
+    #[test]
+    fn short_reads() {
+        let (expected, bytes) = samples(37);
+        for n in [1, 7, 63, 64, 65, 1000] {
+            let reader = ShortReader { data: &bytes, n };
+            let mut stream = SampleStream::with_chunk_samples(reader, 4);
+            let res: Vec<Sample> = (&mut stream).collect();
+            assert_eq!(res, expected);
+            assert_eq!(stream.finish(), Ok(37));
+        }
+    }
+
+    #[test]
+    fn short_reads_v2() {
+        let (expected, _) = samples(9);
+        let bytes: Vec<u8> = expected.iter()
+            .flat_map(|s| s.to_record(SampleFormat::V2))
+            .collect();
+        let reader = ShortReader { data: &bytes, n: 50 };
+        let mut stream = SampleStream::with_chunk_samples(reader, 2)
+            .with_format(SampleFormat::V2);
+        let res: Vec<Sample> = (&mut stream).collect();
+        assert_eq!(res, expected);
+        assert_eq!(stream.finish(), Ok(9));
+    }
+
+    /// Fails every read with an error that doesn't come from the OS.
+    struct FailingReader;
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad"))
+        }
+    }
+
+    #[test]
+    fn failed_read() {
+        let mut stream = SampleStream::new(FailingReader);
+        assert_eq!((&mut stream).count(), 0);
+        let err = stream.finish().unwrap_err();
+        match &err {
+            Error::StreamRead(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+                assert_eq!(e.inner().to_string(), "bad");
+            },
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(err.errno(), None);
+    }
+
+    #[test]
+    fn truncated_input() {
+        let (expected, bytes) = samples(3);
+        let reader = ShortReader { data: &bytes[..bytes.len() - 1], n: 5 };
+        let mut stream = SampleStream::with_chunk_samples(reader, 2);
+        assert_eq!((&mut stream).count(), 2);
+        assert_eq!(stream.finish(), Err(Error::TruncatedSample { 
+            len: expected.len() * Sample::SIZE - 1, 
+            record_size: Sample::SIZE 
+        }));
+    }
+}
@@ -0,0 +1,102 @@
+//! Sampling a range of micro-ops with a single upload.
+
+use crate::*;
+
+/// The result of sampling a range of micro-ops in user code, see
+/// [MeasurementBackend::measure_sweep].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SweepOutcome {
+    /// The first counter offset in the sweep
+    pub first: usize,
+    /// The sample collected for each offset (in order), or `None` when no
+    /// sample was collected for an offset
+    pub samples: Box<[Option<Sample>]>,
+    /// Register state after user code returned for the last offset
+    pub regs: Registers,
+    /// Statistics about the collected samples
+    pub stats: SampleStats,
+}
+impl SweepOutcome {
+    /// Create a new outcome.
+    ///
+    /// This assumes that no samples were lost (see 
+    /// [SweepOutcome::with_stats]).
+    pub fn new(first: usize, samples: Box<[Option<Sample>]>, regs: Registers)
+        -> Self
+    {
+        let stats = SampleStats::new(samples.iter().flatten().count());
+        Self { first, samples, regs, stats }
+    }
+
+    /// Use the statistics reported for this sweep.
+    pub fn with_stats(mut self, stats: SampleStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Return the number of offsets in the sweep.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Returns `true` if the sweep didn't cover any offsets.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Return the sample collected for a particular offset.
+    pub fn sample(&self, offset: usize) -> Option<&Sample> {
+        let idx = offset.checked_sub(self.first)?;
+        self.samples.get(idx)?.as_ref()
+    }
+
+    /// Iterate over all collected samples along with their offsets.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Sample)> {
+        let first = self.first;
+        self.samples.iter().enumerate().filter_map(move |(idx, s)| {
+            s.as_ref().map(|s| (first + idx, s))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use crate::ioctl::{ PreciseArgs, SweepArgs };
+    use std::ops::RangeInclusive;
+
+    #[test]
+    fn sweep_mock() {
+        let mut dev = MockBackend::new().with_generator(|req| {
+            match req {
+                // Only even offsets produce a sample
+                backend::MockRequest::Precise { offset, .. } if offset % 2 == 0 => {
+                    vec![Sample { rip: *offset, ..Default::default() }]
+                },
+                _ => Vec::new(),
+            }
+        });
+        dev.upload(&[0xc3]).unwrap();
+        let res = dev.measure_sweep(&SweepArgs::new(0, 3..=8).unwrap()).unwrap();
+        assert_eq!(dev.measurements(), 6);
+        assert_eq!(res.len(), 6);
+        assert_eq!(res.sample(4).map(|s| s.rip), Some(4));
+        assert!(res.sample(5).is_none() && res.sample(9).is_none());
+        assert!(res.sample(2).is_none());
+        assert_eq!(res.iter().map(|(off, _)| off).collect::<Vec<_>>(), [4, 6, 8]);
+        assert_eq!(res.stats, SampleStats::new(3));
+
+        assert!(matches!(SweepArgs::new(0, RangeInclusive::new(8, 3)),
+            Err(Error::InvalidOffsets { first: 8, last: 3 })
+        ));
+        assert!(SweepArgs::new(0, 0..=ioctl::MAX_OFFSET + 1).is_err());
+        assert!(PreciseArgs::new(0, ioctl::MAX_OFFSET + 1).is_err());
+
+        // MAX_OFFSET is masked to offset 0 by the precise trampoline
+        assert!(SweepArgs::new(0, 0..=ioctl::MAX_OFFSET).is_err());
+        assert!(PreciseArgs::new(0, ioctl::MAX_OFFSET).is_err());
+        let args = SweepArgs::new(0, 0..=ioctl::MAX_OFFSET - 1).unwrap();
+        assert_eq!(args.count(), ioctl::MAX_OFFSET);
+        assert!(PreciseArgs::new(0, ioctl::MAX_OFFSET - 1).is_ok());
+    }
+}
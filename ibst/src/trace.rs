@@ -3,19 +3,21 @@ use std::ops::RangeInclusive;
 
 use crate::*;
 use crate::codegen::*;
+use crate::decoded::fmt_addr;
 use crate::ibs::*;
-use crate::analysis::run_precise_test;
-use crate::ioctl::PreciseArgs;
+use crate::ioctl::SweepArgs;
 
 use serde;
 use serde::Serialize;
 
 /// Properties of samples load/store ops. 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct LdstProps { 
-    pub lin: usize,
-    pub phy: usize,
+    /// Linear address (if valid)
+    pub lin: Option<usize>,
+    /// Physical address (if valid)
+    pub phy: Option<usize>,
     pub width: usize,
     pub locked: bool,
     pub uc: bool,
@@ -26,18 +28,22 @@ pub struct LdstProps {
     pub src: NbDataSrc
 }
 impl LdstProps { 
-    pub fn from_sample(s: &Sample) -> Self { 
+    pub fn from_sample(s: &Sample, layout: IbsLayout) -> Self { 
+        Self::from_decoded(&DecodedSample::new(s, layout))
+    }
+
+    pub fn from_decoded(d: &DecodedSample) -> Self { 
         Self { 
-            lin: s.linad,
-            phy: s.phyad,
-            ld: s.data3.ld_op(),
-            st: s.data3.st_op(),
-            width: s.data3.op_mem_width() as usize,
-            locked: s.data3.dc_locked_op(),
-            uc: s.data3.dc_uc_mem_acc(),
-            wc: s.data3.dc_wc_mem_acc(),
-            swpf: s.data3.sw_pf(),
-            src: s.data2.data_src(),
+            lin: d.linad,
+            phy: d.phyad,
+            ld: d.op.ld_op,
+            st: d.op.st_op,
+            width: d.op.op_mem_width.bits().unwrap_or(0),
+            locked: d.op.dc_locked_op,
+            uc: d.op.dc_uc_mem_acc,
+            wc: d.op.dc_wc_mem_acc,
+            swpf: d.op.sw_pf,
+            src: d.op.data_src,
         }
     }
 
@@ -57,18 +63,22 @@ impl LdstProps {
         let wc = if self.wc { "[uc]" } else { "" };
         let swpf = if self.swpf { "[swpf]" } else { "" };
         let src = match self.src { 
-            NbDataSrc::Dram => "[dram]",
-            NbDataSrc::Invalid => "",
-            NbDataSrc::Cache => "[cache]",
-            NbDataSrc::Other => "[other]",
-            NbDataSrc::Reserved1 => "[res1]",
-            NbDataSrc::Reserved4 => "[res4]",
-            NbDataSrc::Reserved5 => "[res5]",
-            NbDataSrc::Reserved6 => "[res6]",
+            NbDataSrc::Dram => "[dram]".to_string(),
+            NbDataSrc::Invalid => "".to_string(),
+            NbDataSrc::Cache => "[cache]".to_string(),
+            NbDataSrc::RemoteCache => "[rmtcache]".to_string(),
+            NbDataSrc::Io => "[io]".to_string(),
+            NbDataSrc::LocalCcx => "[lclccx]".to_string(),
+            NbDataSrc::NearCcx => "[nearccx]".to_string(),
+            NbDataSrc::FarCcx => "[farccx]".to_string(),
+            NbDataSrc::Pmem => "[pmem]".to_string(),
+            NbDataSrc::ExtMem => "[extmem]".to_string(),
+            NbDataSrc::PeerAgentMem => "[peermem]".to_string(),
+            NbDataSrc::Reserved(x) => format!("[res{}]", x),
         };
-        format!("{} {:3}b lin={:016x} phy={:016x} {}{}{}{}{}",
+        format!("{} {:3}b lin={} phy={} {}{}{}{}{}",
             self.mnemonic(),
-            self.width, self.lin, self.phy,
+            self.width, fmt_addr(self.lin), fmt_addr(self.phy),
             locked,uc,wc,swpf,src
         )
     }
@@ -76,24 +86,29 @@ impl LdstProps {
 
 /// Properties of sampled branch ops. 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct BrnProps {
     pub misp: bool,
     pub retired: bool,
     pub taken: bool,
     pub retrn: bool,
     pub fused: bool,
-    pub tgt_rip: usize,
+    /// Branch target address (only valid for retired branches)
+    pub tgt_rip: Option<usize>,
 }
 impl BrnProps {
-    pub fn from_sample(s: &Sample) -> Self { 
+    pub fn from_sample(s: &Sample, layout: IbsLayout) -> Self { 
+        Self::from_decoded(&DecodedSample::new(s, layout))
+    }
+
+    pub fn from_decoded(d: &DecodedSample) -> Self { 
         Self { 
-            tgt_rip: s.tgt_rip,
-            misp: s.data.op_brn_misp(),
-            retired: s.data.op_brn_ret(),
-            taken: s.data.op_brn_taken(),
-            retrn: s.data.op_return(),
-            fused: s.data.op_brn_fuse()
+            tgt_rip: d.tgt_rip,
+            misp: d.op.op_brn_misp,
+            retired: d.op.op_brn_ret,
+            taken: d.op.op_brn_taken,
+            retrn: d.op.op_return,
+            fused: d.op.op_brn_fuse,
         }
     }
 
@@ -114,18 +129,21 @@ impl BrnProps {
         let dir = if self.taken { "[t]" } else { "[nt]" };
         let retrn = if self.retrn { "[return]" } else { "" };
         let fused = if self.retrn { "[fused]" } else { "" };
-        format!("{} tgt={:016x} {}{}{}{}{}",
-            self.mnemonic(), self.tgt_rip, miss, ret, dir, retrn, fused
+        format!("{} tgt={} {}{}{}{}{}",
+            self.mnemonic(), fmt_addr(self.tgt_rip), miss, ret, dir, retrn, fused
         )
     }
 }
 
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct TraceEntry {
     pub offset: usize,
-    pub rip: usize,
+    /// The raw sample
+    pub raw: Sample,
+    /// Instruction pointer (unless the hardware marked it as invalid)
+    pub rip: Option<usize>,
     pub tag_to_retire: usize,
     pub complete_to_retire: usize,
     pub ucode: bool,
@@ -133,41 +151,29 @@ pub struct TraceEntry {
     pub brn_props: Option<BrnProps>,
 }
 impl TraceEntry { 
-    pub fn from_sample(offset: usize, s: &Sample) -> Self { 
+    pub fn from_sample(offset: usize, s: &Sample, layout: IbsLayout) -> Self { 
+        let d = DecodedSample::new(s, layout);
 
-        let rip = s.rip;
-        let tag_to_retire = s.data.tag_to_ret_ctr();
-        let complete_to_retire = s.data.comp_to_ret_ctr();
-        let ucode = s.data.op_microcode();
-
-        let has_ldst = (
-            (s.data3.st_op() || s.data3.ld_op())
-        );
-
-        let has_brn = (
-            (s.data.op_brn_fuse() ||
-            s.data.op_brn_ret() ||
-            s.data.op_return() ||
-            s.data.op_brn_misp() ||
-            s.data.op_brn_taken())
-        );
+        let rip = d.rip;
+        let tag_to_retire = d.op.tag_to_ret_ctr;
+        let complete_to_retire = d.op.comp_to_ret_ctr;
+        let ucode = d.op.op_microcode;
 
-
-        let ldst_props = if has_ldst {
-            Some(LdstProps::from_sample(s))
+        let ldst_props = if d.is_ldst() {
+            Some(LdstProps::from_decoded(&d))
         } else { 
             None
         };
 
-        let brn_props = if has_brn {
-            Some(BrnProps::from_sample(s))
+        let brn_props = if d.is_brn() {
+            Some(BrnProps::from_decoded(&d))
         } else { 
             None
         };
 
-
         Self {
             offset,
+            raw: s.clone(),
             rip,
             tag_to_retire,
             complete_to_retire,
@@ -181,9 +187,16 @@ impl TraceEntry {
 
 /// A collection of `ibstrace` samples corresponding to a "trace" of 
 /// micro-ops in dispatched/retired order. 
+///
+/// Traces are serialized with both the raw sample and the decoded fields for
+/// each entry, and can be loaded back with [Trace::from_json] or 
+/// [Trace::from_reader].
 #[derive(Clone, Debug)]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct Trace { 
+    /// Schema version (see [Trace::VERSION])
+    pub version: usize,
+
     /// User-defined annotation
     pub annotation: String,
 
@@ -193,38 +206,56 @@ pub struct Trace {
     /// Program counter of the instruction marked as the "target" in user code
     pub target_rip: usize,
 
+    /// Layout used to decode samples (from the machine the trace was 
+    /// collected on)
+    pub layout: IbsLayout,
+
     /// Container for `ibstrace` samples
     pub samples: Vec<TraceEntry>,
 }
 impl Trace { 
+    /// The current version of the serialized format.
+    pub const VERSION: usize = 1;
 
     /// Collect a trace 
-    pub fn collect_from(
+    ///
+    /// User code is uploaded once, and offsets are swept in chunks which fit
+    /// in the sample buffer (see [MeasurementBackend::measure_sweep]).
+    pub fn collect_from<B>(
+        dev: &mut B,
         params: &TestParameters,
         offset_range: RangeInclusive<usize>,
         rdi_val: usize,
-    ) -> Result<Self, &'static str>
+    ) -> Result<Self, Error>
+        where B: MeasurementBackend + ?Sized
     {
         let mut samples = Vec::new();
-        let base_addr = get_base_address()?;
+        let base_addr = dev.base_address()?;
         let target_rip = base_addr + params.tgt_instr_off;
+        let layout = dev.layout()?;
 
-        let fd = ibstrace_open()?;
-        for offset in offset_range.clone() { 
-            let res = run_precise_test(fd, &params, 
-                PreciseArgs::new(rdi_val, offset)
-            );
-            if !res.is_empty() {
-                let entry = TraceEntry::from_sample(offset, &res[0]);
-                samples.push(entry);
+        let chunk = dev.capacity()?.max(1);
+
+        dev.upload(&params.buf)?;
+        let (mut first, last) = offset_range.clone().into_inner();
+        while first <= last {
+            let end = last.min(first.saturating_add(chunk - 1));
+            let res = dev.measure_sweep(&SweepArgs::new(rdi_val, first..=end)?)?;
+            for (offset, sample) in res.iter() {
+                samples.push(TraceEntry::from_sample(offset, sample, layout));
+            }
+            if end == last {
+                break;
             }
+            first = end + 1;
         }
-        ibstrace_close(fd);
 
         Ok(Self { 
+            version: Self::VERSION,
             samples,
             offset_range,
             target_rip,
+            layout,
             annotation: String::new(),
         })
     }
@@ -242,6 +273,27 @@ impl Trace {
         serde_json::to_string(&self).unwrap()
     }
 
+    /// Load a trace from JSON (as produced by [Trace::to_json]).
+    pub fn from_json(s: &str) -> Result<Self, Error> {
+        let res: Self = serde_json::from_str(s)
+            .map_err(|e| Error::TraceFormat(e.to_string()))?;
+        res.check_version()
+    }
+
+    /// Load a trace from JSON in some reader (ie. a file).
+    pub fn from_reader(r: impl std::io::Read) -> Result<Self, Error> {
+        let res: Self = serde_json::from_reader(r)
+            .map_err(|e| Error::TraceFormat(e.to_string()))?;
+        res.check_version()
+    }
+
+    fn check_version(self) -> Result<Self, Error> {
+        if self.version != Self::VERSION {
+            return Err(Error::UnsupportedTraceVersion { version: self.version });
+        }
+        Ok(self)
+    }
+
     pub fn print(&self) { 
         if !self.annotation.is_empty() {
             println!("[*] Trace '{}'", self.annotation);
@@ -263,9 +315,9 @@ impl Trace {
                 "".to_string()
             };
 
-            println!("  {:08} {:016x} t2c={:05} {} {}",
+            println!("  {:08} {} t2c={:05} {} {}",
                 entry.offset,
-                entry.rip,
+                fmt_addr(entry.rip),
                 t2c, 
                 lprops,
                 bprops
@@ -275,3 +327,94 @@ impl Trace {
 }
 
 
+
+#[cfg(test)]
+mod test {
+    use crate::backend::MockRequest;
+    use crate::codegen::emit_msr_test;
+    use crate::trace::*;
+
+    #[test]
+    fn collect_from_mock() {
+        let base = MockBackend::DEFAULT_BASE_ADDRESS;
+        // Offsets are swept in chunks which fit in the sample buffer
+        let mut dev = MockBackend::new().with_capacity(3)
+            .with_layout(IbsLayout::Zen3)
+            .with_generator(move |req| {
+            match req {
+                // Only odd offsets produce a sample
+                MockRequest::Precise { offset, .. } if offset % 2 == 1 => {
+                    vec![SampleBuilder::new()
+                        .with_rip(base + offset)
+                        .with_latency(0x10, 0x8)
+                        .build()]
+                },
+                _ => Vec::new(),
+            }
+        });
+        let params = emit_msr_test(0xc001_0015, 1);
+        let mut trace = Trace::collect_from(&mut dev, &params, 0..=7, 0)
+            .unwrap();
+        assert_eq!(dev.measurements(), 8);
+        assert_eq!(trace.target_rip, base + params.tgt_instr_off);
+        assert_eq!(trace.layout, IbsLayout::Zen3);
+        assert_eq!(trace.samples.len(), 4);
+        assert!(trace.samples.iter().all(|e| e.rip == Some(base + e.offset)));
+        assert!(trace.samples.iter().all(|e| {
+            e.tag_to_retire == 0x10 && e.complete_to_retire == 0x8
+        }));
+
+        trace.retain(|e| e.offset > 4);
+        assert_eq!(trace.samples.len(), 2);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let samples = [
+            SampleBuilder::new().with_rip(0x1000)
+                .with_load(IbsMemWidth::Qword)
+                .with_linad(0x7fff_0000).with_phyad(0x1_0000)
+                .with_data_src(NbDataSrc::Dram)
+                .with_latency(0x20, 0x4)
+                .build(),
+            SampleBuilder::new().with_rip(0x1004)
+                .with_branch(true, true).with_tgt_rip(0x1000)
+                .with_meta(SampleMeta { seq: 1, tsc: 0x1234, intr_rip: 0x1008 })
+                .build(),
+            // Unknown width and a leftover physical address
+            Sample { phyad: 0xdead, ..SampleBuilder::new().with_rip(0x1008)
+                .with_store(IbsMemWidth::Unknown(0xf)).build() },
+        ];
+        let mut trace = Trace {
+            version: Trace::VERSION,
+            annotation: "test".to_string(),
+            offset_range: 0..=2,
+            target_rip: 0x1004,
+            layout: IbsLayout::Zen4,
+            samples: samples.iter().enumerate()
+                .map(|(idx, s)| TraceEntry::from_sample(idx, s, IbsLayout::Zen4))
+                .collect(),
+        };
+        let json = trace.to_json();
+        assert!(json.contains("\"version\":1"));
+        assert!(json.contains("\"data3\":"));
+        assert!(json.contains("\"ldst_props\":"));
+
+        let res = Trace::from_json(&json).unwrap();
+        assert_eq!(res.samples, trace.samples);
+        assert_eq!(res.offset_range, 0..=2);
+        assert_eq!(res.annotation, "test");
+        assert_eq!(res.layout, IbsLayout::Zen4);
+        assert_eq!(res.samples[1].raw.meta, samples[1].meta);
+        assert_eq!(res.samples[2].ldst_props.unwrap().phy, None);
+        assert_eq!(res.samples[2].raw.data3.op_mem_width(), IbsMemWidth::Unknown(0xf));
+        let res = Trace::from_reader(json.as_bytes()).unwrap();
+        assert_eq!(res.samples, trace.samples);
+
+        trace.version = Trace::VERSION + 1;
+        assert_eq!(Trace::from_json(&trace.to_json()).err(), 
+            Some(Error::UnsupportedTraceVersion { version: Trace::VERSION + 1 })
+        );
+        assert!(matches!(Trace::from_json("{}"), Err(Error::TraceFormat(_))));
+    }
+}
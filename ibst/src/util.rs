@@ -5,6 +5,8 @@ use std::os::raw::c_void;
 use std::num::NonZero;
 use std::ptr::NonNull;
 
+use crate::IbstraceInfo;
+
 pub fn disas(buf: &[u8]) {
     use iced_x86::{
         Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter
@@ -99,6 +101,9 @@ impl PageMap {
 
 
 /// A physically-contiguous memory allocation. 
+///
+/// Measured code can access this memory through the kernel direct map, see 
+/// [RawAlloc::kernel_addr].
 pub struct RawAlloc { 
     /// Pointer returned from call to `mmap()`
     pub ptr: NonNull<c_void>,
@@ -173,6 +178,40 @@ impl RawAlloc {
         self.ptr.cast()
     }
 
+    /// Return the virtual address of this allocation in the kernel direct 
+    /// map (ie. the address that measured code should use to access it), 
+    /// if the module exports `PAGE_OFFSET`.
+    pub fn kernel_addr(&self, info: &IbstraceInfo) -> Option<usize> { 
+        info.direct_map_addr(self.paddr)
+    }
+
+    /// Returns `true` if the given physical address lies in this allocation.
+    pub fn contains_paddr(&self, paddr: usize) -> bool { 
+        (self.paddr..self.paddr + self.len).contains(&paddr)
+    }
+
+    /// Return the offset of some physical address within this allocation 
+    /// (ie. for matching [Sample::phyad](crate::Sample::phyad) to our own 
+    /// buffer).
+    pub fn offset_of_paddr(&self, paddr: usize) -> Option<usize> { 
+        if self.contains_paddr(paddr) { 
+            Some(paddr - self.paddr) 
+        } else { 
+            None 
+        }
+    }
+
+    /// Return the contents of this allocation.
+    pub fn as_slice(&self) -> &[u8] { 
+        unsafe { std::slice::from_raw_parts(self.ptr().as_ptr(), self.len) }
+    }
+
+    /// Return the contents of this allocation (for filling in data before 
+    /// measurement).
+    pub fn as_mut_slice(&mut self) -> &mut [u8] { 
+        unsafe { std::slice::from_raw_parts_mut(self.ptr().as_ptr(), self.len) }
+    }
+
 }
 
 impl Drop for RawAlloc { 
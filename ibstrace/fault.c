@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: GPL-2.0
+
+#include <linux/kdebug.h>
+#include <linux/notifier.h>
+#include <asm/traps.h>
+
+#include <ibstrace.h>
+#include "state.h"
+#include "fault.h"
+
+extern struct ibstrace_state state;
+
+// The fault taken by measured code during the most-recent measurement.
+struct ibstrace_fault ibstrace_fault;
+
+// Where to resume after measured code faults (see ARM_FAULT_FIXUP). 
+// The trampolines set these immediately before calling into measured code, 
+// and clear 'ibstrace_fixup_sp' after measured code returns.
+unsigned long ibstrace_fixup_ip;
+unsigned long ibstrace_fixup_sp;
+
+// Returns true if 'ip' points into the code buffer.
+static bool in_code_buf(unsigned long ip)
+{
+	unsigned long start = (unsigned long)state.code_buf;
+	return (ip >= start) && (ip < start + CODE_BUFFER_MAX_SIZE);
+}
+
+// Cursed hack #4: 
+// Recover from exceptions in measured code. Instead of letting the kernel 
+// kill the current task (or panic, since we're in interrupt context), record 
+// the fault and resume in the trampoline as if measured code had returned.
+//
+// NOTE: #GP and #UD are reported before the kernel does anything else. 
+// Page faults are only reported after the kernel has already printed an 
+// oops (and tainted itself), so this is best-effort.
+static int ibstrace_die_handler(struct notifier_block *nb, unsigned long val, 
+		void *data)
+{
+	struct die_args *args = (struct die_args*)data;
+	struct pt_regs *regs = args->regs;
+
+	switch (val) {
+	case DIE_GPF:
+	case DIE_TRAP:
+	case DIE_OOPS:
+		break;
+	default:
+		return NOTIFY_DONE;
+	}
+
+	if ((ibstrace_fixup_sp == 0) || (regs == NULL) || user_mode(regs))
+		return NOTIFY_DONE;
+	if (smp_processor_id() != state.target_cpu)
+		return NOTIFY_DONE;
+	if (!in_code_buf(regs->ip))
+		return NOTIFY_DONE;
+
+	ibstrace_fault.valid = 1;
+	ibstrace_fault.vector = args->trapnr;
+	ibstrace_fault.rip = regs->ip;
+	ibstrace_fault.error_code = args->err;
+
+	// Only recover once for each call into measured code
+	regs->ip = ibstrace_fixup_ip;
+	regs->sp = ibstrace_fixup_sp;
+	regs->ax = 0;
+	ibstrace_fixup_sp = 0;
+
+	return NOTIFY_STOP;
+}
+
+static struct notifier_block ibstrace_die_notifier = {
+	.notifier_call = ibstrace_die_handler,
+	// Run before anything else (ie. kprobes and debuggers)
+	.priority = INT_MAX,
+};
+
+int ibstrace_register_fault_handler(void)
+{
+	return register_die_notifier(&ibstrace_die_notifier);
+}
+
+void ibstrace_unregister_fault_handler(void)
+{
+	unregister_die_notifier(&ibstrace_die_notifier);
+}
+
+// Forget about any fault from a previous measurement.
+void ibstrace_reset_fault(void)
+{
+	memset(&ibstrace_fault, 0, sizeof(struct ibstrace_fault));
+	ibstrace_fixup_sp = 0;
+}
+
+// Returns true if measured code faulted during the most-recent measurement.
+bool ibstrace_faulted(void)
+{
+	return ibstrace_fault.valid != 0;
+}
@@ -0,0 +1,12 @@
+// SPDX-License-Identifier: GPL-2.0
+
+#ifndef _FAULT_H
+#define _FAULT_H
+
+int ibstrace_register_fault_handler(void);
+void ibstrace_unregister_fault_handler(void);
+
+void ibstrace_reset_fault(void);
+bool ibstrace_faulted(void);
+
+#endif // _FAULT_H
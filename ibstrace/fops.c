@@ -2,60 +2,189 @@
 
 #include <linux/smp.h>
 #include <linux/fs.h>
+#include <linux/random.h>
+#include <linux/slab.h>
 #include <ibstrace.h>
 #include "state.h"
 #include "fops.h"
+#include "apic.h"
+#include "msr.h"
+#include "fault.h"
 
 struct ibstrace_msg tmp;
 struct ibstrace_precise_msg precise_tmp;
+struct ibstrace_measure_msg measure_tmp;
+struct ibstrace_fetch_msg fetch_tmp;
+struct ibstrace_sweep_msg sweep_tmp;
+struct ibstrace_batch_msg batch_tmp;
+struct ibstrace_batch batch;
 
 extern void trampoline(void *info);
 extern void precise_trampoline(void *info);
+extern void fetch_trampoline(void *info);
+extern void sweep_trampoline(void *info);
+extern void batch_trampoline(void *info);
 
 extern struct ibstrace_state state;
+extern struct ibstrace_regs ibstrace_regs;
+extern struct ibstrace_fault ibstrace_fault;
 
 static call_single_data_t trampoline_csd = {
 	.func = trampoline,
-	.info = NULL,
+	.info = (void*)&measure_tmp,
 };
 
+// Default sampling configuration for IBSTRACE_CMD_MEASURE (a sample is 
+// tagged every 0x1000 dispatched ops).
+static const struct ibstrace_measure_msg measure_default = {
+	.op_ctl = IBS_OP_CNT_CTL | 0x0100,
+	.flags = 0,
+	.warmup = 0,
+};
+
+// Validate the sampling configuration passed to IBSTRACE_CMD_MEASURE, 
+// and compute the value written to IBS_OP_CTL by the trampoline.
+static int prepare_measure_msg(struct ibstrace_measure_msg *msg)
+{
+	u64 max_cnt;
+	u64 cur_cnt = 0;
+
+	if (msg->op_ctl & ~(IBS_OP_MAX_CNT | IBS_OP_CNT_CTL | 
+				IBS_OP_L3_MISS_ONLY | IBS_OP_LDLAT_EN | IBS_OP_LDLAT_THRSH))
+		return -EINVAL;
+	if (msg->flags & ~IBSTRACE_MEASURE_RANDOMIZE)
+		return -EINVAL;
+
+	// Filtering is only supported on some parts
+	if ((msg->op_ctl & IBS_OP_L3_MISS_ONLY) && 
+			!(state.ibs_caps & IBS_CAPS_ZEN4))
+		return -EOPNOTSUPP;
+	if ((msg->op_ctl & IBS_OP_LDLAT_EN) && 
+			!(state.ibs_caps & IBS_CAPS_OPLDLAT))
+		return -EOPNOTSUPP;
+	// A threshold without IbsOpLdLatEn is probably a mistake
+	if ((msg->op_ctl & IBS_OP_LDLAT_THRSH) && 
+			!(msg->op_ctl & IBS_OP_LDLAT_EN))
+		return -EINVAL;
+
+	// IbsOpMaxCnt holds bits [26:4] of the max count
+	max_cnt = ((msg->op_ctl & IBS_OP_MAX_CNT_15_0) << 4) | 
+		(msg->op_ctl & IBS_OP_MAX_CNT_26_20);
+	if (max_cnt == 0)
+		return -EINVAL;
+
+	// The hardware only randomizes the bottom bits of the counter when it 
+	// rolls over, so pick a random starting value for the first sample.
+	if (msg->flags & IBSTRACE_MEASURE_RANDOMIZE)
+		cur_cnt = get_random_u32() % min_t(u64, max_cnt, 0x80);
+
+	msg->op_ctl |= IBS_OP_EN | (cur_cnt << 32);
+	return 0;
+}
+
+static call_single_data_t fetch_trampoline_csd = {
+	.func = fetch_trampoline,
+	.info = (void*)&fetch_tmp,
+};
+
+// Validate the sampling configuration passed to IBSTRACE_CMD_FETCH, 
+// and compute the value written to IBS_FETCH_CTL by the trampoline.
+static int prepare_fetch_msg(struct ibstrace_fetch_msg *msg)
+{
+	if (msg->fetch_ctl & ~(IBS_FETCH_MAX_CNT | IBS_FETCH_RAND_EN))
+		return -EINVAL;
+	if ((msg->fetch_ctl & IBS_FETCH_MAX_CNT) == 0)
+		return -EINVAL;
+
+	msg->fetch_ctl |= IBS_FETCH_EN;
+	return 0;
+}
+
 static call_single_data_t precise_trampoline_csd = {
 	.func = precise_trampoline,
 	.info = (void*)&precise_tmp,
 };
 
+static call_single_data_t sweep_trampoline_csd = {
+	.func = sweep_trampoline,
+	.info = (void*)&sweep_tmp,
+};
+
+static call_single_data_t batch_trampoline_csd = {
+	.func = batch_trampoline,
+	.info = (void*)&batch,
+};
+
+// Copy a batch of programs into the code buffer (each aligned to BATCH_ALIGN 
+// bytes), filling in the load offset for each program.
+static int upload_batch(struct ibstrace_msg *progs, 
+		struct ibstrace_batch_result *results, u64 count)
+{
+	u64 i;
+	u64 off = 0;
+
+	for (i = 0; i < count; i++) {
+		if ((progs[i].len == 0) || 
+				(progs[i].len > CODE_BUFFER_MAX_SIZE - off))
+			return -EINVAL;
+		if (copy_from_user(state.code_buf + off, progs[i].ptr, progs[i].len))
+			return -EFAULT;
+
+		results[i].load_offset = off;
+		state.code_buf_len = off + progs[i].len;
+		off = ALIGN(state.code_buf_len, BATCH_ALIGN);
+	}
+	return 0;
+}
+
+
+// Reset the counters for dropped samples and spurious NMIs (and the sample 
+// sequence number) before starting a new measurement.
+static void reset_sample_stats(void)
+{
+	atomic_long_xchg(&state.samples_dropped, 0);
+	atomic_long_xchg(&state.spurious_nmis, 0);
+	atomic_long_xchg(&state.sample_seq, 0);
+}
 
 // Read handler. 
-// Consumes the sample buffer, copying all samples to userspace.
+// Copies samples to userspace, starting at the current file position. 
+// Samples may be read across multiple calls; the sample buffer is consumed 
+// once all samples have been copied.
 ssize_t ibstrace_read(struct file *file, char __user *buf, size_t count,
 		loff_t *fpos)
 {
 	ssize_t res;
-	int num_bytes;
+	size_t num_bytes;
 	long num_samples;
 
 	mutex_lock(&state.in_use);
 
 	num_samples = atomic_long_read(&state.samples_collected);
-	num_bytes = (num_samples * sizeof(struct sample));
+	num_bytes = (num_samples * sizeof(struct sample_v2));
 
-	if ((count == 0) || (num_samples == 0)) {
+	if ((count == 0) || (num_samples == 0) || (*fpos >= num_bytes)) {
 		res = 0;
 		goto out;
 	} 
-	if (count > num_bytes) {
-		count = num_bytes;
+	if (count > (num_bytes - *fpos)) {
+		count = num_bytes - *fpos;
 	}
 
-	// Consume the buffer
-	if (copy_to_user(buf, state.sample_buf, count)) {
+	if (copy_to_user(buf, (u8 *)state.sample_buf + *fpos, count)) {
 		res = -EFAULT;
 		goto out;
 	}
-	atomic_long_xchg(&state.samples_collected, 0);
-	memset(state.sample_buf, 0, num_bytes);
+	*fpos += count;
 	res = count;
 
+	// Consume the buffer after the last sample has been copied
+	if (*fpos >= num_bytes) {
+		atomic_long_xchg(&state.samples_collected, 0);
+		memset(state.sample_buf, 0, num_bytes);
+		*fpos = 0;
+	}
+
 out:
 	mutex_unlock(&state.in_use);
 	return res;
@@ -97,20 +226,130 @@ long int ibstrace_ioctl(struct file *file, unsigned int cmd, unsigned long arg)
 		mutex_unlock(&state.in_use);
 		break;
 
+	case IBSTRACE_CMD_WRITE_DATA:
+		mutex_lock(&state.in_use);
+
+		if (copy_from_user(&tmp, (struct ibstrace_msg *)arg, 
+				sizeof(struct ibstrace_msg))) {
+			pr_info("ibstrace: invalid IBSTRACE_CMD_WRITE_DATA message?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		if (tmp.len > PAGE_SIZE) {
+			pr_info("ibstrace: invalid buffer for scratch data?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+
+		// The rest of the scratch page is zeroed
+		memset(state.scratch_page, 0, PAGE_SIZE);
+		if (copy_from_user(state.scratch_page, tmp.ptr, tmp.len)) {
+			pr_info("ibstrace: error uploading scratch data?\n");
+			res = -EFAULT;
+		}
+		mutex_unlock(&state.in_use);
+		break;
+
+	case IBSTRACE_CMD_READ_DATA:
+		mutex_lock(&state.in_use);
+
+		if (copy_from_user(&tmp, (struct ibstrace_msg *)arg, 
+				sizeof(struct ibstrace_msg))) {
+			pr_info("ibstrace: invalid IBSTRACE_CMD_READ_DATA message?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		if (tmp.len > PAGE_SIZE) {
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		if (copy_to_user(tmp.ptr, state.scratch_page, tmp.len)) {
+			res = -EFAULT;
+		}
+		mutex_unlock(&state.in_use);
+		break;
+
 	case IBSTRACE_CMD_MEASURE:
 		mutex_lock(&state.in_use);
+
+		// Older clients don't pass a sampling configuration
+		if (arg == 0) {
+			measure_tmp = measure_default;
+		} else if (copy_from_user(&measure_tmp, 
+				(struct ibstrace_measure_msg *)arg, 
+				sizeof(struct ibstrace_measure_msg))) {
+			pr_info("ibstrace: invalid IBSTRACE_CMD_MEASURE message?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		if (prepare_measure_msg(&measure_tmp) != 0) {
+			pr_info("ibstrace: invalid sampling configuration?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+
+		file->f_pos = 0;
+		memset(&ibstrace_regs, 0, sizeof(struct ibstrace_regs));
+		reset_sample_stats();
+		ibstrace_reset_fault();
 		atomic_long_xchg(&state.precise_mode, 0);
-		smp_call_function_single_async(TARGET_CPU, &trampoline_csd);
+		atomic_long_xchg(&state.fetch_mode, 0);
+		smp_call_function_single_async(state.target_cpu, &trampoline_csd);
 
 		// Wait around until the trampoline returns and the target core
 		// releases the lock. There's probably a better way to do this ...
 		mutex_lock(&state.in_use);
+		if (ibstrace_faulted())
+			res = -EFAULT;
+		mutex_unlock(&state.in_use);
+		break;
+
+	case IBSTRACE_CMD_FETCH:
+		mutex_lock(&state.in_use);
+
+		if (copy_from_user(&fetch_tmp, (struct ibstrace_fetch_msg *)arg, 
+				sizeof(struct ibstrace_fetch_msg))) {
+			pr_info("ibstrace: invalid IBSTRACE_CMD_FETCH message?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		if (prepare_fetch_msg(&fetch_tmp) != 0) {
+			pr_info("ibstrace: invalid fetch sampling configuration?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+
+		file->f_pos = 0;
+		memset(&ibstrace_regs, 0, sizeof(struct ibstrace_regs));
+		reset_sample_stats();
+		ibstrace_reset_fault();
+		atomic_long_xchg(&state.precise_mode, 0);
+		atomic_long_xchg(&state.fetch_mode, 1);
+		smp_call_function_single_async(state.target_cpu, 
+				&fetch_trampoline_csd);
+
+		mutex_lock(&state.in_use);
+		if (ibstrace_faulted())
+			res = -EFAULT;
 		mutex_unlock(&state.in_use);
 		break;
 
 	case IBSTRACE_CMD_PRECISE:
 		mutex_lock(&state.in_use);
+		file->f_pos = 0;
+		memset(&ibstrace_regs, 0, sizeof(struct ibstrace_regs));
+		reset_sample_stats();
+		ibstrace_reset_fault();
 		atomic_long_xchg(&state.precise_mode, 1);
+		atomic_long_xchg(&state.fetch_mode, 0);
 
 		res = copy_from_user(&precise_tmp, (struct ibstrace_precise_msg *)arg, 
 				sizeof(struct ibstrace_precise_msg));
@@ -122,9 +361,126 @@ long int ibstrace_ioctl(struct file *file, unsigned int cmd, unsigned long arg)
 
 		//precise_tmp.offset &= (MAX_OFFSET - 1);
 
-		smp_call_function_single_async(TARGET_CPU, &precise_trampoline_csd);
+		smp_call_function_single_async(state.target_cpu, &precise_trampoline_csd);
+
+		mutex_lock(&state.in_use);
+		if (ibstrace_faulted())
+			res = -EFAULT;
+		mutex_unlock(&state.in_use);
+		break;
+
+	case IBSTRACE_CMD_SWEEP:
+		mutex_lock(&state.in_use);
+
+		if (copy_from_user(&sweep_tmp, (struct ibstrace_sweep_msg *)arg, 
+				sizeof(struct ibstrace_sweep_msg))) {
+			pr_info("ibstrace: invalid IBSTRACE_CMD_SWEEP message?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		// NOTE: Written so that 'first + count' can't wrap around. 
+		// Offsets must be below MAX_OFFSET, which the precise trampoline 
+		// would mask to offset 0.
+		if ((sweep_tmp.count == 0) || 
+				(sweep_tmp.count > state.sample_buf_capacity) ||
+				(sweep_tmp.first >= MAX_OFFSET) ||
+				(sweep_tmp.count > MAX_OFFSET - sweep_tmp.first)) {
+			pr_info("ibstrace: invalid range of offsets?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+
+		// Discard any unread samples, since entries in the sample buffer 
+		// correspond to offsets in the sweep.
+		num_samples = atomic_long_xchg(&state.samples_collected, 0);
+		memset(state.sample_buf, 0, 
+				max_t(u64, num_samples, sweep_tmp.count) * 
+				sizeof(struct sample_v2));
+
+		file->f_pos = 0;
+		memset(&ibstrace_regs, 0, sizeof(struct ibstrace_regs));
+		reset_sample_stats();
+		ibstrace_reset_fault();
+		atomic_long_xchg(&state.precise_mode, 1);
+		atomic_long_xchg(&state.fetch_mode, 0);
+		smp_call_function_single_async(state.target_cpu, 
+				&sweep_trampoline_csd);
+
+		mutex_lock(&state.in_use);
+		if (ibstrace_faulted())
+			res = -EFAULT;
+		mutex_unlock(&state.in_use);
+		break;
+
+	case IBSTRACE_CMD_BATCH:
+		mutex_lock(&state.in_use);
+
+		if (copy_from_user(&batch_tmp, (struct ibstrace_batch_msg *)arg, 
+				sizeof(struct ibstrace_batch_msg))) {
+			pr_info("ibstrace: invalid IBSTRACE_CMD_BATCH message?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		if ((batch_tmp.count == 0) || (batch_tmp.count > BATCH_MAX_PROGS) ||
+				(prepare_measure_msg(&batch_tmp.config) != 0)) {
+			pr_info("ibstrace: invalid batch configuration?\n");
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+
+		{
+			struct ibstrace_msg *progs;
+
+			progs = kcalloc(batch_tmp.count, sizeof(*progs), GFP_KERNEL);
+			batch.results = kcalloc(batch_tmp.count, 
+					sizeof(struct ibstrace_batch_result), GFP_KERNEL);
+			if (!progs || !batch.results) {
+				res = -ENOMEM;
+			} else if (copy_from_user(progs, batch_tmp.progs, 
+					batch_tmp.count * sizeof(*progs))) {
+				res = -EFAULT;
+			} else {
+				res = upload_batch(progs, batch.results, batch_tmp.count);
+			}
+			kfree(progs);
+		}
+		if (res != 0) {
+			pr_info("ibstrace: error uploading batch?\n");
+			kfree(batch.results);
+			mutex_unlock(&state.in_use);
+			break;
+		}
+		batch.count = batch_tmp.count;
+		batch.config = batch_tmp.config;
+
+		// Discard any unread samples, since results refer to indices in 
+		// the sample buffer.
+		num_samples = atomic_long_xchg(&state.samples_collected, 0);
+		memset(state.sample_buf, 0, num_samples * sizeof(struct sample_v2));
+
+		file->f_pos = 0;
+		memset(&ibstrace_regs, 0, sizeof(struct ibstrace_regs));
+		reset_sample_stats();
+		ibstrace_reset_fault();
+		atomic_long_xchg(&state.precise_mode, 0);
+		atomic_long_xchg(&state.fetch_mode, 0);
+		smp_call_function_single_async(state.target_cpu, 
+				&batch_trampoline_csd);
 
+		// A fault in some program is reported in its result (instead of
+		// failing with -EFAULT), so the results for the programs before 
+		// it aren't lost.
 		mutex_lock(&state.in_use);
+		if (copy_to_user(batch_tmp.results, batch.results, 
+				batch.count * sizeof(struct ibstrace_batch_result))) {
+			res = -EFAULT;
+		}
+		kfree(batch.results);
+		batch.results = NULL;
 		mutex_unlock(&state.in_use);
 		break;
 
@@ -135,6 +491,65 @@ long int ibstrace_ioctl(struct file *file, unsigned int cmd, unsigned long arg)
 		mutex_unlock(&state.in_use);
 		break;
 
+	case IBSTRACE_CMD_REGS:
+		mutex_lock(&state.in_use);
+		if (copy_to_user((struct ibstrace_regs *)arg, &ibstrace_regs, 
+				sizeof(struct ibstrace_regs))) {
+			res = -EFAULT;
+		}
+		mutex_unlock(&state.in_use);
+		break;
+
+	case IBSTRACE_CMD_STATS:
+		mutex_lock(&state.in_use);
+		{
+			struct ibstrace_stats stats = {
+				.collected = atomic_long_read(&state.samples_collected),
+				.dropped = atomic_long_read(&state.samples_dropped),
+				.spurious = atomic_long_read(&state.spurious_nmis),
+			};
+			if (copy_to_user((struct ibstrace_stats *)arg, &stats, 
+					sizeof(struct ibstrace_stats))) {
+				res = -EFAULT;
+			}
+		}
+		mutex_unlock(&state.in_use);
+		break;
+
+	case IBSTRACE_CMD_FAULT:
+		mutex_lock(&state.in_use);
+		if (copy_to_user((struct ibstrace_fault *)arg, &ibstrace_fault, 
+				sizeof(struct ibstrace_fault))) {
+			res = -EFAULT;
+		}
+		mutex_unlock(&state.in_use);
+		break;
+
+	case IBSTRACE_CMD_VERSION:
+		res = IBSTRACE_SAMPLE_VERSION;
+		break;
+
+	case IBSTRACE_CMD_SET_CPU:
+		mutex_lock(&state.in_use);
+		if ((arg >= nr_cpu_ids) || !cpu_online(arg)) {
+			pr_info("ibstrace: invalid target cpu #%lu?\n", arg);
+			res = -EINVAL;
+			mutex_unlock(&state.in_use);
+			break;
+		}
+
+		// Tear down the local APIC on the old target, and set up the 
+		// local APIC on the new target.
+		if (arg != state.target_cpu) {
+			smp_call_function_single(state.target_cpu, ibs_apic_exit, 
+					NULL, 1);
+			state.target_cpu = arg;
+			smp_call_function_single(state.target_cpu, ibs_apic_init, 
+					NULL, 1);
+		}
+		mutex_unlock(&state.in_use);
+		break;
+
 	case IBSTRACE_CMD_CAPACITY:
 		mutex_lock(&state.in_use);
 		res = IBSTRACE_SAMPLE_CAPACITY;
@@ -20,6 +20,8 @@
 #include "apic.h"
 #include "fops.h"
 #include "nmi.h"
+#include "fault.h"
+#include "msr.h"
 
 
 // Filled-in with hacky kprobe magic on module_init.
@@ -30,9 +32,14 @@ static int (*set_memory_nx)(unsigned long, int) = NULL;
 struct ibstrace_state state = {
 	.sample_buf = NULL,
 	.sample_buf_capacity = IBSTRACE_SAMPLE_CAPACITY,
-	.sample_buf_len = sizeof(struct sample) * IBSTRACE_SAMPLE_CAPACITY,
+	.sample_buf_len = sizeof(struct sample_v2) * IBSTRACE_SAMPLE_CAPACITY,
 	.samples_collected = ATOMIC_INIT(0),
+	.samples_dropped = ATOMIC_INIT(0),
+	.spurious_nmis = ATOMIC_INIT(0),
+	.sample_seq = ATOMIC_INIT(0),
 	.precise_mode = ATOMIC_INIT(0),
+	.fetch_mode = ATOMIC_INIT(0),
+	.target_cpu = TARGET_CPU,
 	.__scratch_page = NULL,
 	.scratch_page = NULL,
 	.scratch_page_paddr = 0,
@@ -44,6 +51,9 @@ static u64 debugfs_code_buf;
 static u64 debugfs_sample_buf;
 static u64 debugfs_scratch_page;
 static u64 debugfs_scratch_page_paddr;
+static u64 debugfs_sample_capacity;
+static u64 debugfs_code_buf_max_size;
+static u64 debugfs_page_offset;
 
 // File operations for the character device
 static const struct file_operations ibstrace_fops = {
@@ -94,6 +104,7 @@ static __init int ibstrace_init(void)
 		pr_err("ibstrace: cpuid says IBS isn't supported?\n");
 		return -1;
 	}
+	state.ibs_caps = cpuid_eax(IBS_CPUID_FEATURES);
 
 	// We have to resolve these symbols in order to set pages as executable.
 	// I wonder if there's another way to do this?
@@ -111,7 +122,7 @@ static __init int ibstrace_init(void)
 	}
 
 	// Initialize the local APIC for the target CPU
-	smp_call_function_single(TARGET_CPU, ibs_apic_init, NULL, 1);
+	smp_call_function_single(state.target_cpu, ibs_apic_init, NULL, 1);
 	err = register_nmi_handler(NMI_LOCAL, ibs_nmi_handler,
 			NMI_FLAG_FIRST, "ibstrace");
 	if (err) {
@@ -119,6 +130,13 @@ static __init int ibstrace_init(void)
 		return -1;
 	}
 
+	// Recover from faults in measured code
+	err = ibstrace_register_fault_handler();
+	if (err) {
+		pr_err("ibstrace: register_die_notifier() returned %d\n", err);
+		return -1;
+	}
+
 	// Allocate space for sample data, and for user input.
 	mutex_init(&state.in_use);
 
@@ -137,6 +155,10 @@ static __init int ibstrace_init(void)
 		debugfs_sample_buf = (u64)(state.sample_buf);
 		debugfs_scratch_page = (u64)(state.scratch_page);
 		debugfs_scratch_page_paddr = (u64)(state.scratch_page_paddr);
+		debugfs_sample_capacity = (u64)(state.sample_buf_capacity);
+		debugfs_code_buf_max_size = (u64)(CODE_BUFFER_MAX_SIZE);
+		// Base of the direct map (this is randomized with KASLR)
+		debugfs_page_offset = (u64)(PAGE_OFFSET);
 
 		debugfs_create_x64("code_buf", 0444, ibstrace_debugfs_dir, 
 				&debugfs_code_buf);
@@ -146,6 +168,14 @@ static __init int ibstrace_init(void)
 				&debugfs_scratch_page);
 		debugfs_create_x64("scratch_page_paddr", 0444, ibstrace_debugfs_dir, 
 				&debugfs_scratch_page_paddr);
+		debugfs_create_x64("sample_capacity", 0444, ibstrace_debugfs_dir, 
+				&debugfs_sample_capacity);
+		debugfs_create_x64("code_buf_max_size", 0444, ibstrace_debugfs_dir, 
+				&debugfs_code_buf_max_size);
+		debugfs_create_x64("page_offset", 0444, ibstrace_debugfs_dir, 
+				&debugfs_page_offset);
+		debugfs_create_x32("target_cpu", 0444, ibstrace_debugfs_dir, 
+				&state.target_cpu);
 
 		pr_info("ibstrace: see /sys/kernel/debug/ibstrace for info\n");
 	}
@@ -160,8 +190,9 @@ static __exit void ibstrace_exit(void)
 	vfree(state.code_buf);
 	vfree(state.sample_buf);
 
-	smp_call_function_single(TARGET_CPU, ibs_apic_exit, NULL, 1);
+	smp_call_function_single(state.target_cpu, ibs_apic_exit, NULL, 1);
 	unregister_nmi_handler(NMI_LOCAL, "ibstrace");
+	ibstrace_unregister_fault_handler();
 
 	misc_deregister(&ibstrace_dev);
 	if (ibstrace_debugfs_dir != NULL) {
@@ -1,5 +1,9 @@
 // SPDX-License-Identifier: GPL-2.0
 
+#define IBS_FETCH_CTL			0xc0011030
+#define IBS_FETCH_LINADDR		0xc0011031
+#define IBS_FETCH_PHYSADDR		0xc0011032
+#define IC_IBS_EXTD_CTL			0xc001103c
 #define IBS_OP_CTL				0xc0011033
 #define IBS_OP_RIP				0xc0011034
 #define IBS_OP_DATA				0xc0011035
@@ -13,19 +17,28 @@
 #define IBS_LVT_OFFSET_VALID	(1ULL << 8)
 #define IBS_LVT_OFFSET_MASK		(0xf)
 
+// CPUID Fn8000_001B EAX [Instruction Based Sampling Identifiers]
+#define IBS_CPUID_FEATURES		0x8000001b
+// L3MissOnly filtering and extended DataSrc encodings (Zen 4)
+#define IBS_CAPS_ZEN4			(1U << 11)
+// Load latency filtering
+#define IBS_CAPS_OPLDLAT		(1U << 12)
+
 
 // IBS_OP_CTL
 //
 //   63       55       47       39       
 //   v        v        v        v        
-//   .....ccc cccccccc cccccccc cccccccc 
+//   lhhhhccc cccccccc cccccccc cccccccc 
 //
 //   31       23       15       07
 //   v        v        v        v
-//   .....mmm mmmmtve. mmmmmmmm mmmmmmmm
+//   .....mmm mmmmtvel mmmmmmmm mmmmmmmm
 //
 //		. - reserved		c - IbsOpCurCnt		m - IbsOpMaxCnt
 //		t - IbsOpCntCtl		v - IbsOpVal		e - IbsOpEn
+//		l - IbsOpL3MissOnly (bit 16), IbsOpLdLatEn (bit 63)
+//		h - IbsOpLdLatThrsh
 //
 
 // Bitmask for the current counter value
@@ -37,8 +50,40 @@
 
 // Bitmask for the max counter value
 #define IBS_OP_MAX_CNT			(0x07f0ffffULL)
+// Bitmask for bits [15:0] of the max counter value (max count bits [19:4])
+#define IBS_OP_MAX_CNT_15_0		(0x0000ffffULL)
+// Bitmask for bits [26:20] of the max counter value (max count bits [26:20])
+#define IBS_OP_MAX_CNT_26_20	(0x07f00000ULL)
 
 #define IBS_OP_CNT_CTL			(1ULL << 19)
 #define IBS_OP_VAL				(1ULL << 18)
 #define IBS_OP_EN				(1ULL << 17)
+#define IBS_OP_L3_MISS_ONLY		(1ULL << 16)
+
+#define IBS_OP_LDLAT_EN			(1ULL << 63)
+// Bitmask for the load latency threshold (in units of 128 cycles, minus 1)
+#define IBS_OP_LDLAT_THRSH		(0xfULL << 59)
+
+
+// IBS_FETCH_CTL
+//
+//   63       55       47       39       
+//   v        v        v        v        
+//   ......rl lppicvel llllllll llllllll 
+//
+//   31       23       15       07
+//   v        v        v        v
+//   cccccccc cccccccc mmmmmmmm mmmmmmmm
+//
+//		. - reserved		r - IbsRandEn		l - TLB miss/latency
+//		p - IbsL1TlbPgSz	i - IbsIcMiss		c - IbsFetchCnt
+//		v - IbsFetchVal		e - IbsFetchEn		m - IbsFetchMaxCnt
+//
+
+// Bitmask for the max counter value
+#define IBS_FETCH_MAX_CNT		(0x0000ffffULL)
+
+#define IBS_FETCH_RAND_EN		(1ULL << 57)
+#define IBS_FETCH_VAL			(1ULL << 49)
+#define IBS_FETCH_EN			(1ULL << 48)
 
@@ -8,6 +8,16 @@
 
 extern struct ibstrace_state state;
 
+// Fill in the header for a record in the sample buffer.
+static void write_record_header(struct sample_v2 *rec, long seq, 
+		struct pt_regs *regs)
+{
+	rec->version = IBSTRACE_SAMPLE_VERSION;
+	rec->seq = seq;
+	rec->tsc = rdtsc();
+	rec->intr_rip = regs->ip;
+}
+
 // Read the IBS data registers, then clear them.
 static void read_sample_data(struct sample *sample, struct pt_regs *regs)
 {
@@ -29,6 +39,58 @@ static void read_sample_data(struct sample *sample, struct pt_regs *regs)
 	wrmsrl(BP_IBSTGT_RIP, 0);
 }
 
+// Read the IBS fetch registers.
+static void read_fetch_data(struct fetch_sample *sample)
+{
+	BUILD_BUG_ON(sizeof(struct fetch_sample) != sizeof(struct sample));
+
+	rdmsrl(IBS_FETCH_CTL, sample->fetch_ctl);
+	rdmsrl(IBS_FETCH_LINADDR, sample->fetch_lin_addr);
+	rdmsrl(IBS_FETCH_PHYSADDR, sample->fetch_phys_addr);
+	rdmsrl(IC_IBS_EXTD_CTL, sample->fetch_ext_ctl);
+}
+
+// Handle an NMI while collecting fetch samples.
+static int ibs_fetch_nmi_handler(struct pt_regs *regs)
+{
+	u64 ibs_fetch_ctl;
+	long sample_idx;
+	long seq;
+
+	rdmsrl(IBS_FETCH_CTL, ibs_fetch_ctl);
+	if (!(ibs_fetch_ctl & IBS_FETCH_VAL)) {
+		return NMI_DONE;
+	}
+
+	// If IBS_FETCH_MAX_CNT is zeroed out, this is a hanging NMI 
+	// that occured after the trampoline disabled sampling
+	if (!(ibs_fetch_ctl & IBS_FETCH_MAX_CNT)) {
+		atomic_long_inc(&state.spurious_nmis);
+		wrmsrl(IBS_FETCH_CTL, 0);
+		return NMI_HANDLED;
+	}
+
+	seq = atomic_long_fetch_inc(&state.sample_seq);
+	sample_idx = atomic_long_read(&state.samples_collected);
+	if (sample_idx < state.sample_buf_capacity) {
+		write_record_header(&state.sample_buf[sample_idx], seq, regs);
+		read_fetch_data(
+			(struct fetch_sample *)&state.sample_buf[sample_idx].data
+		);
+		atomic_long_inc(&state.samples_collected);
+	} else {
+		atomic_long_inc(&state.samples_dropped);
+	}
+
+	// Clear IbsFetchVal and IbsFetchCnt so we can handle another sample. 
+	// If the trampoline has already cleared IbsFetchEn, this disables 
+	// sampling entirely.
+	ibs_fetch_ctl &= (IBS_FETCH_MAX_CNT | IBS_FETCH_EN | IBS_FETCH_RAND_EN);
+	wrmsrl(IBS_FETCH_CTL, ibs_fetch_ctl);
+
+	return NMI_HANDLED;
+}
+
 // The handler for IBS non-maskable interrupts. 
 int ibs_nmi_handler(unsigned int cmd, struct pt_regs *regs)
 {
@@ -36,7 +98,13 @@ int ibs_nmi_handler(unsigned int cmd, struct pt_regs *regs)
 	u64 ibs_op_rip;
 	long sample_idx;
 	long precise_mode;
-	struct sample *this_sample;
+	long seq;
+	struct sample_v2 *this_sample;
+
+	if (atomic_long_read(&state.fetch_mode)) {
+		return ibs_fetch_nmi_handler(regs);
+	}
+
 	rdmsrl(IBS_OP_CTL, ibs_op_ctl);
 
 	rdmsrl(IBS_OP_RIP, ibs_op_rip);
@@ -44,34 +112,34 @@ int ibs_nmi_handler(unsigned int cmd, struct pt_regs *regs)
 	// If the valid bit is set, this is [probably] an IBS NMI
 	if (ibs_op_ctl & IBS_OP_VAL) {
 
-		// If we don't have any more space to store samples, just return
-		sample_idx = atomic_long_read(&state.samples_collected);
-		if (sample_idx >= state.sample_buf_capacity) {
-			return NMI_HANDLED;
-		}
-
-		// Are we handling "normal" or "precise" sampling?
-		precise_mode = atomic_long_read(&state.precise_mode);
-
-		//// Hanging NMI, I guess
-		//if (ibs_op_ctl == 0) { 
-		//	return NMI_HANDLED; 
-		//}
-
 		// If IBS_OP_MAX_CNT is zeroed out, this is a hanging NMI 
 		// that occured after clearing the IBS_OP_EN bit
-		//if (!(ibs_op_ctl & IBS_OP_MAX_CNT)) {
-		//	return NMI_HANDLED; 
-		//}
+		if (!(ibs_op_ctl & IBS_OP_MAX_CNT)) {
+			atomic_long_inc(&state.spurious_nmis);
+			wrmsrl(IBS_OP_CTL, 0);
+			return NMI_HANDLED; 
+		}
 
 		//if (ibs_op_rip == 0) {
 		//	return NMI_HANDLED;
 		//}
 
-		// Collect the sample
-		this_sample = &state.sample_buf[sample_idx];
-		read_sample_data(this_sample, regs);
-		atomic_long_inc(&state.samples_collected);
+		// Are we handling "normal" or "precise" sampling?
+		precise_mode = atomic_long_read(&state.precise_mode);
+
+		// Collect the sample. If we don't have any more space to store 
+		// samples, count it as dropped (but keep sampling enabled, so 
+		// the number of dropped samples is accurate). 
+		seq = atomic_long_fetch_inc(&state.sample_seq);
+		sample_idx = atomic_long_read(&state.samples_collected);
+		if (sample_idx < state.sample_buf_capacity) {
+			this_sample = &state.sample_buf[sample_idx];
+			write_record_header(this_sample, seq, regs);
+			read_sample_data(&this_sample->data, regs);
+			atomic_long_inc(&state.samples_collected);
+		} else {
+			atomic_long_inc(&state.samples_dropped);
+		}
 
 		// For "normal" sampling, reconfigure IBS_OP_CTL so we can handle 
 		// another sample (presumably we just clear the valid bit). 
@@ -6,6 +6,23 @@
 #define CODE_BUFFER_PAGES		32
 #define CODE_BUFFER_MAX_SIZE	(CODE_BUFFER_PAGES * PAGE_SIZE)
 
+// Alignment of each program in the code buffer for IBSTRACE_CMD_BATCH
+#define BATCH_ALIGN				64
+
+// The maximum number of programs in a batch
+#define BATCH_MAX_PROGS			(CODE_BUFFER_MAX_SIZE / BATCH_ALIGN)
+
+// A batch of programs (already uploaded into the code buffer) to-be-sampled 
+// on the target core
+struct ibstrace_batch {
+	// Results for each program (with load offsets filled in)
+	struct ibstrace_batch_result *results;
+	// Number of programs
+	u64 count;
+	// Sampling configuration used for all programs
+	struct ibstrace_measure_msg config;
+};
+
 struct ibstrace_state {
 	// Lock held when we're doing some operation
 	struct mutex in_use;
@@ -13,10 +30,28 @@ struct ibstrace_state {
 	// The number of samples copied into the buffer
 	atomic_long_t samples_collected;
 
+	// The number of samples dropped because the buffer was full
+	atomic_long_t samples_dropped;
+
+	// The number of "hanging" NMIs rejected by the NMI handler
+	atomic_long_t spurious_nmis;
+
+	// The sequence number for the next sample (including dropped samples)
+	atomic_long_t sample_seq;
+
 	atomic_long_t precise_mode;
 
+	// Set when collecting fetch samples (instead of op samples)
+	atomic_long_t fetch_mode;
+
+	// The core used to run user code (initially TARGET_CPU)
+	u32 target_cpu;
+
+	// IBS capabilities (from CPUID Fn8000_001B EAX)
+	u32 ibs_caps;
+
 	// Pointer to buffer of samples
-	struct sample *sample_buf;
+	struct sample_v2 *sample_buf;
 	// Maximum number of samples 
 	u64 sample_buf_capacity;
 	// Length of the sample buffer in bytes
@@ -8,17 +8,28 @@
 #include <ibstrace.h>
 #include "state.h"
 #include "msr.h"
-
-// FIXME: Consider saving the return value from measured code so we can send 
-// it back to userspace
+#include "fault.h"
 
 extern struct ibstrace_state state;
+extern struct ibstrace_fault ibstrace_fault;
+
+// Register state of measured code, filled in by the trampolines.
+// The return value from measured code is in RAX.
+struct ibstrace_regs ibstrace_regs;
 
-int __trampoline_start(void *code_ptr, void *scratch_page_vaddr);
+int __trampoline_start(
+	void *code_ptr, 
+	void *scratch_page_vaddr, 
+	__u64 ctl_msr, 
+	__u64 ctl_enable, 
+	__u64 ctl_disable, 
+	__u64 warmup
+);
 int __precise_trampoline_start(
 	void *code_ptr, 
-	void *scratch_page_vaddr,
-	__u64 offset
+	void *arg,
+	__u64 offset,
+	void *scratch_page_vaddr
 );
 
 // Cursed hack #3: 
@@ -29,7 +40,110 @@ void trampoline(void *info)
 {
 	int res = -1;
 
-	res = __trampoline_start(state.code_buf, state.scratch_page);
+	struct ibstrace_measure_msg *arg = (struct ibstrace_measure_msg*)info;
+
+	res = __trampoline_start(
+			state.code_buf, 
+			state.scratch_page, 
+			IBS_OP_CTL,
+			arg->op_ctl, 
+			IBS_OP_VAL,
+			arg->warmup
+	);
+
+	// This lock is aquired in ibstrace_ioctl() just before we use
+	// smp_call_function_single_async() to call this function.
+
+	mutex_unlock(&state.in_use);
+}
+
+// Run the trampoline once for each program in a batch, recording the range 
+// of samples and the register state for each.
+void batch_trampoline(void *info)
+{
+	u64 i;
+	long first;
+	long dropped;
+	long spurious;
+
+	struct ibstrace_batch *batch = (struct ibstrace_batch*)info;
+
+	for (i = 0; i < batch->count; i++) {
+		struct ibstrace_batch_result *res = &batch->results[i];
+
+		first = atomic_long_read(&state.samples_collected);
+		dropped = atomic_long_read(&state.samples_dropped);
+		spurious = atomic_long_read(&state.spurious_nmis);
+		__trampoline_start(
+				state.code_buf + res->load_offset, 
+				state.scratch_page, 
+				IBS_OP_CTL,
+				batch->config.op_ctl, 
+				IBS_OP_VAL,
+				batch->config.warmup
+		);
+		res->first = first;
+		res->count = atomic_long_read(&state.samples_collected) - first;
+		res->dropped = atomic_long_read(&state.samples_dropped) - dropped;
+		res->spurious = atomic_long_read(&state.spurious_nmis) - spurious;
+		res->regs = ibstrace_regs;
+
+		// Stop after the first program that faults
+		if (ibstrace_faulted()) {
+			res->fault = ibstrace_fault;
+			break;
+		}
+	}
+
+	// This lock is aquired in ibstrace_ioctl() just before we use
+	// smp_call_function_single_async() to call this function.
+
+	mutex_unlock(&state.in_use);
+}
+
+// Run the precise trampoline once for each offset in a sweep. 
+// Each offset occupies exactly one entry in the sample buffer, which is left 
+// zeroed when no sample was collected. 
+void sweep_trampoline(void *info)
+{
+	u64 i;
+
+	struct ibstrace_sweep_msg *arg = (struct ibstrace_sweep_msg*)info;
+
+	for (i = 0; i < arg->count; i++) {
+		__precise_trampoline_start(
+				state.code_buf, 
+				arg->ptr,
+				arg->first + i,
+				state.scratch_page
+		);
+		atomic_long_set(&state.samples_collected, i + 1);
+
+		// Stop after the first offset where user code faults
+		if (ibstrace_faulted())
+			break;
+	}
+
+	// This lock is aquired in ibstrace_ioctl() just before we use
+	// smp_call_function_single_async() to call this function.
+
+	mutex_unlock(&state.in_use);
+}
+
+void fetch_trampoline(void *info)
+{
+	int res = -1;
+
+	struct ibstrace_fetch_msg *arg = (struct ibstrace_fetch_msg*)info;
+
+	res = __trampoline_start(
+			state.code_buf, 
+			state.scratch_page, 
+			IBS_FETCH_CTL,
+			arg->fetch_ctl, 
+			IBS_FETCH_VAL,
+			arg->warmup
+	);
 
 	// This lock is aquired in ibstrace_ioctl() just before we use
 	// smp_call_function_single_async() to call this function.
@@ -46,7 +160,8 @@ void precise_trampoline(void *info)
 	res = __precise_trampoline_start(
 			state.code_buf, 
 			arg->ptr,
-			arg->offset
+			arg->offset,
+			state.scratch_page
 	);
 
 	// This lock is aquired in ibstrace_ioctl() just before we use
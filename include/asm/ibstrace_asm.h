@@ -2,4 +2,72 @@
 // In precise mode, this is the maximum supported offset of a sample. 
 #define MAX_OFFSET                  0x00400000
 
+// Offsets into 'struct ibstrace_regs'
+#define REGS_RAX                    0x00
+#define REGS_RBX                    0x08
+#define REGS_RCX                    0x10
+#define REGS_RDX                    0x18
+#define REGS_RSI                    0x20
+#define REGS_RDI                    0x28
+#define REGS_RBP                    0x30
+#define REGS_RSP                    0x38
+#define REGS_R8                     0x40
+#define REGS_R9                     0x48
+#define REGS_R10                    0x50
+#define REGS_R11                    0x58
+#define REGS_R12                    0x60
+#define REGS_R13                    0x68
+#define REGS_R14                    0x70
+#define REGS_R15                    0x78
+#define REGS_RFLAGS                 0x80
+
+#ifdef __ASSEMBLY__
+
+// Arrange for a fault in measured code to resume at 'resume', with the 
+// current stack pointer (see ibstrace/fault.c). This must be used at the 
+// same stack depth as the call into measured code, and clobbers RAX.
+.macro ARM_FAULT_FIXUP resume
+	lea		rax, [rip + \resume]
+	mov		QWORD PTR [rip + ibstrace_fixup_ip], rax
+	mov		QWORD PTR [rip + ibstrace_fixup_sp], rsp
+.endm
+
+// Stop recovering from faults in measured code. 
+.macro DISARM_FAULT_FIXUP
+	mov		QWORD PTR [rip + ibstrace_fixup_sp], 0
+.endm
+
+// Save the registers clobbered by disabling IBS (RAX, RCX, RDX, and flags) 
+// into 'ibstrace_regs'. This must be used immediately after returning from 
+// measured code, and is kept short since IBS is still enabled. Clobbers RAX.
+.macro SAVE_USER_SCRATCH
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RAX], rax
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RCX], rcx
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RDX], rdx
+	pushfq
+	pop		rax
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RFLAGS], rax
+.endm
+
+// Save the rest of the register state of measured code into 'ibstrace_regs'.
+// This must be used after SAVE_USER_SCRATCH at the same stack depth as the 
+// call into measured code, and leaves all registers untouched.
+.macro SAVE_USER_REGS
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RBX], rbx
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RSI], rsi
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RDI], rdi
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RBP], rbp
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_RSP], rsp
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R8], r8
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R9], r9
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R10], r10
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R11], r11
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R12], r12
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R13], r13
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R14], r14
+	mov		QWORD PTR [rip + ibstrace_regs + REGS_R15], r15
+.endm
+
+#endif // __ASSEMBLY__
+
 
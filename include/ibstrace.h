@@ -23,6 +23,40 @@
 // ioctl() command: execute and sample a particular op in user code
 #define IBSTRACE_CMD_PRECISE		0x00020000
 
+// ioctl() command: return the register state after measured code returns
+#define IBSTRACE_CMD_REGS			0x00040000
+
+// ioctl() command: change the core used to run user code
+#define IBSTRACE_CMD_SET_CPU		0x00080000
+
+// ioctl() command: execute user code and collect fetch samples
+#define IBSTRACE_CMD_FETCH			0x00100000
+
+// ioctl() command: sample a range of ops in user code
+#define IBSTRACE_CMD_SWEEP			0x00200000
+
+// ioctl() command: submit and sample a list of programs
+#define IBSTRACE_CMD_BATCH			0x00400000
+
+// ioctl() command: read statistics about the most-recent measurement
+#define IBSTRACE_CMD_STATS			0x00800000
+
+// ioctl() command: return the version of records in the sample buffer
+#define IBSTRACE_CMD_VERSION		0x01000000
+
+// The version of records in the sample buffer (see 'struct sample_v2'). 
+// Older modules without IBSTRACE_CMD_VERSION store a bare 'struct sample'.
+#define IBSTRACE_SAMPLE_VERSION		2
+
+// ioctl() command: return the fault taken by measured code (if any)
+#define IBSTRACE_CMD_FAULT			0x02000000
+
+// ioctl() command: fill the scratch page passed to user code
+#define IBSTRACE_CMD_WRITE_DATA		0x04000000
+
+// ioctl() command: read back the contents of the scratch page
+#define IBSTRACE_CMD_READ_DATA		0x08000000
+
 // Arguments passed to IBSTRACE_CMD_WRITE 
 struct ibstrace_msg {
 	// Pointer to a buffer with user code to-be-uploaded
@@ -31,6 +65,28 @@ struct ibstrace_msg {
 	__u64 len;
 };
 
+// Flag for IBSTRACE_CMD_MEASURE: randomize the initial counter value
+#define IBSTRACE_MEASURE_RANDOMIZE	(1ULL << 0)
+
+// Arguments passed to IBSTRACE_CMD_MEASURE
+struct ibstrace_measure_msg {
+	// Value for IBS_OP_CTL (only IbsOpMaxCnt, IbsOpCntCtl, IbsOpL3MissOnly, 
+	// IbsOpLdLatEn and IbsOpLdLatThrsh may be set)
+	__u64 op_ctl;
+	// Flags (IBSTRACE_MEASURE_*)
+	__u64 flags;
+	// Number of times to run user code before sampling
+	__u64 warmup;
+};
+
+// Arguments passed to IBSTRACE_CMD_FETCH
+struct ibstrace_fetch_msg {
+	// Value for IBS_FETCH_CTL (only IbsFetchMaxCnt and IbsRandEn may be set)
+	__u64 fetch_ctl;
+	// Number of times to run user code before sampling
+	__u64 warmup;
+};
+
 // Arguments passed to IBSTRACE_CMD_PRECISE
 struct ibstrace_precise_msg {
 	// Arbitrary user input, passed to measured code in RDI
@@ -39,6 +95,95 @@ struct ibstrace_precise_msg {
 	__u64 offset;
 };
 
+// Arguments passed to IBSTRACE_CMD_SWEEP
+struct ibstrace_sweep_msg {
+	// Arbitrary user input, passed to measured code in RDI
+	void *ptr;
+	// First target sample offset (in dispatched micro-ops)
+	__u64 first;
+	// Number of target sample offsets
+	__u64 count;
+};
+
+// Register state captured after measured code returns to the trampoline.
+// NOTE: The field offsets are mirrored by the REGS_* constants in 
+// asm/ibstrace_asm.h. 
+struct ibstrace_regs {
+	__u64 rax;
+	__u64 rbx;
+	__u64 rcx;
+	__u64 rdx;
+	__u64 rsi;
+	__u64 rdi;
+	__u64 rbp;
+	__u64 rsp;
+	__u64 r8;
+	__u64 r9;
+	__u64 r10;
+	__u64 r11;
+	__u64 r12;
+	__u64 r13;
+	__u64 r14;
+	__u64 r15;
+	__u64 rflags;
+};
+
+// Returned by IBSTRACE_CMD_FAULT. 
+// When measured code faults, the measurement stops early and the ioctl() 
+// command used to start the measurement returns -EFAULT (except for 
+// IBSTRACE_CMD_BATCH, see 'struct ibstrace_batch_result').
+struct ibstrace_fault {
+	// Set when measured code faulted during the most-recent measurement
+	__u64 valid;
+	// Exception vector (ie. 6 for #UD, 13 for #GP, 14 for #PF)
+	__u64 vector;
+	// Address of the faulting instruction
+	__u64 rip;
+	// Error code pushed by the exception (if any)
+	__u64 error_code;
+};
+
+// The result of sampling a single program with IBSTRACE_CMD_BATCH
+struct ibstrace_batch_result {
+	// Offset of the program in the code buffer
+	__u64 load_offset;
+	// Index of the first sample collected for this program
+	__u64 first;
+	// Number of samples collected for this program
+	__u64 count;
+	// Number of samples dropped while running this program
+	__u64 dropped;
+	// Number of spurious NMIs while running this program
+	__u64 spurious;
+	// Register state after the program returns
+	struct ibstrace_regs regs;
+	// Set when this program faulted. The batch stops after the first fault, 
+	// and the results for the remaining programs are left zeroed. 
+	struct ibstrace_fault fault;
+};
+
+// Arguments passed to IBSTRACE_CMD_BATCH
+struct ibstrace_batch_msg {
+	// Pointer to a list of programs to-be-uploaded
+	struct ibstrace_msg *progs;
+	// Number of programs
+	__u64 count;
+	// Pointer to a list of results (one for each program)
+	struct ibstrace_batch_result *results;
+	// Sampling configuration used for all programs
+	struct ibstrace_measure_msg config;
+};
+
+// Statistics returned by IBSTRACE_CMD_STATS
+struct ibstrace_stats {
+	// Number of samples in the sample buffer
+	__u64 collected;
+	// Number of samples dropped because the sample buffer was full
+	__u64 dropped;
+	// Number of "hanging" NMIs rejected by the NMI handler
+	__u64 spurious;
+};
+
 // IBS sample data
 struct sample {
 	__u64 op_ctl;
@@ -51,4 +196,31 @@ struct sample {
 	__u64 tgt_rip;
 };
 
+// IBS fetch sample data. 
+// Fetch samples are stored in the same buffer as op samples, so this is 
+// padded to the size of a 'struct sample'.
+struct fetch_sample {
+	__u64 fetch_ctl;
+	__u64 fetch_lin_addr;
+	__u64 fetch_phys_addr;
+	__u64 fetch_ext_ctl;
+	__u64 reserved[4];
+};
+
+// A record in the sample buffer (version 2). 
+// The header is filled in by the NMI handler, followed by either a 'struct 
+// sample' or a 'struct fetch_sample'. Unused records are left zeroed.
+struct sample_v2 {
+	// Record version (IBSTRACE_SAMPLE_VERSION)
+	__u64 version;
+	// Sequence number, starting from zero for each measurement
+	__u64 seq;
+	// Timestamp counter value when the NMI was handled
+	__u64 tsc;
+	// Instruction pointer interrupted by the NMI
+	__u64 intr_rip;
+	// Sample data
+	struct sample data;
+};
+
 #endif // _IBSTRACE_H
@@ -11,7 +11,9 @@ use std::env;
 enum XtaskCommand { 
     /// Build the 'ibstrace' kernel module
     Build { 
-        /// The core number used to run user-uploaded code
+        /// The initial core number used to run user-uploaded code 
+        /// (this can be changed at runtime)
+        #[arg(default_value_t = 0)]
         core: usize 
     },
     /// Clean build artifacts